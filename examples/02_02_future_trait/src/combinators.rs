@@ -0,0 +1,276 @@
+//! Combinators which transform the output of a `SimpleFuture`, or chain
+//! another future onto it.
+//!
+//! Unlike `AndThenFut`, `Then` and `AndThen` create the second future from
+//! the output of the first one, like `get_breakfast.and_then(|food| eat(food))`.
+//! They are state machines with one variant per stage: while in `First`, the
+//! closure is stored alongside the first future; once the first future
+//! completes, the closure is called and its result is stored in `Second`.
+
+use std::mem;
+
+use crate::{fuse::Fuse, Poll, SimpleFuture};
+
+/// A SimpleFuture that applies a function to the output of another future.
+pub struct Map<Fut, F> {
+    future: Fut,
+    // `None` once the future has completed and `f` has been called.
+    f: Option<F>,
+}
+
+impl<Fut, F, T> SimpleFuture for Map<Fut, F>
+where
+    Fut: SimpleFuture,
+    F: FnOnce(Fut::Output) -> T,
+{
+    type Output = T;
+    fn poll(&mut self, wake: fn()) -> Poll<Self::Output> {
+        match self.future.poll(wake) {
+            Poll::Ready(output) => {
+                let f = self.f.take().expect("`Map` polled after completion");
+                Poll::Ready(f(output))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A SimpleFuture that applies a function to the error of a future which
+/// outputs a `Result`.
+pub struct MapErr<Fut, F> {
+    future: Fut,
+    f: Option<F>,
+}
+
+impl<Fut, F, T, E, E2> SimpleFuture for MapErr<Fut, F>
+where
+    Fut: SimpleFuture<Output = Result<T, E>>,
+    F: FnOnce(E) -> E2,
+{
+    type Output = Result<T, E2>;
+    fn poll(&mut self, wake: fn()) -> Poll<Self::Output> {
+        match self.future.poll(wake) {
+            Poll::Ready(output) => {
+                let f = self.f.take().expect("`MapErr` polled after completion");
+                Poll::Ready(output.map_err(f))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A SimpleFuture that runs a future, then uses its output to create a
+/// second future and runs that one.
+pub enum Then<FutureA, F, FutureB> {
+    /// Running the first future.
+    First(FutureA, F),
+    /// Running the future returned by `F`.
+    Second(FutureB),
+    /// The second future has completed.
+    Done,
+}
+
+impl<FutureA, F, FutureB> SimpleFuture for Then<FutureA, F, FutureB>
+where
+    FutureA: SimpleFuture,
+    F: FnOnce(FutureA::Output) -> FutureB,
+    FutureB: SimpleFuture,
+{
+    type Output = FutureB::Output;
+    fn poll(&mut self, wake: fn()) -> Poll<Self::Output> {
+        // Loop so that the second future is polled as soon as it is created.
+        loop {
+            match self {
+                Then::First(first, _) => match first.poll(wake) {
+                    Poll::Ready(output) => {
+                        // Move the closure out of `self` so we can call it.
+                        let Then::First(_, f) = mem::replace(self, Then::Done) else {
+                            unreachable!()
+                        };
+                        *self = Then::Second(f(output));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                Then::Second(second) => match second.poll(wake) {
+                    Poll::Ready(output) => {
+                        *self = Then::Done;
+                        return Poll::Ready(output);
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                Then::Done => panic!("`Then` polled after completion"),
+            }
+        }
+    }
+}
+
+/// A SimpleFuture that runs a future which outputs a `Result`, then, if it
+/// succeeded, uses the `Ok` value to create a second future and runs that one.
+///
+/// If the first future fails, its error is returned and the closure is never
+/// called.
+pub enum AndThen<FutureA, F, FutureB> {
+    /// Running the first future.
+    First(FutureA, F),
+    /// Running the future returned by `F`.
+    Second(FutureB),
+    /// One of the futures has completed.
+    Done,
+}
+
+impl<FutureA, F, FutureB, T, U, E> SimpleFuture for AndThen<FutureA, F, FutureB>
+where
+    FutureA: SimpleFuture<Output = Result<T, E>>,
+    F: FnOnce(T) -> FutureB,
+    FutureB: SimpleFuture<Output = Result<U, E>>,
+{
+    type Output = Result<U, E>;
+    fn poll(&mut self, wake: fn()) -> Poll<Self::Output> {
+        loop {
+            match self {
+                AndThen::First(first, _) => match first.poll(wake) {
+                    Poll::Ready(Ok(output)) => {
+                        let AndThen::First(_, f) = mem::replace(self, AndThen::Done) else {
+                            unreachable!()
+                        };
+                        *self = AndThen::Second(f(output));
+                    }
+                    Poll::Ready(Err(e)) => {
+                        *self = AndThen::Done;
+                        return Poll::Ready(Err(e));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                AndThen::Second(second) => match second.poll(wake) {
+                    Poll::Ready(output) => {
+                        *self = AndThen::Done;
+                        return Poll::Ready(output);
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                AndThen::Done => panic!("`AndThen` polled after completion"),
+            }
+        }
+    }
+}
+
+/// Adapters for chaining `SimpleFuture`s, like `futures::FutureExt` and
+/// `futures::TryFutureExt` do for `Future`s.
+pub trait SimpleFutureExt: SimpleFuture + Sized {
+    fn map<F, T>(self, f: F) -> Map<Self, F>
+    where
+        F: FnOnce(Self::Output) -> T,
+    {
+        Map {
+            future: self,
+            f: Some(f),
+        }
+    }
+
+    fn then<F, FutureB>(self, f: F) -> Then<Self, F, FutureB>
+    where
+        F: FnOnce(Self::Output) -> FutureB,
+        FutureB: SimpleFuture,
+    {
+        Then::First(self, f)
+    }
+
+    fn and_then<F, FutureB, T, U, E>(self, f: F) -> AndThen<Self, F, FutureB>
+    where
+        Self: SimpleFuture<Output = Result<T, E>>,
+        F: FnOnce(T) -> FutureB,
+        FutureB: SimpleFuture<Output = Result<U, E>>,
+    {
+        AndThen::First(self, f)
+    }
+
+    fn map_err<F, T, E, E2>(self, f: F) -> MapErr<Self, F>
+    where
+        Self: SimpleFuture<Output = Result<T, E>>,
+        F: FnOnce(E) -> E2,
+    {
+        MapErr {
+            future: self,
+            f: Some(f),
+        }
+    }
+
+    fn fuse(self) -> Fuse<Self> {
+        Fuse::new(self)
+    }
+}
+
+impl<Fut: SimpleFuture> SimpleFutureExt for Fut {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compat::{FromStdFuture, IntoStdFuture},
+        test_util::{noop_wake, Countdown},
+    };
+    use std::{cell::RefCell, rc::Rc};
+
+    type Log = Rc<RefCell<Vec<&'static str>>>;
+
+    struct Food(&'static str);
+
+    fn get_breakfast(log: &Log) -> Countdown<Result<Food, String>> {
+        Countdown::new("get_breakfast", 2, Ok(Food("eggs")), log)
+    }
+
+    fn eat(food: Food, log: &Log) -> Countdown<Result<usize, String>> {
+        Countdown::new("eat", 2, Ok(food.0.len()), log)
+    }
+
+    #[test]
+    fn and_then_builds_second_future_from_output() {
+        let log = Log::default();
+        let mut breakfast = get_breakfast(&log).and_then(|food| eat(food, &log));
+
+        assert!(matches!(breakfast.poll(noop_wake), Poll::Pending));
+        // The first future completes and `eat` is polled straight away.
+        assert!(matches!(breakfast.poll(noop_wake), Poll::Pending));
+        assert!(matches!(breakfast, AndThen::Second(_)));
+        assert!(matches!(breakfast.poll(noop_wake), Poll::Ready(Ok(4))));
+        assert!(matches!(breakfast, AndThen::Done));
+        assert_eq!(
+            *log.borrow(),
+            ["get_breakfast", "get_breakfast", "eat", "eat"]
+        );
+    }
+
+    #[test]
+    fn and_then_skips_second_future_on_error() {
+        let log = Log::default();
+        let mut breakfast = Countdown::new("get_breakfast", 1, Err("burnt".to_string()), &log)
+            .and_then(|food: Food| eat(food, &log));
+        match breakfast.poll(noop_wake) {
+            Poll::Ready(Err(e)) => assert_eq!(e, "burnt"),
+            _ => panic!("expected an error"),
+        }
+        assert_eq!(*log.borrow(), ["get_breakfast"]);
+    }
+
+    #[test]
+    fn then_map_and_map_err_chain() {
+        let log = Log::default();
+        let mut chain = Countdown::new("first", 1, 2, &log)
+            .then(|n| Countdown::new("second", 1, Err::<(), _>(n * 10), &log))
+            .map_err(|n: i32| format!("error {n}"))
+            .map(|result| result.unwrap_err());
+        match chain.poll(noop_wake) {
+            Poll::Ready(e) => assert_eq!(e, "error 20"),
+            Poll::Pending => panic!("expected the chain to complete"),
+        }
+        assert_eq!(*log.borrow(), ["first", "second"]);
+    }
+
+    #[test]
+    fn chains_with_async_blocks() {
+        let future = FromStdFuture::new(async { 20 })
+            .then(|n| FromStdFuture::new(async move { n + 1 }))
+            .map(|n| n * 2);
+        assert_eq!(futures::executor::block_on(IntoStdFuture::new(future)), 42);
+    }
+}
@@ -0,0 +1,264 @@
+//! Adapters between our `SimpleFuture` and the real `std::future::Future`.
+//!
+//! `SimpleFuture` wakes its task with a bare `fn()`, which can't carry any
+//! data about *which* task to wake. To drive a `SimpleFuture` from a real
+//! executor, `IntoStdFuture` stores the `Waker` it was last polled with in a
+//! global registry, and passes `wake_registered` as the `fn()`. Calling it
+//! wakes every registered task; this may cause spurious wakeups, but those
+//! are allowed by the `Future` contract.
+//!
+//! Each `IntoStdFuture` has its own entry in the registry, since one task
+//! may be waiting on several of them, and it removes the entry when it
+//! completes or is dropped.
+//!
+//! Going the other way is easier: a `Waker` can be built directly from a
+//! `fn()`, since the function pointer fits in the `RawWaker` data pointer.
+
+use std::{
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    task::{self, Context, RawWaker, RawWakerVTable, Waker},
+};
+
+use crate::{Poll, SimpleFuture};
+
+/// Wakers of the tasks which are waiting on an `IntoStdFuture`, keyed by
+/// the id of the future.
+static REGISTRY: Mutex<Vec<(u64, Waker)>> = Mutex::new(Vec::new());
+
+/// The id of the next `IntoStdFuture`.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Wake every task which is currently waiting on an `IntoStdFuture`.
+///
+/// This is the `fn()` passed to the wrapped `SimpleFuture`.
+pub fn wake_registered() {
+    // Take the wakers out before waking them so that a task which is polled
+    // (and re-registers itself) on this thread doesn't deadlock.
+    let wakers = mem::take(&mut *REGISTRY.lock().unwrap());
+    for (_, waker) in wakers {
+        waker.wake();
+    }
+}
+
+/// An `IntoStdFuture`'s entry in the registry, which is removed when this is
+/// dropped.
+struct Registration {
+    id: u64,
+}
+
+impl Registration {
+    fn new() -> Self {
+        Registration {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    fn register(&self, waker: &Waker) {
+        let mut registry = REGISTRY.lock().unwrap();
+        match registry.iter_mut().find(|(id, _)| *id == self.id) {
+            Some((_, registered)) => registered.clone_from(waker),
+            None => registry.push((self.id, waker.clone())),
+        }
+    }
+
+    fn unregister(&self) {
+        let mut registry = REGISTRY.lock().unwrap();
+        if let Some(i) = registry.iter().position(|(id, _)| *id == self.id) {
+            let (_, waker) = registry.swap_remove(i);
+            // Drop the waker outside the lock, in case that wakes a task.
+            drop(registry);
+            drop(waker);
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Wraps a `SimpleFuture` so it can be `.await`ed or run on an executor.
+pub struct IntoStdFuture<F> {
+    inner: F,
+    registration: Registration,
+}
+
+impl<F: SimpleFuture + Unpin> IntoStdFuture<F> {
+    pub fn new(inner: F) -> Self {
+        IntoStdFuture {
+            inner,
+            registration: Registration::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+impl<F: SimpleFuture + Unpin> Future for IntoStdFuture<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> task::Poll<Self::Output> {
+        // Register before polling, otherwise a wakeup which happens between
+        // `poll` returning and the registration would be lost.
+        self.registration.register(cx.waker());
+        match self.inner.poll(wake_registered) {
+            Poll::Ready(output) => {
+                self.registration.unregister();
+                task::Poll::Ready(output)
+            }
+            Poll::Pending => task::Poll::Pending,
+        }
+    }
+}
+
+/// Wraps a `std::future::Future` so it can be used as a `SimpleFuture`.
+///
+/// The future is boxed so that `!Unpin` futures, such as `async` blocks,
+/// can be polled through `&mut self`.
+pub struct FromStdFuture<F> {
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> FromStdFuture<F> {
+    pub fn new(inner: F) -> Self {
+        FromStdFuture {
+            inner: Box::pin(inner),
+        }
+    }
+}
+
+impl<F: Future> SimpleFuture for FromStdFuture<F> {
+    type Output = F::Output;
+
+    fn poll(&mut self, wake: fn()) -> Poll<Self::Output> {
+        let waker = fn_waker(wake);
+        let mut cx = Context::from_waker(&waker);
+        match self.inner.as_mut().poll(&mut cx) {
+            task::Poll::Ready(output) => Poll::Ready(output),
+            task::Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+static FN_WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(fn_waker_clone, fn_waker_wake, fn_waker_wake, fn_waker_drop);
+
+/// Create a `Waker` which calls `wake` when it is woken.
+fn fn_waker(wake: fn()) -> Waker {
+    let raw = RawWaker::new(wake as *const (), &FN_WAKER_VTABLE);
+    // SAFETY: the vtable functions only ever treat the data pointer as the
+    // `fn()` it was created from, and a function pointer is `Send + Sync`.
+    unsafe { Waker::from_raw(raw) }
+}
+
+unsafe fn fn_waker_clone(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &FN_WAKER_VTABLE)
+}
+
+unsafe fn fn_waker_wake(data: *const ()) {
+    // SAFETY: `data` was created from a `fn()` in `fn_waker`.
+    let wake = unsafe { mem::transmute::<*const (), fn()>(data) };
+    wake();
+}
+
+unsafe fn fn_waker_drop(_: *const ()) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AndThenFut, Join};
+    use futures::{executor::block_on, FutureExt};
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use timer_future::TimerFuture;
+
+    #[test]
+    fn join_async_blocks() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (log_a, log_b) = (log.clone(), log.clone());
+        let join = Join {
+            a: Some(FromStdFuture::new(async move {
+                TimerFuture::new(Duration::from_millis(20)).await;
+                log_a.lock().unwrap().push("a");
+            })),
+            b: Some(FromStdFuture::new(async move {
+                log_b.lock().unwrap().push("b");
+            })),
+        };
+        block_on(IntoStdFuture::new(join));
+        // `b` completes while `a` is still waiting on its timer.
+        assert_eq!(*log.lock().unwrap(), ["b", "a"]);
+    }
+
+    #[test]
+    fn and_then_async_blocks() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (log_first, log_second) = (log.clone(), log.clone());
+        let and_then = AndThenFut {
+            first: Some(FromStdFuture::new(async move {
+                TimerFuture::new(Duration::from_millis(20)).await;
+                log_first.lock().unwrap().push("first");
+            })),
+            second: FromStdFuture::new(async move {
+                log_second.lock().unwrap().push("second");
+            }),
+        };
+        block_on(async {
+            IntoStdFuture::new(and_then).await;
+            log.lock().unwrap().push("after");
+        });
+        assert_eq!(*log.lock().unwrap(), ["first", "second", "after"]);
+    }
+
+    #[test]
+    fn join_wrapped_futures_completing_in_reverse_order() {
+        let sleep = |ms| IntoStdFuture::new(FromStdFuture::new(TimerFuture::new(Duration::from_millis(ms))));
+        let log = Mutex::new(Vec::new());
+        let first = async {
+            sleep(40).await;
+            log.lock().unwrap().push("first");
+        };
+        let second = async {
+            sleep(10).await;
+            log.lock().unwrap().push("second");
+        };
+        // Both wrappers wait in the same task. `second` finishing must not
+        // take away the wakeup `first` is waiting for.
+        block_on(futures::future::join(first, second));
+        assert_eq!(*log.lock().unwrap(), ["second", "first"]);
+    }
+
+    #[test]
+    fn dropping_a_pending_future_unregisters_it() {
+        let mut future = IntoStdFuture::new(FromStdFuture::new(futures::future::pending::<()>()));
+        let id = future.registration.id;
+        let registered = || REGISTRY.lock().unwrap().iter().any(|(i, _)| *i == id);
+        // Other tests may call `wake_registered`, which empties the registry,
+        // so poll until our entry is there.
+        while !registered() {
+            assert!((&mut future).now_or_never().is_none());
+        }
+        drop(future);
+        assert!(!registered());
+    }
+
+    #[test]
+    fn round_trip_keeps_output() {
+        let future = FromStdFuture::new(async {
+            TimerFuture::new(Duration::from_millis(10)).await;
+            42
+        });
+        assert_eq!(block_on(IntoStdFuture::new(future)), 42);
+    }
+}
@@ -0,0 +1,297 @@
+//! Wrappers for futures which may be polled after they have completed.
+//!
+//! Polling a future again after it has returned `Ready` violates the contract
+//! of the `Future` trait: the future may panic, block forever, or return
+//! garbage. Combinators like `Join` are careful never to do this.
+//!
+//! `Checked` catches this mistake in debug builds by panicking with a clear
+//! message, which makes it useful in tests of combinators and `select!` loops.
+//! `Fuse` makes it harmless instead, by returning `Pending` forever once the
+//! wrapped future has completed.
+//!
+//! The `real` module has the same wrappers for `std::future::Future`.
+
+use std::any::type_name;
+
+use crate::{Poll, SimpleFuture};
+
+/// A SimpleFuture which panics, in debug builds, if it is polled after it
+/// has completed.
+pub struct Checked<F> {
+    future: F,
+    done: bool,
+}
+
+impl<F: SimpleFuture> Checked<F> {
+    pub fn new(future: F) -> Self {
+        Checked {
+            future,
+            done: false,
+        }
+    }
+}
+
+impl<F: SimpleFuture> SimpleFuture for Checked<F> {
+    type Output = F::Output;
+    fn poll(&mut self, wake: fn()) -> Poll<Self::Output> {
+        debug_assert!(
+            !self.done,
+            "`{}` was polled after it returned `Poll::Ready`",
+            type_name::<F>(),
+        );
+        let poll = self.future.poll(wake);
+        if let Poll::Ready(_) = poll {
+            self.done = true;
+        }
+        poll
+    }
+}
+
+/// A SimpleFuture which returns `Pending` forever once the wrapped future has
+/// completed, rather than polling it again.
+pub struct Fuse<F> {
+    // `None` once the future has completed.
+    future: Option<F>,
+}
+
+impl<F: SimpleFuture> Fuse<F> {
+    pub fn new(future: F) -> Self {
+        Fuse {
+            future: Some(future),
+        }
+    }
+
+    /// Returns `true` if the wrapped future has completed.
+    pub fn is_terminated(&self) -> bool {
+        self.future.is_none()
+    }
+}
+
+impl<F: SimpleFuture> SimpleFuture for Fuse<F> {
+    type Output = F::Output;
+    fn poll(&mut self, wake: fn()) -> Poll<Self::Output> {
+        let Some(future) = &mut self.future else {
+            // Nothing will ever wake us, so this future never completes again.
+            return Poll::Pending;
+        };
+        let poll = future.poll(wake);
+        if let Poll::Ready(_) = poll {
+            self.future = None;
+        }
+        poll
+    }
+}
+
+pub mod real {
+    //! `Checked` and `Fuse` for `std::future::Future`.
+    //!
+    //! Both implement `FusedFuture` (`Checked` only if the wrapped future
+    //! does), so they can be used in `futures::select!`.
+
+    use std::{
+        any::type_name,
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use futures::future::FusedFuture;
+
+    /// A future which panics, in debug builds, if it is polled after it has
+    /// completed.
+    pub struct Checked<F> {
+        future: F,
+        done: bool,
+    }
+
+    impl<F: Future> Checked<F> {
+        pub fn new(future: F) -> Self {
+            Checked {
+                future,
+                done: false,
+            }
+        }
+    }
+
+    impl<F: Future> Future for Checked<F> {
+        type Output = F::Output;
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            // SAFETY: `future` is pinned whenever `self` is: it is never moved
+            // out of `self`, and `Checked` doesn't implement `Drop`. `done` is
+            // never pinned.
+            let this = unsafe { self.get_unchecked_mut() };
+            let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+            debug_assert!(
+                !this.done,
+                "`{}` was polled after it returned `Poll::Ready`",
+                type_name::<F>(),
+            );
+            let poll = future.poll(cx);
+            if poll.is_ready() {
+                this.done = true;
+            }
+            poll
+        }
+    }
+
+    impl<F: FusedFuture> FusedFuture for Checked<F> {
+        fn is_terminated(&self) -> bool {
+            self.future.is_terminated()
+        }
+    }
+
+    /// A future which returns `Pending` forever once the wrapped future has
+    /// completed, rather than polling it again.
+    pub struct Fuse<F> {
+        future: Option<F>,
+    }
+
+    impl<F: Future> Fuse<F> {
+        pub fn new(future: F) -> Self {
+            Fuse {
+                future: Some(future),
+            }
+        }
+    }
+
+    impl<F: Future> Future for Fuse<F> {
+        type Output = F::Output;
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            // SAFETY: as for `Checked`, `future` is structurally pinned. It is
+            // only ever dropped in place, through `Pin::set`.
+            let mut future = unsafe { self.map_unchecked_mut(|this| &mut this.future) };
+            let Some(inner) = future.as_mut().as_pin_mut() else {
+                return Poll::Pending;
+            };
+            let poll = inner.poll(cx);
+            if poll.is_ready() {
+                future.set(None);
+            }
+            poll
+        }
+    }
+
+    impl<F: Future> FusedFuture for Fuse<F> {
+        fn is_terminated(&self) -> bool {
+            self.future.is_none()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        join::join_all,
+        select::{select, Either},
+        test_util::{noop_wake, Countdown},
+        Join,
+    };
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "was polled after it returned `Poll::Ready`")]
+    fn checked_panics_when_polled_after_ready() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut future = Checked::new(Countdown::new("a", 1, (), &log));
+        assert!(matches!(future.poll(noop_wake), Poll::Ready(())));
+        let _ = future.poll(noop_wake);
+    }
+
+    #[test]
+    fn fuse_is_pending_after_ready() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut future = Fuse::new(Countdown::new("a", 1, (), &log));
+        assert!(!future.is_terminated());
+        assert!(matches!(future.poll(noop_wake), Poll::Ready(())));
+        assert!(future.is_terminated());
+        for _ in 0..3 {
+            assert!(matches!(future.poll(noop_wake), Poll::Pending));
+        }
+        // The wrapped future was only polled once.
+        assert_eq!(*log.borrow(), ["a"]);
+    }
+
+    #[test]
+    fn join_never_polls_completed_futures() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut join = Join {
+            a: Some(Checked::new(Countdown::new("a", 1, (), &log))),
+            b: Some(Checked::new(Countdown::new("b", 3, (), &log))),
+        };
+        while let Poll::Pending = join.poll(noop_wake) {}
+
+        let mut join = join_all(vec![
+            Checked::new(Countdown::new("a", 3, (), &log)),
+            Checked::new(Countdown::new("b", 1, (), &log)),
+        ]);
+        while let Poll::Pending = join.poll(noop_wake) {}
+    }
+
+    #[test]
+    fn select_loser_can_be_polled_to_completion() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut select = select(
+            Checked::new(Countdown::new("a", 2, (), &log)),
+            Checked::new(Countdown::new("b", 1, (), &log)),
+        );
+        let mut a = match select.poll(noop_wake) {
+            Poll::Ready(Either::Right(((), a))) => a,
+            _ => panic!("`b` should win"),
+        };
+        while let Poll::Pending = a.poll(noop_wake) {}
+    }
+
+    mod real {
+        use super::super::real::*;
+        use futures::{
+            executor::block_on,
+            future::{self, FusedFuture},
+            pin_mut, select,
+        };
+        use std::future::Future;
+
+        #[test]
+        #[cfg(debug_assertions)]
+        #[should_panic(expected = "was polled after it returned `Poll::Ready`")]
+        fn checked_panics_when_polled_after_ready() {
+            // `async` blocks panic themselves when polled after completion, so
+            // use `poll_fn`, which doesn't.
+            let future = Checked::new(future::poll_fn(|_| std::task::Poll::Ready(())));
+            pin_mut!(future);
+            block_on(future.as_mut());
+            block_on(future);
+        }
+
+        #[test]
+        fn fuse_is_pending_after_ready() {
+            let future = Fuse::new(async { 5 });
+            pin_mut!(future);
+            assert_eq!(block_on(future.as_mut()), 5);
+            assert!(future.is_terminated());
+            let waker = futures::task::noop_waker();
+            let mut cx = std::task::Context::from_waker(&waker);
+            assert!(future.as_mut().poll(&mut cx).is_pending());
+        }
+
+        #[test]
+        fn select_loop_never_polls_completed_futures() {
+            let a = Checked::new(Fuse::new(async { 1 }));
+            let b = Checked::new(Fuse::new(async { 2 }));
+            pin_mut!(a, b);
+            let mut total = 0;
+            block_on(async {
+                loop {
+                    select! {
+                        x = a => total += x,
+                        x = b => total += x,
+                        complete => break,
+                    }
+                }
+            });
+            assert_eq!(total, 3);
+        }
+    }
+}
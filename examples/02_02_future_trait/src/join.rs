@@ -0,0 +1,339 @@
+//! Joining any number of futures, collecting their outputs.
+//!
+//! Unlike `Join`, these combinators don't require `Output = ()`: each
+//! sub-future's output is stored until every sub-future has completed, and
+//! then they are all returned together. On each `poll`, every sub-future which
+//! hasn't completed yet is polled once in declaration order, so none of them
+//! can be starved by the others.
+//!
+//! The `real` module has the same combinators for `std::future::Future`.
+
+use crate::{Poll, SimpleFuture};
+
+/// A sub-future of a join, or its output once it has completed.
+enum MaybeDone<F: SimpleFuture> {
+    Pending(F),
+    Done(F::Output),
+    Gone,
+}
+
+impl<F: SimpleFuture> MaybeDone<F> {
+    /// Poll the sub-future if it hasn't completed yet, returning whether it
+    /// has completed.
+    fn poll(&mut self, wake: fn()) -> bool {
+        if let MaybeDone::Pending(future) = self {
+            if let Poll::Ready(output) = future.poll(wake) {
+                *self = MaybeDone::Done(output);
+            }
+        }
+        !matches!(self, MaybeDone::Pending(_))
+    }
+
+    fn take_output(&mut self) -> F::Output {
+        match std::mem::replace(self, MaybeDone::Gone) {
+            MaybeDone::Done(output) => output,
+            _ => panic!("join polled after completion"),
+        }
+    }
+}
+
+/// A SimpleFuture that runs a list of futures to completion concurrently,
+/// and returns their outputs in the same order as the list.
+pub struct JoinAll<F: SimpleFuture> {
+    elems: Vec<MaybeDone<F>>,
+}
+
+pub fn join_all<I>(futures: I) -> JoinAll<I::Item>
+where
+    I: IntoIterator,
+    I::Item: SimpleFuture,
+{
+    JoinAll {
+        elems: futures.into_iter().map(MaybeDone::Pending).collect(),
+    }
+}
+
+impl<F: SimpleFuture> SimpleFuture for JoinAll<F> {
+    type Output = Vec<F::Output>;
+    fn poll(&mut self, wake: fn()) -> Poll<Self::Output> {
+        let mut all_done = true;
+        for elem in &mut self.elems {
+            // Don't short-circuit: every pending future gets polled.
+            all_done &= elem.poll(wake);
+        }
+
+        if all_done {
+            Poll::Ready(self.elems.iter_mut().map(MaybeDone::take_output).collect())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+macro_rules! join_n {
+    ($(#[$attr:meta])* $name:ident, $fn_name:ident, $($F:ident . $f:ident),+) => {
+        $(#[$attr])*
+        pub struct $name<$($F: SimpleFuture),+> {
+            $($f: MaybeDone<$F>,)+
+        }
+
+        pub fn $fn_name<$($F: SimpleFuture),+>($($f: $F),+) -> $name<$($F),+> {
+            $name { $($f: MaybeDone::Pending($f),)+ }
+        }
+
+        impl<$($F: SimpleFuture),+> SimpleFuture for $name<$($F),+> {
+            type Output = ($($F::Output,)+);
+            fn poll(&mut self, wake: fn()) -> Poll<Self::Output> {
+                let mut all_done = true;
+                $(all_done &= self.$f.poll(wake);)+
+
+                if all_done {
+                    Poll::Ready(($(self.$f.take_output(),)+))
+                } else {
+                    Poll::Pending
+                }
+            }
+        }
+    };
+}
+
+join_n!(
+    /// A SimpleFuture that runs three futures to completion concurrently.
+    Join3, join3, A.a, B.b, C.c
+);
+join_n!(
+    /// A SimpleFuture that runs four futures to completion concurrently.
+    Join4, join4, A.a, B.b, C.c, D.d
+);
+
+pub mod real {
+    //! `JoinAll`, `Join3` and `Join4` for `std::future::Future`.
+    //!
+    //! To keep the examples free of `unsafe` pin projections, the sub-futures
+    //! must be `Unpin`. Futures which aren't, such as `async` blocks, can be
+    //! joined after pinning them with `Box::pin`.
+
+    use std::{
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    enum MaybeDone<F: Future> {
+        Pending(F),
+        Done(F::Output),
+        Gone,
+    }
+
+    impl<F: Future + Unpin> MaybeDone<F> {
+        fn poll(&mut self, cx: &mut Context<'_>) -> bool {
+            if let MaybeDone::Pending(future) = self {
+                if let Poll::Ready(output) = Pin::new(future).poll(cx) {
+                    *self = MaybeDone::Done(output);
+                }
+            }
+            !matches!(self, MaybeDone::Pending(_))
+        }
+
+        fn take_output(&mut self) -> F::Output {
+            match std::mem::replace(self, MaybeDone::Gone) {
+                MaybeDone::Done(output) => output,
+                _ => panic!("join polled after completion"),
+            }
+        }
+    }
+
+    /// A future that runs a list of futures to completion concurrently,
+    /// and returns their outputs in the same order as the list.
+    pub struct JoinAll<F: Future> {
+        // A boxed slice is `Unpin` even if the outputs aren't, so `JoinAll`
+        // is too.
+        elems: Box<[MaybeDone<F>]>,
+    }
+
+    pub fn join_all<I>(futures: I) -> JoinAll<I::Item>
+    where
+        I: IntoIterator,
+        I::Item: Future + Unpin,
+    {
+        JoinAll {
+            elems: futures.into_iter().map(MaybeDone::Pending).collect(),
+        }
+    }
+
+    impl<F: Future + Unpin> Future for JoinAll<F> {
+        type Output = Vec<F::Output>;
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let mut all_done = true;
+            for elem in &mut self.elems {
+                all_done &= elem.poll(cx);
+            }
+
+            if all_done {
+                Poll::Ready(self.elems.iter_mut().map(MaybeDone::take_output).collect())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    macro_rules! join_n {
+        ($(#[$attr:meta])* $name:ident, $fn_name:ident, $($F:ident . $f:ident),+) => {
+            $(#[$attr])*
+            pub struct $name<$($F: Future),+> {
+                $($f: MaybeDone<$F>,)+
+            }
+
+            pub fn $fn_name<$($F: Future + Unpin),+>($($f: $F),+) -> $name<$($F),+> {
+                $name { $($f: MaybeDone::Pending($f),)+ }
+            }
+
+            // The outputs are never pinned, so only the futures need to be
+            // `Unpin` for the join to be.
+            impl<$($F: Future + Unpin),+> Unpin for $name<$($F),+> {}
+
+            impl<$($F: Future + Unpin),+> Future for $name<$($F),+> {
+                type Output = ($($F::Output,)+);
+                fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+                    let mut all_done = true;
+                    $(all_done &= self.$f.poll(cx);)+
+
+                    if all_done {
+                        Poll::Ready(($(self.$f.take_output(),)+))
+                    } else {
+                        Poll::Pending
+                    }
+                }
+            }
+        };
+    }
+
+    join_n!(
+        /// A future that runs three futures to completion concurrently.
+        Join3, join3, A.a, B.b, C.c
+    );
+    join_n!(
+        /// A future that runs four futures to completion concurrently.
+        Join4, join4, A.a, B.b, C.c, D.d
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{noop_wake, Countdown};
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn join_all_polls_in_order_and_keeps_output_order() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut join = join_all(vec![
+            Countdown::new("a", 3, 'a', &log),
+            Countdown::new("b", 1, 'b', &log),
+            Countdown::new("c", 2, 'c', &log),
+        ]);
+
+        assert!(matches!(join.poll(noop_wake), Poll::Pending));
+        assert_eq!(*log.borrow(), ["a", "b", "c"]);
+
+        // `b` has completed, so only `a` and `c` are polled again.
+        assert!(matches!(join.poll(noop_wake), Poll::Pending));
+        assert_eq!(log.borrow()[3..], ["a", "c"]);
+
+        match join.poll(noop_wake) {
+            Poll::Ready(outputs) => assert_eq!(outputs, ['a', 'b', 'c']),
+            Poll::Pending => panic!("all futures should have completed"),
+        }
+        assert_eq!(log.borrow()[5..], ["a"]);
+    }
+
+    #[test]
+    fn join_all_empty_is_ready() {
+        let mut join = join_all(Vec::<Countdown<()>>::new());
+        assert!(matches!(join.poll(noop_wake), Poll::Ready(v) if v.is_empty()));
+    }
+
+    #[test]
+    fn join_n_returns_typed_outputs() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut join = join4(
+            Countdown::new("a", 2, 1u8, &log),
+            Countdown::new("b", 1, "two", &log),
+            Countdown::new("c", 2, 3.0f32, &log),
+            Countdown::new("d", 1, '4', &log),
+        );
+
+        assert!(matches!(join.poll(noop_wake), Poll::Pending));
+        match join.poll(noop_wake) {
+            Poll::Ready(outputs) => assert_eq!(outputs, (1, "two", 3.0, '4')),
+            Poll::Pending => panic!("all futures should have completed"),
+        }
+        assert_eq!(*log.borrow(), ["a", "b", "c", "d", "a", "c"]);
+
+        let mut join = join3(
+            Countdown::new("a", 1, (), &log),
+            Countdown::new("b", 1, (), &log),
+            Countdown::new("c", 1, (), &log),
+        );
+        assert!(matches!(join.poll(noop_wake), Poll::Ready(((), (), ()))));
+    }
+
+    #[test]
+    fn join_does_not_starve_slow_futures() {
+        // A future which is never ready doesn't stop the others from being
+        // polled to completion.
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut join = join3(
+            Countdown::new("never", usize::MAX, (), &log),
+            Countdown::new("b", 3, (), &log),
+            Countdown::new("c", 2, (), &log),
+        );
+        for _ in 0..3 {
+            assert!(matches!(join.poll(noop_wake), Poll::Pending));
+        }
+        let polls = |name| log.borrow().iter().filter(|n| **n == name).count();
+        assert_eq!((polls("never"), polls("b"), polls("c")), (3, 3, 2));
+    }
+
+    mod real {
+        use super::super::real::*;
+        use crate::test_util::yield_now;
+        use futures::{
+            executor::block_on,
+            future::{self, FutureExt},
+        };
+        use std::{cell::RefCell, rc::Rc};
+
+        #[test]
+        fn join_all_collects_outputs_in_order() {
+            let log = Rc::new(RefCell::new(Vec::new()));
+            let futures = (0..4).map(|i| {
+                let log = log.clone();
+                async move {
+                    // Yield `4 - i` times, so later futures complete first.
+                    for _ in i..4 {
+                        yield_now().await;
+                    }
+                    log.borrow_mut().push(i);
+                    i * 10
+                }
+                .boxed_local()
+            });
+            assert_eq!(block_on(join_all(futures)), [0, 10, 20, 30]);
+            assert_eq!(*log.borrow(), [3, 2, 1, 0]);
+        }
+
+        #[test]
+        fn join_n_returns_typed_outputs() {
+            let outputs = block_on(join4(
+                future::ready(1u8),
+                future::ready("two"),
+                Box::pin(async { 3.0f32 }),
+                future::ready('4'),
+            ));
+            assert_eq!(outputs, (1, "two", 3.0, '4'));
+            let outputs = block_on(join3(future::ready(()), future::ready(()), future::ready(())));
+            assert_eq!(outputs, ((), (), ()));
+        }
+    }
+}
@@ -1,10 +1,10 @@
 // ANCHOR: simple_future
-trait SimpleFuture {
+pub trait SimpleFuture {
     type Output;
     fn poll(&mut self, wake: fn()) -> Poll<Self::Output>;
 }
 
-enum Poll<T> {
+pub enum Poll<T> {
     Ready(T),
     Pending,
 }
@@ -162,3 +162,12 @@ impl<O> Future for dyn RealFuture<Output = O> {
     }
 }
 }
+
+pub mod combinators;
+pub mod compat;
+pub mod fuse;
+pub mod join;
+pub mod select;
+
+#[cfg(test)]
+mod test_util;
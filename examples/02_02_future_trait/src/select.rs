@@ -0,0 +1,317 @@
+//! Racing futures against each other.
+//!
+//! `Select` completes as soon as either of its futures does, returning that
+//! future's output along with the other future, which may still be polled to
+//! completion or simply dropped to cancel it. `SelectAll` does the same for
+//! a list of futures, returning the index of the one which completed and
+//! the rest of the list.
+//!
+//! Both are biased: on each `poll`, the futures are polled in order, so if
+//! more than one is ready at the same time, the first one wins. The later
+//! futures are still polled whenever the earlier ones are pending, so they
+//! can't be starved by an earlier future which never completes.
+//!
+//! The `real` module has the same combinators for `std::future::Future`.
+
+use crate::{Poll, SimpleFuture};
+
+/// The result of a `Select`: which future completed first, its output, and
+/// the future which didn't complete.
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// A SimpleFuture that waits for either of two futures to complete.
+pub struct Select<FutureA, FutureB> {
+    // `None` once `Select` has completed and handed the futures back.
+    inner: Option<(FutureA, FutureB)>,
+}
+
+pub fn select<FutureA, FutureB>(a: FutureA, b: FutureB) -> Select<FutureA, FutureB>
+where
+    FutureA: SimpleFuture,
+    FutureB: SimpleFuture,
+{
+    Select {
+        inner: Some((a, b)),
+    }
+}
+
+impl<FutureA, FutureB> SimpleFuture for Select<FutureA, FutureB>
+where
+    FutureA: SimpleFuture,
+    FutureB: SimpleFuture,
+{
+    type Output = Either<(FutureA::Output, FutureB), (FutureB::Output, FutureA)>;
+    fn poll(&mut self, wake: fn()) -> Poll<Self::Output> {
+        let (a, b) = self.inner.as_mut().expect("select polled after completion");
+
+        if let Poll::Ready(output) = a.poll(wake) {
+            let (_, b) = self.inner.take().unwrap();
+            return Poll::Ready(Either::Left((output, b)));
+        }
+
+        if let Poll::Ready(output) = b.poll(wake) {
+            let (a, _) = self.inner.take().unwrap();
+            return Poll::Ready(Either::Right((output, a)));
+        }
+
+        Poll::Pending
+    }
+}
+
+/// A SimpleFuture that waits for any of a list of futures to complete.
+pub struct SelectAll<F> {
+    // Empty once `SelectAll` has completed and handed the futures back.
+    inner: Vec<F>,
+}
+
+/// Wait for the first of `futures` to complete.
+///
+/// The output is the completed future's output, its index in `futures`, and
+/// the remaining futures in their original order.
+///
+/// # Panics
+///
+/// Panics if `futures` is empty, since then there is nothing to wait for.
+pub fn select_all<I>(futures: I) -> SelectAll<I::Item>
+where
+    I: IntoIterator,
+    I::Item: SimpleFuture,
+{
+    let inner: Vec<_> = futures.into_iter().collect();
+    assert!(!inner.is_empty(), "select_all needs at least one future");
+    SelectAll { inner }
+}
+
+impl<F: SimpleFuture> SimpleFuture for SelectAll<F> {
+    type Output = (F::Output, usize, Vec<F>);
+    fn poll(&mut self, wake: fn()) -> Poll<Self::Output> {
+        assert!(!self.inner.is_empty(), "select_all polled after completion");
+        for i in 0..self.inner.len() {
+            if let Poll::Ready(output) = self.inner[i].poll(wake) {
+                let mut rest = std::mem::take(&mut self.inner);
+                rest.remove(i);
+                return Poll::Ready((output, i, rest));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+pub mod real {
+    //! `Select` for `std::future::Future`.
+    //!
+    //! As with `join::real`, both futures must be `Unpin` so they can be
+    //! handed back without pinning `Select` itself in place.
+
+    use std::{
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    pub use super::Either;
+
+    /// A future that waits for either of two futures to complete.
+    pub struct Select<FutureA, FutureB> {
+        inner: Option<(FutureA, FutureB)>,
+    }
+
+    pub fn select<FutureA, FutureB>(a: FutureA, b: FutureB) -> Select<FutureA, FutureB>
+    where
+        FutureA: Future + Unpin,
+        FutureB: Future + Unpin,
+    {
+        Select {
+            inner: Some((a, b)),
+        }
+    }
+
+    impl<FutureA, FutureB> Future for Select<FutureA, FutureB>
+    where
+        FutureA: Future + Unpin,
+        FutureB: Future + Unpin,
+    {
+        type Output = Either<(FutureA::Output, FutureB), (FutureB::Output, FutureA)>;
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let (a, b) = self.inner.as_mut().expect("select polled after completion");
+
+            if let Poll::Ready(output) = Pin::new(a).poll(cx) {
+                let (_, b) = self.inner.take().unwrap();
+                return Poll::Ready(Either::Left((output, b)));
+            }
+
+            if let Poll::Ready(output) = Pin::new(b).poll(cx) {
+                let (a, _) = self.inner.take().unwrap();
+                return Poll::Ready(Either::Right((output, a)));
+            }
+
+            Poll::Pending
+        }
+    }
+
+    /// A future that waits for any of a list of futures to complete.
+    pub struct SelectAll<F> {
+        inner: Vec<F>,
+    }
+
+    pub fn select_all<I>(futures: I) -> SelectAll<I::Item>
+    where
+        I: IntoIterator,
+        I::Item: Future + Unpin,
+    {
+        let inner: Vec<_> = futures.into_iter().collect();
+        assert!(!inner.is_empty(), "select_all needs at least one future");
+        SelectAll { inner }
+    }
+
+    impl<F: Future + Unpin> Future for SelectAll<F> {
+        type Output = (F::Output, usize, Vec<F>);
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            assert!(!self.inner.is_empty(), "select_all polled after completion");
+            for i in 0..self.inner.len() {
+                if let Poll::Ready(output) = Pin::new(&mut self.inner[i]).poll(cx) {
+                    let mut rest = std::mem::take(&mut self.inner);
+                    rest.remove(i);
+                    return Poll::Ready((output, i, rest));
+                }
+            }
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{noop_wake, Countdown};
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn first_future_wins_a_tie() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut select = select(Countdown::new("a", 1, 'a', &log), Countdown::new("b", 1, 'b', &log));
+        match select.poll(noop_wake) {
+            Poll::Ready(Either::Left(('a', _))) => {}
+            _ => panic!("`a` should win"),
+        }
+        // `b` is never polled once `a` is ready.
+        assert_eq!(*log.borrow(), ["a"]);
+    }
+
+    #[test]
+    fn second_future_is_not_starved() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut select = select(
+            Countdown::new("never", usize::MAX, 'a', &log),
+            Countdown::new("b", 2, 'b', &log),
+        );
+        assert!(matches!(select.poll(noop_wake), Poll::Pending));
+        let remaining = match select.poll(noop_wake) {
+            Poll::Ready(Either::Right(('b', remaining))) => remaining,
+            _ => panic!("`b` should win"),
+        };
+        assert_eq!(*log.borrow(), ["never", "b", "never", "b"]);
+
+        // The loser is handed back and can still be polled.
+        let mut remaining = remaining;
+        assert!(matches!(remaining.poll(noop_wake), Poll::Pending));
+        assert_eq!(log.borrow().len(), 5);
+    }
+
+    #[test]
+    fn remaining_future_can_be_completed() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut select = select(Countdown::new("a", 3, 1, &log), Countdown::new("b", 1, 2, &log));
+        let mut a = match select.poll(noop_wake) {
+            Poll::Ready(Either::Right((2, a))) => a,
+            _ => panic!("`b` should win"),
+        };
+        assert!(matches!(a.poll(noop_wake), Poll::Pending));
+        assert!(matches!(a.poll(noop_wake), Poll::Ready(1)));
+    }
+
+    #[test]
+    fn select_all_returns_index_and_rest() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut select = select_all([
+            Countdown::new("a", 3, 'a', &log),
+            Countdown::new("b", 2, 'b', &log),
+            Countdown::new("c", 2, 'c', &log),
+        ]);
+        assert!(matches!(select.poll(noop_wake), Poll::Pending));
+        let mut rest = match select.poll(noop_wake) {
+            // `b` and `c` are ready together, and `b` comes first.
+            Poll::Ready(('b', 1, rest)) => rest,
+            _ => panic!("`b` should win"),
+        };
+        assert_eq!(*log.borrow(), ["a", "b", "c", "a", "b"]);
+
+        assert_eq!(rest.len(), 2);
+        assert!(matches!(rest[0].poll(noop_wake), Poll::Ready('a')));
+        assert!(matches!(rest[1].poll(noop_wake), Poll::Ready('c')));
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one future")]
+    fn select_all_of_nothing_panics() {
+        select_all(Vec::<Countdown<()>>::new());
+    }
+
+    mod real {
+        use super::super::real::*;
+        use crate::test_util::yield_now;
+        use futures::{
+            executor::block_on,
+            future::{self, FutureExt},
+        };
+
+        #[test]
+        fn first_future_wins_a_tie() {
+            match block_on(select(future::ready(1), future::ready(2))) {
+                Either::Left((1, _)) => {}
+                _ => panic!("the first future should win"),
+            }
+        }
+
+        #[test]
+        fn faster_future_wins_and_loser_is_returned() {
+            let slow = async {
+                for _ in 0..3 {
+                    yield_now().await;
+                }
+                "slow"
+            }
+            .boxed();
+            let fast = async {
+                yield_now().await;
+                "fast"
+            }
+            .boxed();
+            let slow = match block_on(select(slow, fast)) {
+                Either::Right(("fast", slow)) => slow,
+                _ => panic!("the fast future should win"),
+            };
+            assert_eq!(block_on(slow), "slow");
+        }
+
+        #[test]
+        fn select_all_waits_for_the_fastest() {
+            let slow = |yields: usize, output| {
+                async move {
+                    for _ in 0..yields {
+                        yield_now().await;
+                    }
+                    output
+                }
+                .boxed()
+            };
+            let (output, index, rest) =
+                block_on(select_all(vec![slow(3, "three"), slow(1, "one"), slow(2, "two")]));
+            assert_eq!((output, index), ("one", 1));
+            assert_eq!(block_on(future::join_all(rest)), ["three", "two"]);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Futures shared by the tests of the combinator modules.
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    task::{self, Context},
+};
+
+use crate::{Poll, SimpleFuture};
+
+/// A `wake` function for tests which poll futures by hand.
+pub fn noop_wake() {}
+
+/// A SimpleFuture which completes with `output` on its `polls`th poll, and
+/// records its name in `log` every time it is polled.
+pub struct Countdown<T> {
+    name: &'static str,
+    remaining: usize,
+    output: Option<T>,
+    log: Rc<RefCell<Vec<&'static str>>>,
+}
+
+impl<T> Countdown<T> {
+    pub fn new(
+        name: &'static str,
+        polls: usize,
+        output: T,
+        log: &Rc<RefCell<Vec<&'static str>>>,
+    ) -> Self {
+        Countdown {
+            name,
+            remaining: polls,
+            output: Some(output),
+            log: log.clone(),
+        }
+    }
+}
+
+impl<T> SimpleFuture for Countdown<T> {
+    type Output = T;
+    fn poll(&mut self, _wake: fn()) -> Poll<Self::Output> {
+        self.log.borrow_mut().push(self.name);
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            Poll::Ready(self.output.take().expect("polled after completion"))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// A future which is pending the first time it is polled, and immediately
+/// wakes its task so that it is polled again.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> task::Poll<Self::Output> {
+        if self.yielded {
+            task::Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            task::Poll::Pending
+        }
+    }
+}
@@ -0,0 +1,365 @@
+//! Hand-written versions of the state machines the compiler generates for
+//! `async fn`s.
+//!
+//! Each `async fn` becomes an enum with one variant for each point at which
+//! it can be suspended, plus `Unresumed` (created, but not yet polled) and
+//! `Returned`. A variant holds the sub-future being awaited and every local
+//! variable which is still alive at that `.await`.
+//!
+//! The sub-futures here are named types rather than `async fn`s, since the
+//! futures returned by `async fn`s can't be named in a struct or enum.
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    marker::PhantomPinned,
+    pin::Pin,
+    ptr,
+    task::{Context, Poll},
+};
+
+thread_local! {
+    /// Records what the futures in this module do, so that the compiler's
+    /// futures and ours can be checked to behave identically.
+    static EVENTS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+fn record(event: impl Into<String>) {
+    EVENTS.with(|events| events.borrow_mut().push(event.into()));
+}
+
+fn take_events() -> Vec<String> {
+    EVENTS.with(|events| events.take())
+}
+
+/// A future which is pending the first time it is polled (waking its task
+/// immediately), then completes with `output`.
+///
+/// This is a stand-in for real asynchronous work, so that the state machines
+/// below are actually suspended at each `.await`.
+pub struct YieldThen<T> {
+    output: Option<T>,
+    yielded: bool,
+}
+
+impl<T> YieldThen<T> {
+    fn new(output: T) -> Self {
+        YieldThen {
+            output: Some(output),
+            yielded: false,
+        }
+    }
+}
+
+impl<T: Unpin> Future for YieldThen<T> {
+    type Output = T;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        if !self.yielded {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        Poll::Ready(self.output.take().expect("polled after completion"))
+    }
+}
+
+pub struct Song;
+
+// `learn_song` and `sing_song` from `01_04_async_await_primer`.
+fn learn_song() -> YieldThen<Song> {
+    record("learn_song");
+    YieldThen::new(Song)
+}
+
+fn sing_song(_: Song) -> YieldThen<()> {
+    record("sing_song");
+    YieldThen::new(())
+}
+
+mod learn_and_sing {
+use super::*;
+
+// What we write:
+pub async fn learn_and_sing() {
+    let song = learn_song().await;
+    sing_song(song).await;
+}
+
+// What the compiler generates, more or less:
+pub fn learn_and_sing_expanded() -> LearnAndSing {
+    LearnAndSing::Unresumed
+}
+
+pub enum LearnAndSing {
+    /// `learn_and_sing()` has been called, but not yet polled.
+    Unresumed,
+    /// Suspended at `learn_song().await`.
+    Suspend0(YieldThen<Song>),
+    /// Suspended at `sing_song(song).await`. `song` has been moved into the
+    /// future, so it isn't stored separately.
+    Suspend1(YieldThen<()>),
+    /// The body has run to completion.
+    Returned,
+}
+
+impl Future for LearnAndSing {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // Each iteration runs the body from one `.await` to the next, until
+        // a sub-future is pending or the body returns.
+        loop {
+            // SAFETY: we never move out of the pinned enum. Sub-futures are
+            // only accessed through `Pin::new_unchecked`, and are only
+            // replaced with `Pin::set`, which drops them in place.
+            match unsafe { self.as_mut().get_unchecked_mut() } {
+                LearnAndSing::Unresumed => {
+                    self.set(LearnAndSing::Suspend0(learn_song()));
+                }
+                LearnAndSing::Suspend0(fut) => {
+                    let song = match unsafe { Pin::new_unchecked(fut) }.poll(cx) {
+                        Poll::Ready(song) => song,
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    self.set(LearnAndSing::Suspend1(sing_song(song)));
+                }
+                LearnAndSing::Suspend1(fut) => {
+                    match unsafe { Pin::new_unchecked(fut) }.poll(cx) {
+                        Poll::Ready(()) => {}
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    self.set(LearnAndSing::Returned);
+                    return Poll::Ready(());
+                }
+                LearnAndSing::Returned => panic!("`async fn` resumed after completion"),
+            }
+        }
+    }
+}
+}
+
+mod blocks {
+use super::*;
+
+// What we write (`blocks` from `async_move_examples`, but recording rather
+// than printing, and with `future_one` suspended once):
+pub async fn blocks() {
+    let my_string = "foo".to_string();
+
+    let future_one = async {
+        YieldThen::new(()).await;
+        record(format!("one: {my_string}"));
+    };
+
+    let future_two = async {
+        record(format!("two: {my_string}"));
+    };
+
+    let ((), ()) = futures::join!(future_one, future_two);
+}
+
+// What the compiler generates, more or less:
+pub fn blocks_expanded() -> Blocks {
+    Blocks::Unresumed
+}
+
+/// The state machine for `blocks`.
+///
+/// While suspended, the `async` blocks borrow `my_string`, which is stored in
+/// the same enum variant: the future is self-referential. That's sound only
+/// because the future is pinned, so `my_string` never moves while the
+/// pointers to it exist.
+pub enum Blocks {
+    Unresumed,
+    /// Suspended at the `join!`.
+    Suspend0 {
+        // Fields are dropped in declaration order, so the blocks borrowing
+        // `my_string` are dropped before it is.
+        future_one: Option<FutureOne>,
+        future_two: Option<FutureTwo>,
+        my_string: String,
+        _pinned: PhantomPinned,
+    },
+    Returned,
+}
+
+/// The first `async` block in `blocks`.
+pub enum FutureOne {
+    Unresumed { my_string: *const String },
+    Suspend0 { my_string: *const String, fut: YieldThen<()> },
+    Returned,
+}
+
+/// The second `async` block in `blocks`.
+pub enum FutureTwo {
+    Unresumed { my_string: *const String },
+    Returned,
+}
+
+// SAFETY: the raw pointers in the `async` blocks are really `&String`s
+// borrowed from the same `Blocks`, and `&String` is `Send`. The compiler
+// knows this about its own futures, but can't for ours.
+unsafe impl Send for Blocks {}
+
+impl Future for Blocks {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // SAFETY: as for `LearnAndSing`. The fields of `Suspend0` are never
+        // moved; it is only ever replaced as a whole, through `Pin::set`.
+        if let Blocks::Unresumed = unsafe { self.as_mut().get_unchecked_mut() } {
+            self.set(Blocks::Suspend0 {
+                future_one: None,
+                future_two: None,
+                my_string: "foo".to_string(),
+                _pinned: PhantomPinned,
+            });
+            // Now that `my_string` is at its final address, the blocks can
+            // borrow it.
+            if let Blocks::Suspend0 {
+                future_one,
+                future_two,
+                my_string,
+                ..
+            } = unsafe { self.as_mut().get_unchecked_mut() }
+            {
+                *future_one = Some(FutureOne::Unresumed { my_string: ptr::from_ref(my_string) });
+                *future_two = Some(FutureTwo::Unresumed { my_string: ptr::from_ref(my_string) });
+            }
+        }
+
+        match unsafe { self.as_mut().get_unchecked_mut() } {
+            Blocks::Suspend0 {
+                future_one,
+                future_two,
+                ..
+            } => {
+                // `join!` polls each unfinished future in turn, and finishes
+                // once both have.
+                if let Some(fut) = future_one {
+                    if unsafe { Pin::new_unchecked(fut) }.poll(cx).is_ready() {
+                        *future_one = None;
+                    }
+                }
+                if let Some(fut) = future_two {
+                    if unsafe { Pin::new_unchecked(fut) }.poll(cx).is_ready() {
+                        *future_two = None;
+                    }
+                }
+                if future_one.is_some() || future_two.is_some() {
+                    return Poll::Pending;
+                }
+            }
+            Blocks::Unresumed => unreachable!(),
+            Blocks::Returned => panic!("`async fn` resumed after completion"),
+        }
+
+        // Dropping `my_string` at the end of the body.
+        self.set(Blocks::Returned);
+        Poll::Ready(())
+    }
+}
+
+impl Future for FutureOne {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            // SAFETY: as for `LearnAndSing`.
+            match unsafe { self.as_mut().get_unchecked_mut() } {
+                FutureOne::Unresumed { my_string } => {
+                    let my_string = *my_string;
+                    self.set(FutureOne::Suspend0 {
+                        my_string,
+                        fut: YieldThen::new(()),
+                    });
+                }
+                FutureOne::Suspend0 { my_string, fut } => {
+                    if unsafe { Pin::new_unchecked(fut) }.poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    // SAFETY: `my_string` points into the `Blocks` which owns
+                    // this future, and is pinned for as long as we exist.
+                    let my_string = unsafe { &**my_string };
+                    record(format!("one: {my_string}"));
+                    self.set(FutureOne::Returned);
+                    return Poll::Ready(());
+                }
+                FutureOne::Returned => panic!("`async` block resumed after completion"),
+            }
+        }
+    }
+}
+
+impl Future for FutureTwo {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        // SAFETY: as for `LearnAndSing`.
+        match unsafe { self.as_mut().get_unchecked_mut() } {
+            FutureTwo::Unresumed { my_string } => {
+                // SAFETY: as for `FutureOne`.
+                let my_string = unsafe { &**my_string };
+                record(format!("two: {my_string}"));
+                self.set(FutureTwo::Returned);
+                Poll::Ready(())
+            }
+            FutureTwo::Returned => panic!("`async` block resumed after completion"),
+        }
+    }
+}
+}
+
+pub(super) fn audit(report: &mut future_audit::Report) {
+    future_audit::audit!(report, learn_and_sing::learn_and_sing(), 16);
+    future_audit::audit!(report, learn_and_sing::learn_and_sing_expanded(), 16);
+    future_audit::audit!(report, blocks::blocks(), 256);
+    future_audit::audit!(report, blocks::blocks_expanded(), 128);
+}
+
+/// Poll `fut` to completion on the current thread, recording each poll's
+/// result, then return everything that was recorded.
+fn trace(fut: impl Future<Output = ()>) -> Vec<String> {
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = std::pin::pin!(fut);
+    take_events();
+    while fut.as_mut().poll(&mut cx).is_pending() {
+        record("pending");
+    }
+    record("ready");
+    take_events()
+}
+
+#[test]
+fn learn_and_sing_behaves_identically() {
+    use learn_and_sing::*;
+
+    let expected = ["learn_song", "pending", "sing_song", "pending", "ready"];
+    assert_eq!(trace(learn_and_sing()), expected);
+    assert_eq!(trace(learn_and_sing_expanded()), expected);
+}
+
+#[test]
+fn blocks_behaves_identically() {
+    use blocks::*;
+
+    let expected = ["two: foo", "pending", "one: foo", "ready"];
+    assert_eq!(trace(blocks()), expected);
+    assert_eq!(trace(blocks_expanded()), expected);
+}
+
+#[test]
+fn sizes_compared_to_the_compiler() {
+    use std::mem::{size_of, size_of_val};
+
+    // `learn_and_sing` only ever holds one sub-future at a time, so the
+    // compiler overlaps them, just like the variants of our enum.
+    let compiled = learn_and_sing::learn_and_sing();
+    assert_eq!(size_of_val(&compiled), size_of::<learn_and_sing::LearnAndSing>());
+
+    // `blocks` holds `my_string` and both `async` blocks at once. Ours is
+    // no bigger than the compiler's: `join!` moves `future_one` and
+    // `future_two` into storage of its own, and the compiler doesn't always
+    // manage to overlap that with the space for the original variables.
+    let compiled = blocks::blocks();
+    let expanded = size_of::<blocks::Blocks>();
+    assert!(expanded >= size_of::<String>() + 2 * size_of::<&String>());
+    assert!(expanded <= size_of_val(&compiled));
+}
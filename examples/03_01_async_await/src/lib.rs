@@ -18,6 +18,11 @@ fn bar() -> impl Future<Output = u8> {
     }
 }
 // ANCHOR_END: async_fn_and_block_examples
+
+pub(super) fn audit(report: &mut future_audit::Report) {
+    future_audit::audit!(report, foo(), 8);
+    future_audit::audit!(report, bar(), 16);
+}
 }
 
 mod async_lifetimes_examples {
@@ -48,6 +53,14 @@ fn good() -> impl Future<Output = u8> {
     }
 }
 // ANCHOR_END: static_future_with_borrow
+
+pub(super) fn audit(report: &mut future_audit::Report) {
+    let x = 5;
+    future_audit::audit!(report, foo(&x), 32);
+    future_audit::audit!(report, foo_expanded(&x), 32);
+    future_audit::audit!(report, borrow_x(&x), 32);
+    future_audit::audit!(report, good(), 64);
+}
 }
 
 mod async_move_examples {
@@ -88,4 +101,31 @@ fn move_block() -> impl Future<Output = ()> {
     }
 }
 // ANCHOR_END: async_move_examples
+
+pub(super) fn audit(report: &mut future_audit::Report) {
+    future_audit::audit!(report, blocks(), 256);
+    future_audit::audit!(report, move_block(), 64);
+}
+}
+
+mod desugar;
+
+#[test]
+fn future_sizes() {
+    use future_audit::Report;
+
+    let mut report = Report::new("03_01_async_await");
+    async_fn_and_block_examples::audit(&mut report);
+    async_lifetimes_examples::audit(&mut report);
+    async_move_examples::audit(&mut report);
+    desugar::audit(&mut report);
+    report.assert_all_send();
+
+    // A future holds the futures it's waiting on, and anything it borrows
+    // from across an `.await`.
+    assert!(report.size("bar()") > report.size("foo()"));
+    assert!(report.size("good()") > report.size("borrow_x(&x)"));
+    assert!(report.size("blocks()") > report.size("move_block()"));
+    // The "equivalent" desugaring really is the same future.
+    assert_eq!(report.size("foo(&x)"), report.size("foo_expanded(&x)"));
 }
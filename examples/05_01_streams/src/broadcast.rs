@@ -0,0 +1,398 @@
+//! A broadcast channel: every receiver gets a copy of every message.
+//!
+//! Sending never waits. Messages are kept in a ring buffer of a fixed size,
+//! so a receiver which falls too far behind misses the oldest messages. It
+//! finds out how many it missed from a `Lagged` error, and carries on from
+//! the oldest message which is still in the buffer.
+
+use futures::stream::{FusedStream, Stream};
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+/// Create a broadcast channel which keeps up to `capacity` messages for
+/// receivers which haven't seen them yet.
+///
+/// # Panics
+///
+/// If `capacity` is 0.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be at least 1");
+    let shared = Arc::new(Mutex::new(State {
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+        head: 0,
+        senders: 1,
+        receivers: 0,
+        next_id: 0,
+        wakers: HashMap::new(),
+    }));
+    let sender = Sender { shared };
+    let receiver = sender.subscribe();
+    (sender, receiver)
+}
+
+struct State<T> {
+    buffer: VecDeque<T>,
+    capacity: usize,
+    /// The position of `buffer[0]` in the sequence of all messages sent.
+    head: u64,
+    senders: usize,
+    receivers: usize,
+    next_id: u64,
+    /// The wakers of receivers waiting for a message, by receiver id.
+    wakers: HashMap<u64, Waker>,
+}
+
+impl<T> State<T> {
+    /// The position of the next message to be sent.
+    fn tail(&self) -> u64 {
+        self.head + self.buffer.len() as u64
+    }
+
+    /// Take the wakers of every waiting receiver. They're woken once the
+    /// lock has been released, since a waker might use the channel again.
+    fn take_wakers(&mut self) -> Vec<Waker> {
+        self.wakers.drain().map(|(_, waker)| waker).collect()
+    }
+}
+
+fn wake_all(wakers: Vec<Waker>) {
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+/// The sending half of a broadcast channel.
+pub struct Sender<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Send a message to every receiver, returning how many there are.
+    ///
+    /// If the buffer is full, the oldest message is dropped to make room.
+    /// Fails, returning the message, if there are no receivers.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let mut state = self.shared.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(SendError(value));
+        }
+        if state.buffer.len() == state.capacity {
+            state.buffer.pop_front();
+            state.head += 1;
+        }
+        state.buffer.push_back(value);
+        let wakers = state.take_wakers();
+        let receivers = state.receivers;
+        drop(state);
+        wake_all(wakers);
+        Ok(receivers)
+    }
+
+    /// Create a receiver which gets every message sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut state = self.shared.lock().unwrap();
+        state.receivers += 1;
+        let id = state.next_id;
+        state.next_id += 1;
+        Receiver {
+            shared: self.shared.clone(),
+            id,
+            next: state.tail(),
+            terminated: false,
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.lock().unwrap().receivers
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            let wakers = state.take_wakers();
+            drop(state);
+            wake_all(wakers);
+        }
+    }
+}
+
+/// The receiving half of a broadcast channel.
+///
+/// It is a `Stream` which yields `Ok` for each message, or `Err(Lagged(n))`
+/// if it missed `n` messages, and ends once every `Sender` has been dropped
+/// and it has received every message left in the buffer.
+pub struct Receiver<T> {
+    shared: Arc<Mutex<State<T>>>,
+    id: u64,
+    /// The position of the next message we'll receive.
+    next: u64,
+    terminated: bool,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Receive the next message. Equivalent to `StreamExt::next`.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<T, Lagged>>> {
+        if self.terminated {
+            return Poll::Ready(None);
+        }
+        let mut state = self.shared.lock().unwrap();
+        if self.next < state.head {
+            // Our next message has been overwritten. Skip to the oldest
+            // message we still have.
+            let missed = state.head - self.next;
+            self.next = state.head;
+            return Poll::Ready(Some(Err(Lagged(missed))));
+        }
+        if self.next < state.tail() {
+            let value = state.buffer[(self.next - state.head) as usize].clone();
+            self.next += 1;
+            return Poll::Ready(Some(Ok(value)));
+        }
+        if state.senders == 0 {
+            self.terminated = true;
+            return Poll::Ready(None);
+        }
+        state.wakers.insert(self.id, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.receivers -= 1;
+        state.wakers.remove(&self.id);
+    }
+}
+
+impl<T: Clone> Stream for Receiver<T> {
+    type Item = Result<T, Lagged>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.terminated {
+            return (0, Some(0));
+        }
+        let state = self.shared.lock().unwrap();
+        let available = (state.tail() - self.next.max(state.head)) as usize;
+        // Whether or not we've lagged, there's at least one item for each
+        // message still in the buffer.
+        (available, None)
+    }
+}
+
+impl<T: Clone> FusedStream for Receiver<T> {
+    fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+}
+
+/// The future returned by `Receiver::recv`.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T: Clone> Future for Recv<'_, T> {
+    type Output = Option<Result<T, Lagged>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
+/// The error returned by `Sender::send` when there are no receivers. It
+/// contains the message which couldn't be sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "broadcasting to a channel with no receivers")
+    }
+}
+
+impl<T: fmt::Debug> Error for SendError<T> {}
+
+/// The error yielded by a `Receiver` which fell so far behind that it
+/// missed some messages. It contains how many it missed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lagged(pub u64);
+
+impl fmt::Display for Lagged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "receiver lagged behind by {} messages", self.0)
+    }
+}
+
+impl Error for Lagged {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::yield_now;
+    use futures::{executor::block_on, future, select, StreamExt};
+
+    #[test]
+    fn every_receiver_gets_every_message() {
+        let (tx, rx1) = channel(4);
+        let rx2 = tx.subscribe();
+        assert_eq!(tx.send(1), Ok(2));
+        assert_eq!(tx.send(2), Ok(2));
+        drop(tx);
+
+        let received = |rx: Receiver<i32>| block_on(rx.map(Result::unwrap).collect::<Vec<_>>());
+        assert_eq!(received(rx1), [1, 2]);
+        assert_eq!(received(rx2), [1, 2]);
+    }
+
+    #[test]
+    fn subscribers_only_get_later_messages() {
+        let (tx, mut rx1) = channel(4);
+        tx.send(1).unwrap();
+        let mut rx2 = tx.subscribe();
+        tx.send(2).unwrap();
+        block_on(async {
+            assert_eq!(rx1.recv().await, Some(Ok(1)));
+            assert_eq!(rx1.recv().await, Some(Ok(2)));
+            assert_eq!(rx2.recv().await, Some(Ok(2)));
+        });
+    }
+
+    #[test]
+    fn slow_receiver_lags() {
+        let (tx, slow) = channel(2);
+        let mut fast = tx.subscribe();
+        for i in 0..5 {
+            tx.send(i).unwrap();
+            assert_eq!(block_on(fast.recv()), Some(Ok(i)));
+        }
+        drop(tx);
+
+        // Only the last two messages are left in the buffer.
+        assert_eq!(slow.size_hint(), (2, None));
+        let received: Vec<_> = block_on(slow.collect());
+        assert_eq!(received, [Err(Lagged(3)), Ok(3), Ok(4)]);
+    }
+
+    #[test]
+    fn send_without_receivers_fails() {
+        let (tx, rx) = channel(1);
+        drop(rx);
+        assert_eq!(tx.receiver_count(), 0);
+        assert_eq!(tx.send(1), Err(SendError(1)));
+        let mut rx = tx.subscribe();
+        tx.send(2).unwrap();
+        assert_eq!(block_on(rx.recv()), Some(Ok(2)));
+    }
+
+    #[test]
+    fn ends_when_senders_are_dropped() {
+        let (tx, mut rx) = channel(1);
+        let tx2 = tx.clone();
+        tx.send(1).unwrap();
+        drop(tx);
+        tx2.send(2).unwrap();
+        drop(tx2);
+        block_on(async {
+            assert_eq!(rx.recv().await, Some(Err(Lagged(1))));
+            assert_eq!(rx.recv().await, Some(Ok(2)));
+            assert_eq!(rx.recv().await, None);
+            assert!(rx.is_terminated());
+            assert_eq!(rx.recv().await, None);
+        });
+    }
+
+    #[test]
+    fn receivers_are_woken_after_unlocking() {
+        use std::task::Wake;
+
+        // A waker which drops a receiver, and so locks the channel, when
+        // it's woken. It would deadlock if the channel were still locked.
+        struct DropReceiver(Mutex<Option<Receiver<u32>>>);
+        impl Wake for DropReceiver {
+            fn wake(self: Arc<Self>) {
+                drop(self.0.lock().unwrap().take());
+            }
+        }
+        let drop_waker = |rx| Waker::from(Arc::new(DropReceiver(Mutex::new(Some(rx)))));
+
+        let (tx, mut rx) = channel(1);
+        let waker = drop_waker(tx.subscribe());
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut rx.recv()).poll(&mut cx).is_pending());
+        assert_eq!(tx.send(1), Ok(2));
+        assert_eq!(tx.receiver_count(), 1);
+
+        // Dropping the last sender wakes the receivers too.
+        assert!(Pin::new(&mut rx.recv()).poll(&mut cx).is_ready());
+        let waker = drop_waker(tx.subscribe());
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut rx.recv()).poll(&mut cx).is_pending());
+        drop(tx);
+        assert!(Pin::new(&mut rx.recv()).poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn select_over_receivers() {
+        // A consumer which handles two broadcast channels at once, like a
+        // service listening for both commands and events.
+        let (commands, mut command_rx) = channel(8);
+        let (events, mut event_rx) = channel(8);
+
+        let producer = async move {
+            for i in 0..3 {
+                commands.send(format!("command {i}")).unwrap();
+                events.send(format!("event {i}")).unwrap();
+                // Let the consumer run between messages.
+                yield_now().await;
+            }
+        };
+        let consumer = async {
+            let mut log = Vec::new();
+            loop {
+                select! {
+                    // Each stream yields `None` once when it ends, and is then
+                    // skipped until the other one ends too.
+                    command = command_rx.next() => log.extend(command.map(Result::unwrap)),
+                    event = event_rx.next() => log.extend(event.map(Result::unwrap)),
+                    complete => break,
+                }
+            }
+            log
+        };
+
+        let ((), mut log) = block_on(future::join(producer, consumer));
+        log.sort();
+        assert_eq!(
+            log,
+            ["command 0", "command 1", "command 2", "event 0", "event 1", "event 2"]
+        );
+    }
+}
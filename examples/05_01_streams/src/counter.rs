@@ -0,0 +1,85 @@
+//! The simplest possible stream: one which is always ready.
+
+use futures::stream::{FusedStream, Stream};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A stream which yields `1..=max`, like the `Counter` iterator from the
+/// standard library's `Iterator` documentation.
+pub struct Counter {
+    count: u32,
+    max: u32,
+}
+
+impl Counter {
+    pub fn new(max: u32) -> Self {
+        Counter { count: 0, max }
+    }
+}
+
+impl Stream for Counter {
+    type Item = u32;
+
+    // `Counter` is `Unpin`, so we can get a `&mut Self` out of the `Pin`
+    // without any `unsafe`.
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<u32>> {
+        if self.count < self.max {
+            self.count += 1;
+            Poll::Ready(Some(self.count))
+        } else {
+            Poll::Ready(None)
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.max - self.count) as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+impl FusedStream for Counter {
+    fn is_terminated(&self) -> bool {
+        self.count == self.max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, StreamExt};
+
+    #[test]
+    fn counts_to_max() {
+        let counter = Counter::new(5);
+        assert_eq!(block_on(counter.collect::<Vec<_>>()), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn size_hint_is_exact() {
+        let mut counter = Counter::new(3);
+        for remaining in (0..=3).rev() {
+            assert_eq!(counter.size_hint(), (remaining, Some(remaining)));
+            assert_eq!(counter.is_terminated(), remaining == 0);
+            block_on(counter.next());
+        }
+    }
+
+    #[test]
+    fn keeps_returning_none() {
+        let mut counter = Counter::new(1);
+        assert_eq!(block_on(counter.next()), Some(1));
+        for _ in 0..3 {
+            assert_eq!(block_on(counter.next()), None);
+            assert!(counter.is_terminated());
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let mut counter = Counter::new(0);
+        assert!(counter.is_terminated());
+        assert_eq!(block_on(counter.next()), None);
+    }
+}
@@ -29,6 +29,17 @@ impl<I> Stream for dyn RealStream<Item = I> {
         RealStream::poll_next(self, cx)
     }
 }
+
+#[test]
+fn counter_implements_stream() {
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut counter: Pin<&mut dyn RealStream<Item = u32>> =
+        std::pin::pin!(crate::counter::Counter::new(2));
+    assert_eq!(Stream::poll_next(counter.as_mut(), &mut cx), Poll::Ready(Some(1)));
+    assert_eq!(Stream::poll_next(counter.as_mut(), &mut cx), Poll::Ready(Some(2)));
+    assert_eq!(Stream::poll_next(counter.as_mut(), &mut cx), Poll::Ready(None));
+}
 }
 
 mod channels {
@@ -57,3 +68,13 @@ async fn send_recv() {
 #[test]
 fn run_send_recv() { futures::executor::block_on(send_recv()) }
 }
+
+mod counter;
+mod lines;
+mod unfold;
+mod mpsc;
+mod broadcast;
+mod watch;
+mod time;
+mod sink;
+mod test_util;
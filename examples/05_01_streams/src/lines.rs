@@ -0,0 +1,195 @@
+//! A stream of the lines read from an `AsyncRead`.
+
+use futures::{
+    io::AsyncRead,
+    stream::{FusedStream, Stream},
+};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A stream of the lines of `reader`, like `BufRead::lines`.
+///
+/// Each line is returned without its trailing `\n` or `\r\n`. Lines which
+/// aren't valid UTF-8 are returned as an `InvalidData` error, and reading
+/// carries on with the next line.
+pub struct Lines<R> {
+    reader: R,
+    /// Bytes which have been read, but not yet returned as a line.
+    buf: Vec<u8>,
+    /// Whether `reader` has reached end of file.
+    eof: bool,
+}
+
+impl<R: AsyncRead> Lines<R> {
+    pub fn new(reader: R) -> Self {
+        Lines {
+            reader,
+            buf: Vec::new(),
+            eof: false,
+        }
+    }
+
+    /// Take the first line out of `buf`, if there is a whole one.
+    fn take_line(&mut self) -> Option<io::Result<String>> {
+        let line = match self.buf.iter().position(|&b| b == b'\n') {
+            Some(i) => {
+                let mut line: Vec<u8> = self.buf.drain(..=i).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                line
+            }
+            // At end of file, whatever is left is the last line.
+            None if self.eof && !self.buf.is_empty() => std::mem::take(&mut self.buf),
+            None => return None,
+        };
+        Some(String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+}
+
+impl<R: AsyncRead> Stream for Lines<R> {
+    type Item = io::Result<String>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // SAFETY: `reader` is structurally pinned: it is never moved out of
+        // `self`, and `Lines` doesn't implement `Drop`. `buf` and `eof` are
+        // never pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut chunk = [0; 1024];
+        loop {
+            if let Some(line) = this.take_line() {
+                return Poll::Ready(Some(line));
+            }
+            if this.eof {
+                // Don't poll `reader` again once it has returned end of file.
+                return Poll::Ready(None);
+            }
+            let reader = unsafe { Pin::new_unchecked(&mut this.reader) };
+            match reader.poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(0)) => this.eof = true,
+                Poll::Ready(Ok(n)) => this.buf.extend_from_slice(&chunk[..n]),
+                Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.is_terminated() {
+            (0, Some(0))
+        } else {
+            (0, None)
+        }
+    }
+}
+
+impl<R: AsyncRead> FusedStream for Lines<R> {
+    fn is_terminated(&self) -> bool {
+        self.eof && self.buf.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, io::Cursor, StreamExt};
+
+    /// A reader which returns one chunk per read, with a `Pending` before
+    /// each one, and panics if it is read from after end of file.
+    struct Chunked {
+        chunks: Vec<&'static [u8]>,
+        ready: bool,
+        eof: bool,
+    }
+
+    impl Chunked {
+        fn new(chunks: &[&'static [u8]]) -> Self {
+            Chunked {
+                chunks: chunks.iter().rev().copied().collect(),
+                ready: false,
+                eof: false,
+            }
+        }
+    }
+
+    impl AsyncRead for Chunked {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            assert!(!self.eof, "read after end of file");
+            if !self.ready {
+                self.ready = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.ready = false;
+            match self.chunks.pop() {
+                Some(chunk) => {
+                    buf[..chunk.len()].copy_from_slice(chunk);
+                    Poll::Ready(Ok(chunk.len()))
+                }
+                None => {
+                    self.eof = true;
+                    Poll::Ready(Ok(0))
+                }
+            }
+        }
+    }
+
+    fn lines(reader: impl AsyncRead + Unpin) -> Vec<String> {
+        block_on(Lines::new(reader).map(Result::unwrap).collect())
+    }
+
+    #[test]
+    fn splits_lines() {
+        let reader = Cursor::new("one\ntwo\r\n\nlast");
+        assert_eq!(lines(reader), ["one", "two", "", "last"]);
+    }
+
+    #[test]
+    fn trailing_newline() {
+        assert_eq!(lines(Cursor::new("one\n")), ["one"]);
+        assert!(lines(Cursor::new("")).is_empty());
+    }
+
+    #[test]
+    fn lines_split_across_reads() {
+        let reader = Chunked::new(&[b"fir", b"st\nsec", b"ond\r", b"\nthird"]);
+        assert_eq!(lines(reader), ["first", "second", "third"]);
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let mut lines = Lines::new(Cursor::new(b"ok\n\xff\nalso ok\n"));
+        block_on(async {
+            assert_eq!(lines.next().await.unwrap().unwrap(), "ok");
+            let err = lines.next().await.unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(lines.next().await.unwrap().unwrap(), "also ok");
+            assert!(lines.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn keeps_returning_none() {
+        // `Chunked` panics if `Lines` reads from it again after end of file.
+        let mut lines = Lines::new(Chunked::new(&[b"a\nb"]));
+        block_on(async {
+            assert_eq!(lines.next().await.unwrap().unwrap(), "a");
+            assert!(!lines.is_terminated());
+            assert_eq!(lines.next().await.unwrap().unwrap(), "b");
+            for _ in 0..3 {
+                assert!(lines.next().await.is_none());
+                assert!(lines.is_terminated());
+                assert_eq!(lines.size_hint(), (0, Some(0)));
+            }
+        });
+    }
+}
@@ -0,0 +1,959 @@
+//! A bounded multi-producer, single-consumer channel.
+//!
+//! This is a simpler version of `futures::channel::mpsc`: everything is kept
+//! behind one `Mutex`, and the only clever part is making sure no wakeup is
+//! ever lost.
+//!
+//! * When the channel is full, `Sender::send` parks the sending task until
+//!   the receiver makes space. Parked senders are woken one at a time, in the
+//!   order they started waiting, as messages are received.
+//! * When the channel is empty, the receiver parks until a message is sent,
+//!   or the last `Sender` is dropped, or the channel is closed.
+//!
+//! The tests for the waker handoffs run under [loom] with
+//! `RUSTFLAGS="--cfg loom" cargo test --release -p example_05_01_streams mpsc`.
+//!
+//! [loom]: https://docs.rs/loom
+
+use futures::stream::{FusedStream, Stream};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+#[cfg(loom)]
+use loom::sync::{Arc, Mutex};
+#[cfg(not(loom))]
+use std::sync::{Arc, Mutex};
+
+/// Create a channel which can hold up to `capacity` messages.
+///
+/// # Panics
+///
+/// If `capacity` is 0.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be at least 1");
+    let shared = Arc::new(Mutex::new(State {
+        queue: VecDeque::with_capacity(capacity),
+        capacity,
+        senders: 1,
+        closed: false,
+        recv_waker: None,
+        blocked: VecDeque::new(),
+        next_id: 0,
+        max_len: 0,
+        total_blocked: 0,
+        sent: 0,
+        received: 0,
+        to_wake: Vec::new(),
+    }));
+    let sender = Sender {
+        shared: shared.clone(),
+    };
+    let receiver = Receiver {
+        shared,
+        terminated: false,
+    };
+    (sender, receiver)
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    /// The number of `Sender`s which are still alive.
+    senders: usize,
+    /// Set by `close`, or when the `Receiver` is dropped.
+    closed: bool,
+    recv_waker: Option<Waker>,
+    /// Senders waiting for space, in the order they started waiting, each
+    /// with the id of its `Send` future.
+    blocked: VecDeque<(u64, Waker)>,
+    next_id: u64,
+    max_len: usize,
+    total_blocked: u64,
+    sent: u64,
+    received: u64,
+    /// Tasks to wake once the lock has been released; see `with_state`.
+    to_wake: Vec<Waker>,
+}
+
+/// Lock the channel's state and run `f` on it, then wake the tasks it
+/// queued up in `State::to_wake`.
+///
+/// The wakers are only woken, and dropped, after the lock is released. A
+/// waker is arbitrary code, which might use this channel again, and it
+/// would deadlock if we were still holding the lock.
+fn with_state<T, R>(shared: &Mutex<State<T>>, f: impl FnOnce(&mut State<T>) -> R) -> R {
+    let mut state = shared.lock().unwrap();
+    let result = f(&mut state);
+    let to_wake = mem::take(&mut state.to_wake);
+    drop(state);
+    for waker in to_wake {
+        waker.wake();
+    }
+    result
+}
+
+impl<T> State<T> {
+    fn push(&mut self, value: T) {
+        self.queue.push_back(value);
+        self.sent += 1;
+        self.max_len = self.max_len.max(self.queue.len());
+        self.wake_receiver();
+    }
+
+    fn wake_receiver(&mut self) {
+        if let Some(waker) = self.recv_waker.take() {
+            self.to_wake.push(waker);
+        }
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let value = self.queue.pop_front()?;
+        self.received += 1;
+        // There's room for one more message now.
+        self.wake_blocked_sender();
+        Some(value)
+    }
+
+    fn wake_blocked_sender(&mut self) {
+        if let Some((_, waker)) = self.blocked.pop_front() {
+            self.to_wake.push(waker);
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        let blocked = self.blocked.drain(..).map(|(_, waker)| waker);
+        self.to_wake.extend(blocked);
+        self.wake_receiver();
+    }
+
+    fn stats(&self) -> Stats {
+        Stats {
+            len: self.queue.len(),
+            capacity: self.capacity,
+            max_len: self.max_len,
+            blocked_senders: self.blocked.len(),
+            total_blocked: self.total_blocked,
+            sent: self.sent,
+            received: self.received,
+        }
+    }
+}
+
+/// A snapshot of a channel's queue depth and back-pressure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of messages in the channel.
+    pub len: usize,
+    pub capacity: usize,
+    /// The most messages the channel has held at once.
+    pub max_len: usize,
+    /// The number of senders currently waiting for space.
+    pub blocked_senders: usize,
+    /// The number of sends which have had to wait for space.
+    pub total_blocked: u64,
+    /// The number of messages sent.
+    pub sent: u64,
+    /// The number of messages received.
+    pub received: u64,
+}
+
+/// The sending half of a channel. It can be cloned to send from several
+/// tasks.
+pub struct Sender<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Send a message, waiting for space if the channel is full.
+    ///
+    /// Fails, returning the message, if the channel is closed.
+    pub fn send(&self, value: T) -> Send<'_, T> {
+        Send {
+            sender: self,
+            value: Some(value),
+            id: None,
+        }
+    }
+
+    /// Send a message if there's space for it, without waiting.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        with_state(&self.shared, |state| {
+            if state.closed {
+                Err(TrySendError::Closed(value))
+            } else if state.queue.len() == state.capacity {
+                Err(TrySendError::Full(value))
+            } else {
+                state.push(value);
+                Ok(())
+            }
+        })
+    }
+
+    /// Close the channel. Messages which have already been sent can still
+    /// be received, but any further sends fail.
+    pub fn close(&self) {
+        with_state(&self.shared, State::close);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().unwrap().closed
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.shared.lock().unwrap().stats()
+    }
+
+    /// Send `value`, or wait in `State::blocked` for space. This is the body
+    /// of `Send::poll`, with its state passed in, so that other ways of
+    /// sending can share it.
+    ///
+    /// `value` is taken when the message is sent or returned in an error.
+    /// `id` is our place in `State::blocked`, once we have had to wait. When
+    /// a receiver wakes us it removes our entry, but we keep the id, so that
+    /// we know we have been handed a wakeup.
+    pub(crate) fn poll_send(
+        &self,
+        value: &mut Option<T>,
+        id: &mut Option<u64>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), SendError<T>>> {
+        with_state(&self.shared, |state| {
+            if state.closed {
+                // `close` has already removed us from `blocked`.
+                *id = None;
+                return Poll::Ready(Err(SendError(value.take().unwrap())));
+            }
+            if state.queue.len() < state.capacity {
+                if let Some(id) = id.take() {
+                    state.blocked.retain(|(blocked, _)| *blocked != id);
+                }
+                state.push(value.take().unwrap());
+                return Poll::Ready(Ok(()));
+            }
+
+            match *id {
+                Some(id) => match state.blocked.iter_mut().find(|(blocked, _)| *blocked == id) {
+                    Some((_, waker)) => waker.clone_from(cx.waker()),
+                    // We were woken, but another sender took the space first.
+                    // We were at the front of the queue, so go back there.
+                    None => state.blocked.push_front((id, cx.waker().clone())),
+                },
+                None => {
+                    let next_id = state.next_id;
+                    state.next_id += 1;
+                    state.total_blocked += 1;
+                    state.blocked.push_back((next_id, cx.waker().clone()));
+                    *id = Some(next_id);
+                }
+            }
+            Poll::Pending
+        })
+    }
+
+    /// Give up waiting to send, after `poll_send` returned `Pending`.
+    pub(crate) fn cancel_send(&self, id: u64) {
+        with_state(&self.shared, |state| {
+            let blocked = state.blocked.len();
+            state.blocked.retain(|(blocked, _)| *blocked != id);
+            if state.blocked.len() == blocked {
+                // We were woken because there was space, but we're never
+                // going to use it, so pass the wakeup on to the next sender.
+                state.wake_blocked_sender();
+            }
+        })
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        with_state(&self.shared, |state| {
+            state.senders -= 1;
+            if state.senders == 0 {
+                // Let the receiver see that the stream has ended.
+                state.wake_receiver();
+            }
+        })
+    }
+}
+
+/// The future returned by `Sender::send`.
+pub struct Send<'a, T> {
+    sender: &'a Sender<T>,
+    // `None` once the message has been sent, or returned in an error.
+    value: Option<T>,
+    /// Our id in `State::blocked`; see `Sender::poll_send`.
+    id: Option<u64>,
+}
+
+// We never pin the message.
+impl<T> Unpin for Send<'_, T> {}
+
+impl<T> Future for Send<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        assert!(this.value.is_some(), "`Send` polled after completion");
+        this.sender.poll_send(&mut this.value, &mut this.id, cx)
+    }
+}
+
+impl<T> Drop for Send<'_, T> {
+    fn drop(&mut self) {
+        if let (Some(_), Some(id)) = (&self.value, self.id) {
+            self.sender.cancel_send(id);
+        }
+    }
+}
+
+/// The receiving half of a channel.
+///
+/// It is a `Stream` of the messages sent on the channel, which ends once
+/// every `Sender` has been dropped, or the channel has been closed, and every
+/// message has been received.
+pub struct Receiver<T> {
+    shared: Arc<Mutex<State<T>>>,
+    terminated: bool,
+}
+
+impl<T> Receiver<T> {
+    /// Receive the next message. Equivalent to `StreamExt::next`.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    /// Receive a message if there is one, without waiting.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        with_state(&self.shared, |state| match state.pop() {
+            Some(value) => Ok(value),
+            None if state.closed || state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        })
+    }
+
+    /// Close the channel, so that any further sends fail, and any blocked
+    /// senders are woken. Messages which have already been sent can still
+    /// be received.
+    pub fn close(&mut self) {
+        with_state(&self.shared, State::close);
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.shared.lock().unwrap().stats()
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        if self.terminated {
+            return Poll::Ready(None);
+        }
+        let poll = with_state(&self.shared, |state| {
+            if let Some(value) = state.pop() {
+                return Poll::Ready(Some(value));
+            }
+            if state.closed || state.senders == 0 {
+                return Poll::Ready(None);
+            }
+            state.recv_waker = Some(cx.waker().clone());
+            Poll::Pending
+        });
+        self.terminated = matches!(poll, Poll::Ready(None));
+        poll
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let queue = with_state(&self.shared, |state| {
+            state.close();
+            mem::take(&mut state.queue)
+        });
+        // Nobody is going to receive these. Like the wakers, they're dropped
+        // after unlocking, since a message's destructor might use the
+        // channel.
+        drop(queue);
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.terminated {
+            (0, Some(0))
+        } else {
+            (self.shared.lock().unwrap().queue.len(), None)
+        }
+    }
+}
+
+impl<T> FusedStream for Receiver<T> {
+    fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+}
+
+/// The future returned by `Receiver::recv`.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
+/// The error returned by `Sender::send` when the channel is closed. It
+/// contains the message which couldn't be sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sending on a closed channel")
+    }
+}
+
+impl<T: fmt::Debug> Error for SendError<T> {}
+
+/// The error returned by `Sender::try_send`. It contains the message which
+/// couldn't be sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "sending on a full channel"),
+            TrySendError::Closed(_) => write!(f, "sending on a closed channel"),
+        }
+    }
+}
+
+impl<T: fmt::Debug> Error for TrySendError<T> {}
+
+/// The error returned by `Receiver::try_recv`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// There are no messages, but more may be sent.
+    Empty,
+    /// There are no messages, and no more will be sent.
+    Disconnected,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "receiving on an empty channel"),
+            TryRecvError::Disconnected => write!(f, "receiving on a closed channel"),
+        }
+    }
+}
+
+impl Error for TryRecvError {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, StreamExt};
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        task::Wake,
+        thread,
+    };
+
+    /// A waker which counts how many times it has been woken.
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl CountingWaker {
+        fn new() -> (Arc<Self>, Waker) {
+            let counter = Arc::new(CountingWaker::default());
+            let waker = Waker::from(counter.clone());
+            (counter, waker)
+        }
+
+        fn count(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    fn poll<F: Future + Unpin>(fut: &mut F, waker: &Waker) -> Poll<F::Output> {
+        Pin::new(fut).poll(&mut Context::from_waker(waker))
+    }
+
+    #[test]
+    fn send_and_receive() {
+        let (tx, rx) = channel(2);
+        let sender = thread::spawn(move || {
+            block_on(async {
+                for i in 0..10 {
+                    tx.send(i).await.unwrap();
+                }
+            })
+        });
+        assert_eq!(block_on(rx.collect::<Vec<_>>()), (0..10).collect::<Vec<_>>());
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn send_parks_when_full() {
+        let (tx, mut rx) = channel(2);
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        assert_eq!(tx.try_send(3), Err(TrySendError::Full(3)));
+
+        let (counter, waker) = CountingWaker::new();
+        let mut send = tx.send(3);
+        assert!(poll(&mut send, &waker).is_pending());
+        assert_eq!(tx.stats().blocked_senders, 1);
+        assert_eq!(counter.count(), 0);
+
+        // Receiving makes space, and wakes the sender.
+        assert_eq!(rx.try_recv(), Ok(1));
+        assert_eq!(counter.count(), 1);
+        assert_eq!(poll(&mut send, &waker), Poll::Ready(Ok(())));
+        assert_eq!(tx.stats().blocked_senders, 0);
+    }
+
+    #[test]
+    fn blocked_senders_are_woken_in_order() {
+        let (tx, mut rx) = channel(1);
+        tx.try_send(0).unwrap();
+        let (first, first_waker) = CountingWaker::new();
+        let (second, second_waker) = CountingWaker::new();
+        let mut send_first = tx.send(1);
+        let mut send_second = tx.send(2);
+        assert!(poll(&mut send_first, &first_waker).is_pending());
+        assert!(poll(&mut send_second, &second_waker).is_pending());
+
+        assert_eq!(rx.try_recv(), Ok(0));
+        assert_eq!((first.count(), second.count()), (1, 0));
+        assert!(poll(&mut send_first, &first_waker).is_ready());
+
+        assert_eq!(rx.try_recv(), Ok(1));
+        assert_eq!((first.count(), second.count()), (1, 1));
+        assert!(poll(&mut send_second, &second_waker).is_ready());
+        assert_eq!(rx.try_recv(), Ok(2));
+    }
+
+    #[test]
+    fn woken_sender_keeps_its_place() {
+        let (tx, mut rx) = channel(1);
+        tx.try_send(0).unwrap();
+        let (first, first_waker) = CountingWaker::new();
+        let (second, second_waker) = CountingWaker::new();
+        let mut send_first = tx.send(1);
+        let mut send_second = tx.send(2);
+        assert!(poll(&mut send_first, &first_waker).is_pending());
+        assert!(poll(&mut send_second, &second_waker).is_pending());
+
+        // `first` is woken, but `try_send` takes the space before it is
+        // polled again.
+        assert_eq!(rx.try_recv(), Ok(0));
+        tx.try_send(10).unwrap();
+        assert!(poll(&mut send_first, &first_waker).is_pending());
+
+        // `first` is still woken before `second`.
+        assert_eq!(rx.try_recv(), Ok(10));
+        assert_eq!((first.count(), second.count()), (2, 0));
+    }
+
+    #[test]
+    fn dropped_send_passes_on_its_wakeup() {
+        let (tx, mut rx) = channel(1);
+        tx.try_send(0).unwrap();
+        let (first, first_waker) = CountingWaker::new();
+        let (second, second_waker) = CountingWaker::new();
+        let mut send_first = tx.send(1);
+        let mut send_second = tx.send(2);
+        assert!(poll(&mut send_first, &first_waker).is_pending());
+        assert!(poll(&mut send_second, &second_waker).is_pending());
+
+        assert_eq!(rx.try_recv(), Ok(0));
+        assert_eq!((first.count(), second.count()), (1, 0));
+        // If `first` is cancelled after being woken, `second` must be woken
+        // instead, or it would wait forever.
+        drop(send_first);
+        assert_eq!(second.count(), 1);
+        assert_eq!(poll(&mut send_second, &second_waker), Poll::Ready(Ok(())));
+    }
+
+    #[test]
+    fn dropped_send_gives_up_its_place() {
+        let (tx, mut rx) = channel(1);
+        tx.try_send(0).unwrap();
+        let (_, first_waker) = CountingWaker::new();
+        let (second, second_waker) = CountingWaker::new();
+        let mut send_first = tx.send(1);
+        let mut send_second = tx.send(2);
+        assert!(poll(&mut send_first, &first_waker).is_pending());
+        assert!(poll(&mut send_second, &second_waker).is_pending());
+
+        drop(send_first);
+        assert_eq!(tx.stats().blocked_senders, 1);
+        assert_eq!(rx.try_recv(), Ok(0));
+        assert_eq!(second.count(), 1);
+    }
+
+    #[test]
+    fn receiver_is_woken_by_send() {
+        let (tx, mut rx) = channel(1);
+        let (counter, waker) = CountingWaker::new();
+        let mut recv = rx.recv();
+        assert!(poll(&mut recv, &waker).is_pending());
+        tx.try_send(1).unwrap();
+        assert_eq!(counter.count(), 1);
+        assert_eq!(poll(&mut recv, &waker), Poll::Ready(Some(1)));
+    }
+
+    #[test]
+    fn stream_ends_when_last_sender_is_dropped() {
+        let (tx, mut rx) = channel::<i32>(1);
+        let tx2 = tx.clone();
+        let (counter, waker) = CountingWaker::new();
+        assert!(poll(&mut rx.recv(), &waker).is_pending());
+
+        drop(tx);
+        assert_eq!(counter.count(), 0);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        drop(tx2);
+        assert_eq!(counter.count(), 1);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(block_on(rx.next()), None);
+        assert!(rx.is_terminated());
+    }
+
+    #[test]
+    fn close_fails_blocked_senders_but_keeps_messages() {
+        let (tx, mut rx) = channel(1);
+        tx.try_send(1).unwrap();
+        let (counter, waker) = CountingWaker::new();
+        let mut send = tx.send(2);
+        assert!(poll(&mut send, &waker).is_pending());
+
+        rx.close();
+        assert_eq!(counter.count(), 1);
+        assert_eq!(poll(&mut send, &waker), Poll::Ready(Err(SendError(2))));
+        assert!(tx.is_closed());
+        assert_eq!(tx.try_send(3), Err(TrySendError::Closed(3)));
+
+        // Messages sent before the channel was closed can still be received.
+        assert_eq!(block_on(rx.by_ref().collect::<Vec<_>>()), [1]);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn sender_close_ends_stream() {
+        let (tx, mut rx) = channel(2);
+        let tx2 = tx.clone();
+        block_on(tx.send(1)).unwrap();
+        tx.close();
+        assert_eq!(block_on(tx2.send(2)), Err(SendError(2)));
+        assert_eq!(block_on(rx.recv()), Some(1));
+        assert_eq!(block_on(rx.recv()), None);
+    }
+
+    #[test]
+    fn dropping_receiver_fails_sends() {
+        let (tx, rx) = channel(1);
+        tx.try_send(1).unwrap();
+        let (counter, waker) = CountingWaker::new();
+        let mut send = tx.send(2);
+        assert!(poll(&mut send, &waker).is_pending());
+        drop(rx);
+        assert_eq!(counter.count(), 1);
+        assert_eq!(poll(&mut send, &waker), Poll::Ready(Err(SendError(2))));
+        assert_eq!(tx.stats().len, 0);
+    }
+
+    #[test]
+    fn messages_are_dropped_after_unlocking() {
+        // Dropping this message uses the channel, which would deadlock if
+        // the receiver were still holding the lock.
+        struct Message(Sender<Message>);
+        impl Drop for Message {
+            fn drop(&mut self) {
+                assert!(self.0.is_closed());
+            }
+        }
+        let (tx, rx) = channel(1);
+        assert!(tx.try_send(Message(tx.clone())).is_ok());
+        drop(rx);
+    }
+
+    #[test]
+    fn wakers_are_woken_after_unlocking() {
+        // A waker which sends on the channel it's woken by.
+        struct Resend(Sender<u32>);
+        impl Wake for Resend {
+            fn wake(self: Arc<Self>) {
+                self.0.try_send(2).unwrap();
+            }
+        }
+        let (tx, mut rx) = channel(2);
+        let waker = Waker::from(Arc::new(Resend(tx.clone())));
+        assert!(poll(&mut rx.recv(), &waker).is_pending());
+        tx.try_send(1).unwrap();
+        assert_eq!(rx.try_recv(), Ok(1));
+        assert_eq!(rx.try_recv(), Ok(2));
+    }
+
+    #[test]
+    fn stats() {
+        let (tx, mut rx) = channel(2);
+        let (_, waker) = CountingWaker::new();
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        let mut send = tx.send(3);
+        assert!(poll(&mut send, &waker).is_pending());
+        assert_eq!(
+            rx.stats(),
+            Stats {
+                len: 2,
+                capacity: 2,
+                max_len: 2,
+                blocked_senders: 1,
+                total_blocked: 1,
+                sent: 2,
+                received: 0,
+            }
+        );
+
+        rx.try_recv().unwrap();
+        assert!(poll(&mut send, &waker).is_ready());
+        rx.try_recv().unwrap();
+        let stats = rx.stats();
+        assert_eq!((stats.len, stats.max_len, stats.blocked_senders), (1, 2, 0));
+        assert_eq!((stats.total_blocked, stats.sent, stats.received), (1, 3, 2));
+    }
+
+    #[test]
+    fn many_senders_on_threads() {
+        const SENDERS: usize = 4;
+        const MESSAGES: usize = 1000;
+        let (tx, rx) = channel(4);
+        let threads: Vec<_> = (0..SENDERS)
+            .map(|sender| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    block_on(async {
+                        for i in 0..MESSAGES {
+                            tx.send((sender, i)).await.unwrap();
+                        }
+                    })
+                })
+            })
+            .collect();
+        drop(tx);
+
+        let mut next = [0; SENDERS];
+        block_on(rx.for_each(|(sender, i)| {
+            // Messages from each sender arrive in the order they were sent.
+            assert_eq!(next[sender], i);
+            next[sender] += 1;
+            async {}
+        }));
+        assert_eq!(next, [MESSAGES; SENDERS]);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    /// A channel, and the messages which have been taken out of it.
+    struct Received {
+        tx: Sender<u32>,
+        rx: Receiver<u32>,
+        got: Vec<u32>,
+    }
+
+    impl Received {
+        fn new() -> Self {
+            let (tx, rx) = channel(1);
+            Received { tx, rx, got: Vec::new() }
+        }
+
+        /// Fails if a message which was sent has been neither received nor
+        /// left in the channel.
+        fn nothing_lost(&mut self) -> Result<(), String> {
+            let mut seen = self.got.clone();
+            while let Ok(value) = self.rx.try_recv() {
+                seen.push(value);
+            }
+            let sent = self.rx.stats().sent as u32;
+            if seen == (0..sent).collect::<Vec<_>>() {
+                Ok(())
+            } else {
+                Err(format!("sent 0..{}, but saw {:?}", sent, seen))
+            }
+        }
+    }
+
+    #[test]
+    fn recv_in_select_loop_is_cancellation_safe() {
+        use cancel_check::{check, yield_now};
+        use futures::{future::FutureExt, select};
+
+        let points = check(
+            Received::new,
+            |Received { tx, rx, got }| {
+                Box::pin(async move {
+                    let producer = async {
+                        for i in 0..4 {
+                            tx.send(i).await.unwrap();
+                        }
+                    };
+                    let consumer = async {
+                        // A `Recv` which loses the race to `yield_now` is
+                        // dropped, and must leave its message in the channel.
+                        while got.len() < 4 {
+                            select! {
+                                value = rx.recv().fuse() => got.extend(value),
+                                () = yield_now().fuse() => {}
+                            }
+                        }
+                    };
+                    futures::future::join(producer, consumer).await;
+                })
+            },
+            |received, _| received.nothing_lost(),
+        )
+        .unwrap();
+        assert!(points > 0);
+    }
+
+    #[test]
+    fn cancelled_send_gives_up_its_place() {
+        use cancel_check::{check, yield_now, Outcome};
+
+        check(
+            || {
+                let received = Received::new();
+                received.tx.try_send(0).unwrap();
+                received
+            },
+            |Received { tx, rx, got }| {
+                Box::pin(async move {
+                    let send = async { tx.send(1).await.unwrap() };
+                    let recv = async {
+                        yield_now().await;
+                        got.extend(rx.recv().await);
+                    };
+                    futures::future::join(send, recv).await;
+                })
+            },
+            |received, outcome| {
+                let blocked = received.rx.stats().blocked_senders;
+                if blocked != 0 {
+                    return Err(format!("{} senders are still blocked", blocked));
+                }
+                if outcome == Outcome::Completed(()) && received.got != [0] {
+                    return Err(format!("received {:?}", received.got));
+                }
+                received.nothing_lost()
+            },
+        )
+        .unwrap();
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use futures::StreamExt;
+    use loom::{future::block_on, thread};
+
+    #[test]
+    fn send_wakes_parked_receiver() {
+        loom::model(|| {
+            let (tx, mut rx) = channel(1);
+            let sender = thread::spawn(move || block_on(tx.send(1)).unwrap());
+            assert_eq!(block_on(rx.recv()), Some(1));
+            sender.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn recv_wakes_parked_sender() {
+        loom::model(|| {
+            let (tx, rx) = channel(1);
+            let sender = thread::spawn(move || {
+                block_on(async {
+                    tx.send(1).await.unwrap();
+                    tx.send(2).await.unwrap();
+                })
+            });
+            assert_eq!(block_on(rx.collect::<Vec<_>>()), [1, 2]);
+            sender.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn two_senders_share_one_slot() {
+        // With three threads, checking every interleaving takes too long.
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(|| {
+            let (tx, rx) = channel(1);
+            let tx2 = tx.clone();
+            let first = thread::spawn(move || block_on(tx.send(1)).unwrap());
+            let second = thread::spawn(move || block_on(tx2.send(2)).unwrap());
+            let mut received = block_on(rx.collect::<Vec<_>>());
+            received.sort();
+            assert_eq!(received, [1, 2]);
+            first.join().unwrap();
+            second.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn close_wakes_parked_sender() {
+        loom::model(|| {
+            let (tx, mut rx) = channel(1);
+            tx.try_send(1).unwrap();
+            let sender = thread::spawn(move || block_on(tx.send(2)));
+            rx.close();
+            // The channel is full until it is closed, so the send must fail,
+            // whether it parked before the close or not.
+            assert_eq!(sender.join().unwrap(), Err(SendError(2)));
+            assert_eq!(block_on(rx.recv()), Some(1));
+            assert_eq!(block_on(rx.recv()), None);
+        });
+    }
+}
@@ -0,0 +1,491 @@
+//! Implementations of `futures::Sink`.
+//!
+//! A `Sink` is the opposite of a `Stream`: values are pushed into it. Sending
+//! a value takes three steps, so that a sink can apply back-pressure and
+//! buffer values:
+//!
+//! * `poll_ready` waits until the sink can take another value. This is
+//!   usually where a sink makes room by writing out what it has buffered.
+//! * `start_send` hands the value over. It can't wait, so it may only buffer
+//!   the value, and it must only be called after `poll_ready` returned
+//!   `Ready(Ok(()))`.
+//! * `poll_flush` waits until everything which has been sent has been written
+//!   out, and `poll_close` flushes and then shuts the sink down.
+
+use crate::mpsc::{SendError, Sender};
+use futures::{io::AsyncWrite, sink::Sink};
+use std::{
+    io, mem,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+/// A sink of byte buffers which writes them to an `AsyncWrite`, such as a
+/// file, in chunks of at least `capacity` bytes.
+pub struct WriterSink<W> {
+    writer: W,
+    buf: Vec<u8>,
+    capacity: usize,
+    /// How much of `buf` has been written.
+    written: usize,
+}
+
+impl<W: AsyncWrite> WriterSink<W> {
+    pub fn new(writer: W, capacity: usize) -> Self {
+        WriterSink {
+            writer,
+            buf: Vec::with_capacity(capacity),
+            capacity,
+            written: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Any bytes which haven't been flushed are lost.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Write out everything in `buf`.
+    fn poll_write_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> io::Result<Poll<()>> {
+        // SAFETY: `writer` is structurally pinned: it is never moved out of
+        // `self` while pinned, and we don't implement `Drop`. Nothing else is
+        // pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut writer = unsafe { Pin::new_unchecked(&mut this.writer) };
+        while this.written < this.buf.len() {
+            let n = match writer.as_mut().poll_write(cx, &this.buf[this.written..]) {
+                Poll::Ready(result) => result?,
+                Poll::Pending => return Ok(Poll::Pending),
+            };
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            this.written += n;
+        }
+        this.buf.clear();
+        this.written = 0;
+        Ok(Poll::Ready(()))
+    }
+
+    fn writer(self: Pin<&mut Self>) -> Pin<&mut W> {
+        // SAFETY: as for `poll_write_buf`.
+        unsafe { self.map_unchecked_mut(|this| &mut this.writer) }
+    }
+}
+
+impl<W: AsyncWrite, B: AsRef<[u8]>> Sink<B> for WriterSink<W> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.buf.len() >= self.capacity {
+            ready!(self.poll_write_buf(cx)?);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: B) -> io::Result<()> {
+        // SAFETY: we don't touch `writer`.
+        let this = unsafe { self.get_unchecked_mut() };
+        // The buffer can go over `capacity`; the next `poll_ready` will write
+        // it out.
+        this.buf.extend_from_slice(item.as_ref());
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_write_buf(cx)?);
+        self.writer().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_write_buf(cx)?);
+        self.writer().poll_close(cx)
+    }
+}
+
+/// A sink which sends values on an `mpsc` channel.
+///
+/// `start_send` holds on to the value, and `poll_ready` and `poll_flush` wait
+/// for space in the channel to send it, queueing up behind any other blocked
+/// senders. Closing the sink drops the `Sender`.
+pub struct SenderSink<T> {
+    /// `None` once the sink has been closed.
+    sender: Option<Sender<T>>,
+    /// The value passed to `start_send`, which hasn't been sent yet.
+    pending: Option<T>,
+    /// Our id in the channel's queue of blocked senders; see
+    /// `Sender::poll_send`.
+    id: Option<u64>,
+}
+
+// We never pin the value.
+impl<T> Unpin for SenderSink<T> {}
+
+impl<T> SenderSink<T> {
+    pub fn new(sender: Sender<T>) -> Self {
+        SenderSink {
+            sender: Some(sender),
+            pending: None,
+            id: None,
+        }
+    }
+}
+
+impl<T> Sink<T> for SenderSink<T> {
+    /// Holds the value which couldn't be sent.
+    type Error = SendError<T>;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError<T>>> {
+        let this = self.get_mut();
+        match (&this.sender, this.pending.is_some()) {
+            (Some(sender), true) => sender.poll_send(&mut this.pending, &mut this.id, cx),
+            // Values are only held while we have a sender.
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), SendError<T>> {
+        let this = self.get_mut();
+        assert!(
+            this.pending.is_none(),
+            "`start_send` called without `poll_ready`"
+        );
+        match &this.sender {
+            Some(sender) if !sender.is_closed() => {
+                this.pending = Some(item);
+                Ok(())
+            }
+            _ => Err(SendError(item)),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError<T>>> {
+        // There's nothing to flush once the value is in the channel.
+        self.poll_ready(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), SendError<T>>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        // If this was the last sender, the receiver's stream ends.
+        self.sender = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> Drop for SenderSink<T> {
+    fn drop(&mut self) {
+        if let (Some(sender), Some(_), Some(id)) = (&self.sender, &self.pending, self.id) {
+            sender.cancel_send(id);
+        }
+    }
+}
+
+/// A sink which collects values into `Vec`s of `size` values, and sends them
+/// on to `sink`.
+///
+/// A full batch isn't sent straight away, but by the next `poll_ready`, when
+/// it's needed to make room, or by `poll_flush` or `poll_close`, which also
+/// send a partly filled batch.
+pub struct Batched<Si, T> {
+    sink: Si,
+    size: usize,
+    batch: Vec<T>,
+}
+
+impl<Si: Sink<Vec<T>>, T> Batched<Si, T> {
+    /// # Panics
+    ///
+    /// If `size` is 0.
+    pub fn new(sink: Si, size: usize) -> Self {
+        assert!(size > 0, "batch size must be at least 1");
+        Batched {
+            sink,
+            size,
+            batch: Vec::with_capacity(size),
+        }
+    }
+
+    pub fn get_ref(&self) -> &Si {
+        &self.sink
+    }
+
+    /// Any values which haven't been flushed are lost.
+    pub fn into_inner(self) -> Si {
+        self.sink
+    }
+
+    fn project(self: Pin<&mut Self>) -> (Pin<&mut Si>, &mut Vec<T>, usize) {
+        // SAFETY: `sink` is structurally pinned: it is never moved out of
+        // `self` while pinned, and we don't implement `Drop`. The batch is
+        // not pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let sink = unsafe { Pin::new_unchecked(&mut this.sink) };
+        (sink, &mut this.batch, this.size)
+    }
+
+    /// Send the batch on to `sink`, if there is one.
+    fn poll_send_batch(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::Error>> {
+        let (mut sink, batch, size) = self.project();
+        if !batch.is_empty() {
+            ready!(sink.as_mut().poll_ready(cx))?;
+            sink.start_send(mem::replace(batch, Vec::with_capacity(size)))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<Si: Sink<Vec<T>>, T> Sink<T> for Batched<Si, T> {
+    type Error = Si::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::Error>> {
+        if self.batch.len() >= self.size {
+            ready!(self.poll_send_batch(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Si::Error> {
+        let (_, batch, _) = self.project();
+        batch.push(item);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::Error>> {
+        ready!(self.as_mut().poll_send_batch(cx))?;
+        self.project().0.poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::Error>> {
+        ready!(self.as_mut().poll_send_batch(cx))?;
+        self.project().0.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpsc;
+    use futures::{
+        executor::block_on,
+        future,
+        io::AllowStdIo,
+        sink::{self, SinkExt},
+        stream::{self, StreamExt, TryStreamExt},
+    };
+    use std::{convert::Infallible, fs};
+
+    /// Records each write, and fails once `limit` bytes have been written.
+    struct RecordingWriter {
+        writes: Vec<Vec<u8>>,
+        limit: usize,
+        flushed: bool,
+        closed: bool,
+    }
+
+    impl RecordingWriter {
+        fn new(limit: usize) -> Self {
+            RecordingWriter {
+                writes: Vec::new(),
+                limit,
+                flushed: false,
+                closed: false,
+            }
+        }
+
+        fn written(&self) -> usize {
+            self.writes.iter().map(Vec::len).sum()
+        }
+    }
+
+    impl AsyncWrite for RecordingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.written() + buf.len() > self.limit {
+                return Poll::Ready(Err(io::Error::other("disk full")));
+            }
+            self.writes.push(buf.to_vec());
+            self.flushed = false;
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.flushed = true;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.closed = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn lines(n: usize) -> impl futures::Stream<Item = io::Result<String>> {
+        stream::iter(0..n).map(|i| Ok(format!("line {i}\n")))
+    }
+
+    #[test]
+    fn writer_sink_writes_in_chunks() {
+        let mut sink = WriterSink::new(RecordingWriter::new(usize::MAX), 16);
+        block_on(lines(5).forward(&mut sink)).unwrap();
+        let writer = sink.into_inner();
+        // Each line is 7 bytes, so three fit in a chunk.
+        let writes: Vec<_> = writer.writes.iter().map(Vec::len).collect();
+        assert_eq!(writes, [21, 14]);
+        assert!(writer.closed);
+    }
+
+    #[test]
+    fn writer_sink_to_file() {
+        let path = std::env::temp_dir().join(format!("writer-sink-{}", std::process::id()));
+        let file = fs::File::create(&path).unwrap();
+        let mut sink = WriterSink::new(AllowStdIo::new(file), 64);
+        // Unlike `forward`, `send_all` flushes the sink but doesn't close it.
+        block_on(sink.send_all(&mut lines(100))).unwrap();
+        block_on(SinkExt::<String>::close(&mut sink)).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(contents.lines().count(), 100);
+        assert_eq!(contents.lines().last(), Some("line 99"));
+    }
+
+    #[test]
+    fn writer_sink_error_stops_forward() {
+        let mut sink = WriterSink::new(RecordingWriter::new(30), 10);
+        let err = block_on(lines(10).forward(&mut sink)).unwrap_err();
+        assert_eq!(err.to_string(), "disk full");
+        assert_eq!(sink.get_ref().written(), 28);
+        assert!(!sink.get_ref().closed);
+    }
+
+    #[test]
+    fn stream_error_stops_send_all() {
+        let mut sink = WriterSink::new(RecordingWriter::new(usize::MAX), 1);
+        let items = vec![
+            Ok("a".to_owned()),
+            Err(io::Error::other("bad input")),
+            Ok("b".to_owned()),
+        ];
+        let mut items = stream::iter(items);
+        let err = block_on(sink.send_all(&mut items)).unwrap_err();
+        assert_eq!(err.to_string(), "bad input");
+        // The item before the error was sent, but is still buffered.
+        assert!(sink.get_ref().writes.is_empty());
+        block_on(SinkExt::<String>::flush(&mut sink)).unwrap();
+        assert_eq!(sink.get_ref().writes, [b"a"]);
+        // The items after it are left in the stream.
+        assert_eq!(block_on(items.try_next()).unwrap().as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn sender_sink_applies_back_pressure() {
+        let (tx, rx) = mpsc::channel(1);
+        let stats = tx.clone();
+        let mut sink = SenderSink::new(tx);
+        let (sent, received) = block_on(future::join(
+            stream::iter(0..10).map(Ok).forward(&mut sink),
+            async {
+                let received: Vec<_> = rx.take(10).collect().await;
+                received
+            },
+        ));
+        sent.unwrap();
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+        assert!(stats.stats().total_blocked > 0);
+        assert_eq!(stats.stats().max_len, 1);
+    }
+
+    #[test]
+    fn closing_sender_sink_ends_the_stream() {
+        let (tx, rx) = mpsc::channel(4);
+        let mut sink = SenderSink::new(tx);
+        block_on(stream::iter(1..=3).map(Ok).forward(&mut sink)).unwrap();
+        // `forward` closed the sink, which dropped the only sender.
+        assert_eq!(block_on(rx.collect::<Vec<_>>()), [1, 2, 3]);
+        assert_eq!(block_on(sink.send(4)), Err(SendError(4)));
+    }
+
+    #[test]
+    fn sender_sink_returns_unsent_value() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut sink = SenderSink::new(tx);
+        block_on(sink.feed(1)).unwrap();
+        block_on(sink.feed(2)).unwrap();
+        // 2 is waiting for space, and never gets it.
+        rx.close();
+        assert_eq!(block_on(sink.flush()), Err(SendError(2)));
+        assert_eq!(rx.try_recv(), Ok(1));
+    }
+
+    #[test]
+    fn batched_sends_full_batches() {
+        let mut sink = Batched::new(Vec::new(), 3);
+        let items = stream::iter(1..=7).map(Ok::<_, Infallible>);
+        block_on(items.forward(&mut sink)).unwrap();
+        assert_eq!(sink.into_inner(), [vec![1, 2, 3], vec![4, 5, 6], vec![7]]);
+    }
+
+    #[test]
+    fn batched_only_sends_partial_batch_on_flush() {
+        let mut sink = Batched::new(Vec::new(), 3);
+        block_on(async {
+            for i in 1..=4 {
+                sink.feed(i).await.unwrap();
+            }
+        });
+        assert_eq!(sink.get_ref(), &[vec![1, 2, 3]]);
+        block_on(sink.flush()).unwrap();
+        assert_eq!(sink.get_ref(), &[vec![1, 2, 3], vec![4]]);
+    }
+
+    #[test]
+    fn batched_over_a_channel() {
+        let (tx, rx) = mpsc::channel(1);
+        let mut sink = Batched::new(SenderSink::new(tx), 4);
+        let (sent, received) = block_on(future::join(
+            stream::iter(0..10).map(Ok).forward(&mut sink),
+            rx.collect::<Vec<_>>(),
+        ));
+        sent.unwrap();
+        assert_eq!(received, [vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]);
+    }
+
+    #[test]
+    fn batched_propagates_inner_error() {
+        let inner = sink::unfold(0, |total, batch: Vec<u32>| async move {
+            let total = total + batch.iter().sum::<u32>();
+            if total > 10 {
+                Err(total)
+            } else {
+                Ok(total)
+            }
+        });
+        let mut sink = Batched::new(Box::pin(inner), 2);
+        let mut items = stream::iter(1..=10).map(Ok);
+        // The batch [5, 6] takes the total over 10, but `unfold` only
+        // reports that when the next batch is sent.
+        assert_eq!(block_on(sink.send_all(&mut items)), Err(21));
+        // 9 was lost along with its batch.
+        assert_eq!(block_on(items.next()), Some(Ok(10)));
+    }
+
+    #[test]
+    fn batches_of_lines_through_writer_sink() {
+        let mut out = Vec::new();
+        let writer = WriterSink::new(AllowStdIo::new(&mut out), 8)
+            .with(|batch: Vec<String>| future::ready(Ok::<_, io::Error>(batch.concat())));
+        let mut sink = Batched::new(writer, 3);
+        block_on(lines(4).forward(&mut sink)).unwrap();
+        drop(sink);
+        assert_eq!(out, b"line 0\nline 1\nline 2\nline 3\n");
+    }
+}
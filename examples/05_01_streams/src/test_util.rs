@@ -0,0 +1,25 @@
+//! Helpers shared by the tests in this crate.
+
+use std::{future::Future, pin::Pin, task::{Context, Poll}};
+
+/// Returns `Pending` once, waking the task straight away, so that other
+/// futures get a chance to run.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
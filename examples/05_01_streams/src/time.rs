@@ -0,0 +1,626 @@
+//! Time-aware stream adapters, built on `TimerFuture` from `02_03_timer`.
+//!
+//! The adapters only ever need to sleep, so they are generic over a `Clock`
+//! which makes sleep futures. `TimeStreamExt` uses `TimerClock`, which makes
+//! a `TimerFuture` (and so spawns a thread) for each sleep; the tests use a
+//! clock which only moves when they advance it.
+
+use futures::stream::{FusedStream, Stream};
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use timer_future::TimerFuture;
+
+/// Something which can make futures that complete after a while.
+pub trait Clock {
+    type Sleep: Future<Output = ()> + Unpin;
+
+    fn sleep(&self, duration: Duration) -> Self::Sleep;
+}
+
+/// A `Clock` which sleeps with `TimerFuture`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimerClock;
+
+impl Clock for TimerClock {
+    type Sleep = TimerFuture;
+
+    fn sleep(&self, duration: Duration) -> TimerFuture {
+        TimerFuture::new(duration)
+    }
+}
+
+/// Time-aware adapters for `Stream`s, which `futures::StreamExt` doesn't
+/// have.
+pub trait TimeStreamExt: Stream + Sized {
+    /// Group items into `Vec`s of up to `capacity` items. A chunk is yielded
+    /// when it is full, or `duration` after its first item arrived, whichever
+    /// comes first.
+    fn chunks_timeout(self, capacity: usize, duration: Duration) -> ChunksTimeout<Self, TimerClock> {
+        ChunksTimeout::with_clock(self, capacity, duration, TimerClock)
+    }
+
+    /// Yield items at most once every `duration`. Items are delayed, not
+    /// dropped, so a slow consumer slows down the stream.
+    fn throttle(self, duration: Duration) -> Throttle<Self, TimerClock> {
+        Throttle::with_clock(self, duration, TimerClock)
+    }
+
+    /// Only yield an item once `duration` has passed without another one
+    /// arriving, dropping the items in between.
+    fn debounce(self, duration: Duration) -> Debounce<Self, TimerClock> {
+        Debounce::with_clock(self, duration, TimerClock)
+    }
+
+    /// Yield `Err(Elapsed)` each time the stream takes longer than `duration`
+    /// to produce an item, then carry on waiting.
+    fn timeout_each(self, duration: Duration) -> TimeoutEach<Self, TimerClock> {
+        TimeoutEach::with_clock(self, duration, TimerClock)
+    }
+}
+
+impl<S: Stream> TimeStreamExt for S {}
+
+/// Stream for `TimeStreamExt::chunks_timeout`.
+pub struct ChunksTimeout<S: Stream, C: Clock> {
+    stream: S,
+    clock: C,
+    capacity: usize,
+    duration: Duration,
+    items: Vec<S::Item>,
+    /// Running while `items` isn't empty.
+    deadline: Option<C::Sleep>,
+    /// Whether `stream` has ended.
+    done: bool,
+}
+
+impl<S: Stream, C: Clock> ChunksTimeout<S, C> {
+    /// # Panics
+    ///
+    /// If `capacity` is 0.
+    pub fn with_clock(stream: S, capacity: usize, duration: Duration, clock: C) -> Self {
+        assert!(capacity > 0, "chunk capacity must be at least 1");
+        ChunksTimeout {
+            stream,
+            clock,
+            capacity,
+            duration,
+            items: Vec::with_capacity(capacity),
+            deadline: None,
+            done: false,
+        }
+    }
+
+    fn take_chunk(&mut self) -> Vec<S::Item> {
+        self.deadline = None;
+        mem::replace(&mut self.items, Vec::with_capacity(self.capacity))
+    }
+}
+
+impl<S: Stream, C: Clock> Stream for ChunksTimeout<S, C> {
+    type Item = Vec<S::Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // SAFETY: `stream` is structurally pinned: it is never moved out of
+        // `self`, and we don't implement `Drop`. Nothing else is pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        while !this.done {
+            let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+            match stream.poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    if this.items.is_empty() {
+                        this.deadline = Some(this.clock.sleep(this.duration));
+                    }
+                    this.items.push(item);
+                    if this.items.len() == this.capacity {
+                        return Poll::Ready(Some(this.take_chunk()));
+                    }
+                }
+                Poll::Ready(None) => this.done = true,
+                Poll::Pending => break,
+            }
+        }
+
+        if this.done {
+            // Flush whatever is left, then end.
+            if this.items.is_empty() {
+                return Poll::Ready(None);
+            }
+            return Poll::Ready(Some(this.take_chunk()));
+        }
+        match &mut this.deadline {
+            Some(deadline) => {
+                ready!(Pin::new(deadline).poll(cx));
+                Poll::Ready(Some(this.take_chunk()))
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.done {
+            let chunks = usize::from(!self.items.is_empty());
+            return (chunks, Some(chunks));
+        }
+        let (lower, upper) = self.stream.size_hint();
+        let buffered = self.items.len();
+        // Every chunk has at least one item, and at most `capacity`.
+        (
+            lower.saturating_add(buffered).div_ceil(self.capacity),
+            upper.and_then(|upper| upper.checked_add(buffered)),
+        )
+    }
+}
+
+impl<S: Stream, C: Clock> FusedStream for ChunksTimeout<S, C> {
+    fn is_terminated(&self) -> bool {
+        self.done && self.items.is_empty()
+    }
+}
+
+/// Stream for `TimeStreamExt::throttle`.
+pub struct Throttle<S, C: Clock> {
+    stream: S,
+    clock: C,
+    duration: Duration,
+    /// Started each time an item is yielded.
+    delay: Option<C::Sleep>,
+    done: bool,
+}
+
+impl<S: Stream, C: Clock> Throttle<S, C> {
+    pub fn with_clock(stream: S, duration: Duration, clock: C) -> Self {
+        Throttle {
+            stream,
+            clock,
+            duration,
+            delay: None,
+            done: false,
+        }
+    }
+}
+
+impl<S: Stream, C: Clock> Stream for Throttle<S, C> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        // SAFETY: as for `ChunksTimeout`.
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.done {
+            return Poll::Ready(None);
+        }
+        // Don't even look at the next item until the delay is over.
+        if let Some(delay) = &mut this.delay {
+            ready!(Pin::new(delay).poll(cx));
+            this.delay = None;
+        }
+        let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+        match ready!(stream.poll_next(cx)) {
+            Some(item) => {
+                this.delay = Some(this.clock.sleep(this.duration));
+                Poll::Ready(Some(item))
+            }
+            None => {
+                this.done = true;
+                Poll::Ready(None)
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.done {
+            (0, Some(0))
+        } else {
+            self.stream.size_hint()
+        }
+    }
+}
+
+impl<S: Stream, C: Clock> FusedStream for Throttle<S, C> {
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+/// The most items `Debounce` takes from its stream in one poll, so that a
+/// stream which is always ready can't keep it from returning.
+const DEBOUNCE_ITEMS_PER_POLL: usize = 32;
+
+/// Stream for `TimeStreamExt::debounce`.
+pub struct Debounce<S: Stream, C: Clock> {
+    stream: S,
+    clock: C,
+    duration: Duration,
+    /// The latest item, waiting for `quiet` to finish.
+    latest: Option<S::Item>,
+    /// Restarted each time an item arrives.
+    quiet: Option<C::Sleep>,
+    done: bool,
+}
+
+impl<S: Stream, C: Clock> Debounce<S, C> {
+    pub fn with_clock(stream: S, duration: Duration, clock: C) -> Self {
+        Debounce {
+            stream,
+            clock,
+            duration,
+            latest: None,
+            quiet: None,
+            done: false,
+        }
+    }
+}
+
+impl<S: Stream, C: Clock> Stream for Debounce<S, C> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        // SAFETY: as for `ChunksTimeout`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut taken = 0;
+        while !this.done {
+            if taken == DEBOUNCE_ITEMS_PER_POLL {
+                // Give other tasks a turn, and carry on where we left off
+                // when we're polled again.
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+            match stream.poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    this.latest = Some(item);
+                    this.quiet = Some(this.clock.sleep(this.duration));
+                    taken += 1;
+                }
+                Poll::Ready(None) => this.done = true,
+                Poll::Pending => break,
+            }
+        }
+
+        if this.done {
+            // There's nothing left to wait for, so yield the latest item
+            // straight away.
+            this.quiet = None;
+            return Poll::Ready(this.latest.take());
+        }
+        match &mut this.quiet {
+            Some(quiet) => {
+                ready!(Pin::new(quiet).poll(cx));
+                this.quiet = None;
+                Poll::Ready(this.latest.take())
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let latest = usize::from(self.latest.is_some());
+        if self.done {
+            return (latest, Some(latest));
+        }
+        // Any number of items might be dropped.
+        let (_, upper) = self.stream.size_hint();
+        (latest, upper.and_then(|upper| upper.checked_add(latest)))
+    }
+}
+
+impl<S: Stream, C: Clock> FusedStream for Debounce<S, C> {
+    fn is_terminated(&self) -> bool {
+        self.done && self.latest.is_none()
+    }
+}
+
+/// Stream for `TimeStreamExt::timeout_each`.
+pub struct TimeoutEach<S, C: Clock> {
+    stream: S,
+    clock: C,
+    duration: Duration,
+    /// Started when we begin waiting for an item.
+    timeout: Option<C::Sleep>,
+    done: bool,
+}
+
+impl<S: Stream, C: Clock> TimeoutEach<S, C> {
+    pub fn with_clock(stream: S, duration: Duration, clock: C) -> Self {
+        TimeoutEach {
+            stream,
+            clock,
+            duration,
+            timeout: None,
+            done: false,
+        }
+    }
+}
+
+impl<S: Stream, C: Clock> Stream for TimeoutEach<S, C> {
+    type Item = Result<S::Item, Elapsed>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // SAFETY: as for `ChunksTimeout`.
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.done {
+            return Poll::Ready(None);
+        }
+        let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+        match stream.poll_next(cx) {
+            Poll::Ready(Some(item)) => {
+                this.timeout = None;
+                Poll::Ready(Some(Ok(item)))
+            }
+            Poll::Ready(None) => {
+                this.done = true;
+                this.timeout = None;
+                Poll::Ready(None)
+            }
+            Poll::Pending => {
+                let timeout = this
+                    .timeout
+                    .get_or_insert_with(|| this.clock.sleep(this.duration));
+                ready!(Pin::new(timeout).poll(cx));
+                // Start a new timeout the next time we're polled.
+                this.timeout = None;
+                Poll::Ready(Some(Err(Elapsed)))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.done {
+            return (0, Some(0));
+        }
+        // Any number of timeouts might happen.
+        (self.stream.size_hint().0, None)
+    }
+}
+
+impl<S: Stream, C: Clock> FusedStream for TimeoutEach<S, C> {
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+/// The error yielded by `TimeStreamExt::timeout_each` when an item takes too
+/// long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out waiting for the next item")
+    }
+}
+
+impl Error for Elapsed {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{channel::mpsc, executor::block_on, stream, StreamExt};
+    use std::{
+        cell::RefCell,
+        rc::Rc,
+        task::Waker,
+        time::Instant,
+    };
+
+    /// A clock which only moves when `advance` is called.
+    #[derive(Clone, Default)]
+    struct MockClock(Rc<RefCell<MockState>>);
+
+    #[derive(Default)]
+    struct MockState {
+        now: Duration,
+        wakers: Vec<Waker>,
+    }
+
+    impl MockClock {
+        fn advance(&self, duration: Duration) {
+            let wakers = {
+                let mut state = self.0.borrow_mut();
+                state.now += duration;
+                mem::take(&mut state.wakers)
+            };
+            for waker in wakers {
+                waker.wake();
+            }
+        }
+    }
+
+    struct MockSleep {
+        clock: MockClock,
+        deadline: Duration,
+    }
+
+    impl Clock for MockClock {
+        type Sleep = MockSleep;
+
+        fn sleep(&self, duration: Duration) -> MockSleep {
+            MockSleep {
+                clock: self.clone(),
+                deadline: self.0.borrow().now + duration,
+            }
+        }
+    }
+
+    impl Future for MockSleep {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            let mut state = self.clock.0.borrow_mut();
+            if state.now >= self.deadline {
+                Poll::Ready(())
+            } else {
+                state.wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn poll_next<S: Stream + Unpin>(stream: &mut S) -> Poll<Option<S::Item>> {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        stream.poll_next_unpin(&mut cx)
+    }
+
+    #[test]
+    fn chunks_timeout_yields_full_chunks_straight_away() {
+        let clock = MockClock::default();
+        let mut chunks = ChunksTimeout::with_clock(stream::iter(1..=5), 2, ms(10), clock);
+        assert_eq!(poll_next(&mut chunks), Poll::Ready(Some(vec![1, 2])));
+        assert_eq!(poll_next(&mut chunks), Poll::Ready(Some(vec![3, 4])));
+        // The stream has ended, so the last chunk is flushed.
+        assert_eq!(poll_next(&mut chunks), Poll::Ready(Some(vec![5])));
+        assert_eq!(poll_next(&mut chunks), Poll::Ready(None));
+        assert!(chunks.is_terminated());
+    }
+
+    #[test]
+    fn chunks_timeout_flushes_after_timeout() {
+        let clock = MockClock::default();
+        let (tx, rx) = mpsc::unbounded();
+        let mut chunks = ChunksTimeout::with_clock(rx, 3, ms(10), clock.clone());
+
+        assert!(poll_next(&mut chunks).is_pending());
+        // The timeout starts with the first item of a chunk.
+        clock.advance(ms(100));
+        tx.unbounded_send(1).unwrap();
+        assert!(poll_next(&mut chunks).is_pending());
+        clock.advance(ms(5));
+        tx.unbounded_send(2).unwrap();
+        assert!(poll_next(&mut chunks).is_pending());
+        clock.advance(ms(5));
+        assert_eq!(poll_next(&mut chunks), Poll::Ready(Some(vec![1, 2])));
+
+        tx.unbounded_send(3).unwrap();
+        assert!(poll_next(&mut chunks).is_pending());
+        drop(tx);
+        assert_eq!(poll_next(&mut chunks), Poll::Ready(Some(vec![3])));
+        assert_eq!(poll_next(&mut chunks), Poll::Ready(None));
+    }
+
+    #[test]
+    fn throttle_spaces_out_items() {
+        let clock = MockClock::default();
+        let mut throttled = Throttle::with_clock(stream::iter(1..=3), ms(10), clock.clone());
+        assert_eq!(poll_next(&mut throttled), Poll::Ready(Some(1)));
+        assert!(poll_next(&mut throttled).is_pending());
+        clock.advance(ms(9));
+        assert!(poll_next(&mut throttled).is_pending());
+        clock.advance(ms(1));
+        assert_eq!(poll_next(&mut throttled), Poll::Ready(Some(2)));
+        clock.advance(ms(10));
+        assert_eq!(poll_next(&mut throttled), Poll::Ready(Some(3)));
+        // The delay applies before we find out that the stream has ended.
+        assert!(poll_next(&mut throttled).is_pending());
+        clock.advance(ms(10));
+        assert_eq!(poll_next(&mut throttled), Poll::Ready(None));
+        assert!(throttled.is_terminated());
+    }
+
+    #[test]
+    fn throttle_doesnt_delay_slow_streams() {
+        let clock = MockClock::default();
+        let (tx, rx) = mpsc::unbounded();
+        let mut throttled = Throttle::with_clock(rx, ms(10), clock.clone());
+        tx.unbounded_send(1).unwrap();
+        assert_eq!(poll_next(&mut throttled), Poll::Ready(Some(1)));
+        clock.advance(ms(50));
+        assert!(poll_next(&mut throttled).is_pending());
+        tx.unbounded_send(2).unwrap();
+        assert_eq!(poll_next(&mut throttled), Poll::Ready(Some(2)));
+    }
+
+    #[test]
+    fn debounce_waits_for_quiet() {
+        let clock = MockClock::default();
+        let (tx, rx) = mpsc::unbounded();
+        let mut debounced = Debounce::with_clock(rx, ms(10), clock.clone());
+
+        tx.unbounded_send(1).unwrap();
+        tx.unbounded_send(2).unwrap();
+        assert!(poll_next(&mut debounced).is_pending());
+        clock.advance(ms(8));
+        // A new item restarts the quiet period.
+        tx.unbounded_send(3).unwrap();
+        assert!(poll_next(&mut debounced).is_pending());
+        clock.advance(ms(8));
+        assert!(poll_next(&mut debounced).is_pending());
+        clock.advance(ms(2));
+        assert_eq!(poll_next(&mut debounced), Poll::Ready(Some(3)));
+        assert!(poll_next(&mut debounced).is_pending());
+
+        // The latest item is yielded straight away when the stream ends.
+        tx.unbounded_send(4).unwrap();
+        tx.unbounded_send(5).unwrap();
+        drop(tx);
+        assert_eq!(poll_next(&mut debounced), Poll::Ready(Some(5)));
+        assert_eq!(poll_next(&mut debounced), Poll::Ready(None));
+        assert!(debounced.is_terminated());
+    }
+
+    #[test]
+    fn debounce_returns_from_a_stream_which_is_always_ready() {
+        let mut debounced = Debounce::with_clock(stream::repeat(1), ms(10), MockClock::default());
+        // The stream never goes quiet, so there's never an item, but each poll
+        // returns and wakes the task to be polled again. `block_on` would hang
+        // if it didn't.
+        let mut polls = 0;
+        block_on(std::future::poll_fn(|cx| {
+            assert!(debounced.poll_next_unpin(cx).is_pending());
+            polls += 1;
+            if polls == 3 {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }));
+        assert!(!debounced.is_terminated());
+    }
+
+    #[test]
+    fn timeout_each_reports_each_gap() {
+        let clock = MockClock::default();
+        let (tx, rx) = mpsc::unbounded();
+        let mut timed = TimeoutEach::with_clock(rx, ms(10), clock.clone());
+
+        assert!(poll_next(&mut timed).is_pending());
+        clock.advance(ms(10));
+        assert_eq!(poll_next(&mut timed), Poll::Ready(Some(Err(Elapsed))));
+        // We keep waiting, with a new timeout.
+        assert!(poll_next(&mut timed).is_pending());
+        clock.advance(ms(5));
+        tx.unbounded_send(1).unwrap();
+        assert_eq!(poll_next(&mut timed), Poll::Ready(Some(Ok(1))));
+        assert!(poll_next(&mut timed).is_pending());
+        clock.advance(ms(9));
+        assert!(poll_next(&mut timed).is_pending());
+        clock.advance(ms(1));
+        assert_eq!(poll_next(&mut timed), Poll::Ready(Some(Err(Elapsed))));
+        drop(tx);
+        assert_eq!(poll_next(&mut timed), Poll::Ready(None));
+    }
+
+    #[test]
+    fn with_timer_future() {
+        let start = Instant::now();
+        let items: Vec<_> = block_on(stream::iter(1..=3).throttle(ms(20)).collect());
+        assert_eq!(items, [1, 2, 3]);
+        assert!(start.elapsed() >= ms(40));
+
+        let chunks: Vec<_> = block_on(stream::iter(1..=5).chunks_timeout(2, ms(1000)).collect());
+        assert_eq!(chunks, [vec![1, 2], vec![3, 4], vec![5]]);
+
+        let pending = stream::pending::<()>().timeout_each(ms(10)).take(2);
+        assert_eq!(block_on(pending.collect::<Vec<_>>()), [Err(Elapsed), Err(Elapsed)]);
+
+        assert_eq!(block_on(stream::iter(1..=3).debounce(ms(10)).collect::<Vec<_>>()), [3]);
+    }
+}
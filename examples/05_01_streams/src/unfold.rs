@@ -0,0 +1,152 @@
+//! A stream generated by repeatedly calling an async closure.
+
+use futures::stream::{FusedStream, Stream};
+use std::{
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A stream which calls `f` with its state to produce each item, like
+/// `futures::stream::unfold`.
+pub struct Unfold<T, F, Fut> {
+    f: F,
+    state: UnfoldState<T, Fut>,
+}
+
+enum UnfoldState<T, Fut> {
+    /// Waiting to be polled for the next item.
+    Value(T),
+    /// Running the future returned by `f`.
+    Future(Fut),
+    /// `f`'s future returned `None`.
+    Done,
+}
+
+/// Create a stream from an initial state and an async closure.
+///
+/// Each time the stream is polled for an item, `f` is called with the
+/// current state. If its future returns `Some((item, state))`, the stream
+/// yields `item` and keeps `state` for next time; if it returns `None`, the
+/// stream ends.
+pub fn unfold<T, F, Fut, Item>(init: T, f: F) -> Unfold<T, F, Fut>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = Option<(Item, T)>>,
+{
+    Unfold {
+        f,
+        state: UnfoldState::Value(init),
+    }
+}
+
+impl<T, F, Fut, Item> Stream for Unfold<T, F, Fut>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = Option<(Item, T)>>,
+{
+    type Item = Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Item>> {
+        // SAFETY: the future in `UnfoldState::Future` is structurally pinned:
+        // it is never moved, only dropped in place when `state` is assigned
+        // to, and `Unfold` doesn't implement `Drop`. `f` and the state value
+        // are never pinned, so they can be moved freely.
+        let this = unsafe { self.get_unchecked_mut() };
+
+        if let UnfoldState::Value(_) = this.state {
+            let UnfoldState::Value(value) = mem::replace(&mut this.state, UnfoldState::Done)
+            else {
+                unreachable!()
+            };
+            this.state = UnfoldState::Future((this.f)(value));
+        }
+
+        let fut = match &mut this.state {
+            UnfoldState::Future(fut) => unsafe { Pin::new_unchecked(fut) },
+            UnfoldState::Done => return Poll::Ready(None),
+            UnfoldState::Value(_) => unreachable!(),
+        };
+        match fut.poll(cx) {
+            Poll::Ready(Some((item, next))) => {
+                this.state = UnfoldState::Value(next);
+                Poll::Ready(Some(item))
+            }
+            Poll::Ready(None) => {
+                this.state = UnfoldState::Done;
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.is_terminated() {
+            (0, Some(0))
+        } else {
+            (0, None)
+        }
+    }
+}
+
+impl<T, F, Fut, Item> FusedStream for Unfold<T, F, Fut>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = Option<(Item, T)>>,
+{
+    fn is_terminated(&self) -> bool {
+        matches!(self.state, UnfoldState::Done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::yield_now;
+    use futures::{executor::block_on, StreamExt};
+    use std::cell::Cell;
+
+    #[test]
+    fn countdown() {
+        // The `async` blocks aren't `Unpin`, so neither is the stream.
+        let stream = unfold(3, |n| async move {
+            yield_now().await;
+            if n == 0 {
+                None
+            } else {
+                Some((n, n - 1))
+            }
+        });
+        assert_eq!(block_on(stream.collect::<Vec<_>>()), [3, 2, 1]);
+    }
+
+    #[test]
+    fn state_is_threaded_through() {
+        let fibonacci = unfold((0u64, 1u64), |(a, b)| async move { Some((a, (b, a + b))) });
+        let first: Vec<_> = block_on(fibonacci.take(10).collect());
+        assert_eq!(first, [0, 1, 1, 2, 3, 5, 8, 13, 21, 34]);
+    }
+
+    #[test]
+    fn keeps_returning_none() {
+        let calls = Cell::new(0);
+        let stream = unfold(2, |n| {
+            calls.set(calls.get() + 1);
+            async move { (n > 0).then_some((n, n - 1)) }
+        });
+        let mut stream = std::pin::pin!(stream);
+        block_on(async {
+            assert_eq!(stream.next().await, Some(2));
+            assert_eq!(stream.next().await, Some(1));
+            assert!(!stream.is_terminated());
+            for _ in 0..3 {
+                assert_eq!(stream.next().await, None);
+                assert!(stream.is_terminated());
+                assert_eq!(stream.size_hint(), (0, Some(0)));
+            }
+        });
+        // `f` isn't called again once its future has returned `None`.
+        assert_eq!(calls.get(), 3);
+    }
+}
@@ -0,0 +1,439 @@
+//! A watch channel: receivers see the latest value, and are told when it
+//! changes.
+//!
+//! Unlike a broadcast channel, a watch channel only stores one value. A
+//! receiver which doesn't keep up doesn't see every value, just the latest
+//! one, which makes it a good fit for things like configuration or status.
+
+use futures::stream::{FusedStream, Stream};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    future::Future,
+    ops::Deref,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+};
+
+/// Create a watch channel holding `init`.
+pub fn channel<T>(init: T) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Mutex::new(State {
+        value: init,
+        version: 0,
+        closed: false,
+        receivers: 0,
+        next_id: 0,
+        wakers: HashMap::new(),
+    }));
+    let sender = Sender { shared };
+    let mut receiver = sender.subscribe();
+    // A new channel's receiver hasn't seen the initial value yet.
+    receiver.seen = None;
+    (sender, receiver)
+}
+
+struct State<T> {
+    value: T,
+    /// Incremented each time the value is changed.
+    version: u64,
+    /// Set when the `Sender` is dropped.
+    closed: bool,
+    receivers: usize,
+    next_id: u64,
+    /// The wakers of receivers waiting for a change, by receiver id.
+    wakers: HashMap<u64, Waker>,
+}
+
+impl<T> State<T> {
+    /// Take the wakers of every waiting receiver. They're woken once the
+    /// lock has been released, since a waker might use the channel again.
+    fn take_wakers(&mut self) -> Vec<Waker> {
+        self.wakers.drain().map(|(_, waker)| waker).collect()
+    }
+}
+
+fn wake_all(wakers: Vec<Waker>) {
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+/// The sending half of a watch channel.
+pub struct Sender<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Replace the value, and notify every receiver.
+    ///
+    /// Fails, returning the value, if there are no receivers.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut state = self.shared.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(SendError(value));
+        }
+        state.value = value;
+        state.version += 1;
+        let wakers = state.take_wakers();
+        drop(state);
+        wake_all(wakers);
+        Ok(())
+    }
+
+    /// Borrow the current value. The channel is locked until the `Ref` is
+    /// dropped.
+    pub fn borrow(&self) -> Ref<'_, T> {
+        Ref(self.shared.lock().unwrap())
+    }
+
+    /// Create a receiver which has already seen the current value.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut state = self.shared.lock().unwrap();
+        state.receivers += 1;
+        let id = state.next_id;
+        state.next_id += 1;
+        Receiver {
+            shared: self.shared.clone(),
+            id,
+            seen: Some(state.version),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.closed = true;
+        let wakers = state.take_wakers();
+        drop(state);
+        wake_all(wakers);
+    }
+}
+
+/// The receiving half of a watch channel.
+///
+/// It is a `Stream` which yields the value each time it changes (skipping
+/// any values which were replaced before it was polled), starting with the
+/// current value if it hasn't been seen yet. It ends once the `Sender` has
+/// been dropped.
+pub struct Receiver<T> {
+    shared: Arc<Mutex<State<T>>>,
+    id: u64,
+    /// The version of the value we have seen, if any.
+    seen: Option<u64>,
+}
+
+impl<T> Receiver<T> {
+    /// Borrow the current value, without marking it as seen. The channel is
+    /// locked until the `Ref` is dropped.
+    pub fn borrow(&self) -> Ref<'_, T> {
+        Ref(self.shared.lock().unwrap())
+    }
+
+    /// Borrow the current value, and mark it as seen.
+    pub fn borrow_and_update(&mut self) -> Ref<'_, T> {
+        let state = self.shared.lock().unwrap();
+        self.seen = Some(state.version);
+        Ref(state)
+    }
+
+    /// Whether the value has changed since we last saw it.
+    pub fn has_changed(&self) -> bool {
+        self.seen != Some(self.shared.lock().unwrap().version)
+    }
+
+    /// Wait until the value has changed since we last saw it, then mark it as
+    /// seen.
+    ///
+    /// Fails if the `Sender` has been dropped. The value can still be
+    /// borrowed after that.
+    pub fn changed(&mut self) -> Changed<'_, T> {
+        Changed { receiver: self }
+    }
+
+    fn poll_changed(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Closed>> {
+        let mut state = self.shared.lock().unwrap();
+        if self.seen != Some(state.version) {
+            self.seen = Some(state.version);
+            return Poll::Ready(Ok(()));
+        }
+        if state.closed {
+            return Poll::Ready(Err(Closed));
+        }
+        state.wakers.insert(self.id, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    /// The new receiver has seen the same version of the value as this one.
+    fn clone(&self) -> Self {
+        let mut state = self.shared.lock().unwrap();
+        state.receivers += 1;
+        let id = state.next_id;
+        state.next_id += 1;
+        Receiver {
+            shared: self.shared.clone(),
+            id,
+            seen: self.seen,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.receivers -= 1;
+        state.wakers.remove(&self.id);
+    }
+}
+
+impl<T: Clone> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        match this.poll_changed(cx) {
+            // The value may have changed again since `poll_changed`, so
+            // mark whichever version we actually return as seen.
+            Poll::Ready(Ok(())) => Poll::Ready(Some(this.borrow_and_update().clone())),
+            Poll::Ready(Err(Closed)) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T: Clone> FusedStream for Receiver<T> {
+    fn is_terminated(&self) -> bool {
+        let state = self.shared.lock().unwrap();
+        state.closed && self.seen == Some(state.version)
+    }
+}
+
+/// The future returned by `Receiver::changed`.
+pub struct Changed<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for Changed<'_, T> {
+    type Output = Result<(), Closed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().receiver.poll_changed(cx)
+    }
+}
+
+/// A borrow of the value in a watch channel.
+pub struct Ref<'a, T>(MutexGuard<'a, State<T>>);
+
+impl<T> Deref for Ref<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0.value
+    }
+}
+
+/// The error returned by `Sender::send` when there are no receivers. It
+/// contains the value which couldn't be sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sending on a watch channel with no receivers")
+    }
+}
+
+impl<T: fmt::Debug> Error for SendError<T> {}
+
+/// The error returned by `Receiver::changed` once the `Sender` has been
+/// dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the watch channel's sender has been dropped")
+    }
+}
+
+impl Error for Closed {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{broadcast, test_util::yield_now};
+    use futures::{executor::block_on, future, select, StreamExt};
+
+    #[test]
+    fn changed_waits_for_a_new_value() {
+        let (tx, mut rx) = channel("initial");
+        assert!(rx.has_changed());
+        assert_eq!(*rx.borrow_and_update(), "initial");
+        assert!(!rx.has_changed());
+
+        tx.send("updated").unwrap();
+        assert!(rx.has_changed());
+        block_on(rx.changed()).unwrap();
+        assert_eq!(*rx.borrow(), "updated");
+        assert!(!rx.has_changed());
+    }
+
+    #[test]
+    fn stream_only_yields_the_latest_value() {
+        let (tx, mut rx) = channel(0);
+        assert_eq!(block_on(rx.next()), Some(0));
+        for i in 1..=3 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(block_on(rx.next()), Some(3));
+        drop(tx);
+        assert!(rx.is_terminated());
+        assert_eq!(block_on(rx.next()), None);
+    }
+
+    #[test]
+    fn unseen_value_is_yielded_after_sender_is_dropped() {
+        let (tx, rx) = channel(0);
+        tx.send(1).unwrap();
+        drop(tx);
+        assert_eq!(block_on(rx.collect::<Vec<_>>()), [1]);
+    }
+
+    #[test]
+    fn changed_fails_once_sender_is_dropped() {
+        let (tx, mut rx) = channel(0);
+        rx.borrow_and_update();
+        drop(tx);
+        assert_eq!(block_on(rx.changed()), Err(Closed));
+        assert_eq!(*rx.borrow(), 0);
+    }
+
+    #[test]
+    fn receivers() {
+        let (tx, rx) = channel(0);
+        let mut subscribed = tx.subscribe();
+        let mut cloned = rx.clone();
+        assert!(!subscribed.has_changed());
+        assert!(cloned.has_changed());
+
+        tx.send(1).unwrap();
+        block_on(async {
+            subscribed.changed().await.unwrap();
+            cloned.changed().await.unwrap();
+        });
+        drop((rx, subscribed, cloned));
+        assert_eq!(tx.send(2), Err(SendError(2)));
+        assert_eq!(*tx.borrow(), 1);
+    }
+
+    #[test]
+    fn select_between_work_and_config() {
+        // A worker handles jobs from a broadcast channel, and picks up
+        // configuration changes from a watch channel as they happen.
+        let (config_tx, mut config_rx) = channel("slow");
+        let (jobs_tx, mut jobs_rx) = broadcast::channel(8);
+
+        let producer = async move {
+            jobs_tx.send(1).unwrap();
+            yield_now().await;
+            config_tx.send("fast").unwrap();
+            yield_now().await;
+            jobs_tx.send(2).unwrap();
+            jobs_tx.send(3).unwrap();
+        };
+        let worker = async {
+            // Start from the current configuration, so that a job which is
+            // picked before the first `config_rx.next()` doesn't miss it.
+            let mut mode = *config_rx.borrow_and_update();
+            let mut done = Vec::new();
+            loop {
+                select! {
+                    config = config_rx.next() => {
+                        if let Some(config) = config {
+                            mode = config;
+                        }
+                    }
+                    job = jobs_rx.next() => {
+                        if let Some(job) = job {
+                            done.push((mode, job.unwrap()));
+                        }
+                    }
+                    complete => break,
+                }
+            }
+            done
+        };
+
+        let ((), done) = block_on(future::join(producer, worker));
+        assert_eq!(done, [("slow", 1), ("fast", 2), ("fast", 3)]);
+    }
+
+    #[test]
+    fn changed_is_cancellation_safe() {
+        use cancel_check::{check, Outcome};
+
+        check(
+            || {
+                let (tx, mut rx) = channel(0);
+                rx.borrow_and_update();
+                (tx, rx)
+            },
+            |(tx, rx)| {
+                Box::pin(async move {
+                    let update = async {
+                        yield_now().await;
+                        tx.send(1).unwrap();
+                    };
+                    future::join(rx.changed(), update).await.0.unwrap();
+                })
+            },
+            |(tx, rx), outcome| {
+                let sent = *tx.borrow() == 1;
+                match outcome {
+                    // A `changed` which is dropped mustn't use up the change.
+                    Outcome::Cancelled { .. } if sent && !rx.has_changed() => {
+                        Err("the change was lost".to_string())
+                    }
+                    Outcome::Completed(()) if rx.has_changed() => {
+                        Err("the change wasn't marked as seen".to_string())
+                    }
+                    _ => Ok(()),
+                }
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn receivers_are_woken_after_unlocking() {
+        use std::task::Wake;
+
+        // A waker which drops a receiver, and so locks the channel, when
+        // it's woken. It would deadlock if the channel were still locked.
+        struct DropReceiver(Mutex<Option<Receiver<u32>>>);
+        impl Wake for DropReceiver {
+            fn wake(self: Arc<Self>) {
+                drop(self.0.lock().unwrap().take());
+            }
+        }
+        let drop_waker = |rx| Waker::from(Arc::new(DropReceiver(Mutex::new(Some(rx)))));
+
+        let (tx, mut rx) = channel(0);
+        rx.borrow_and_update();
+        let waker = drop_waker(tx.subscribe());
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut rx.changed()).poll(&mut cx).is_pending());
+        tx.send(1).unwrap();
+        assert!(Pin::new(&mut rx.changed()).poll(&mut cx).is_ready());
+
+        // Dropping the sender wakes the receivers too.
+        let waker = drop_waker(tx.subscribe());
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut rx.changed()).poll(&mut cx).is_pending());
+        drop(tx);
+        assert!(Pin::new(&mut rx.changed()).poll(&mut cx).is_ready());
+    }
+}
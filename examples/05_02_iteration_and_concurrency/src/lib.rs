@@ -2,35 +2,91 @@
 
 use futures::{
     executor::block_on,
+    future,
     stream::{self, Stream},
 };
+use num_traits::CheckedAdd;
 use std::{
+    cell::{Cell, RefCell},
+    error::Error,
+    fmt,
+    future::Future,
     io,
-    pin::Pin,
+    ops::Add,
+    pin::{pin, Pin},
+    task::Poll,
+    time::Duration,
 };
+use timer_future::TimerFuture;
 
 // ANCHOR: nexts
-async fn sum_with_next(mut stream: Pin<&mut dyn Stream<Item = i32>>) -> i32 {
+async fn sum_with_next<T: Add<Output = T> + Default>(
+    mut stream: Pin<&mut dyn Stream<Item = T>>,
+) -> T {
     use futures::stream::StreamExt; // for `next`
-    let mut sum = 0;
+    let mut sum = T::default();
     while let Some(item) = stream.next().await {
-        sum += item;
+        sum = sum + item;
     }
     sum
 }
 
-async fn sum_with_try_next(
-    mut stream: Pin<&mut dyn Stream<Item = Result<i32, io::Error>>>,
-) -> Result<i32, io::Error> {
+async fn sum_with_try_next<T: Add<Output = T> + Default>(
+    mut stream: Pin<&mut dyn Stream<Item = Result<T, io::Error>>>,
+) -> Result<T, io::Error> {
     use futures::stream::TryStreamExt; // for `try_next`
-    let mut sum = 0;
+    let mut sum = T::default();
     while let Some(item) = stream.try_next().await? {
-        sum += item;
+        sum = sum + item;
     }
     Ok(sum)
 }
 // ANCHOR_END: nexts
 
+/// The error returned when a sum doesn't fit in its type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Overflow;
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sum overflowed")
+    }
+}
+
+impl Error for Overflow {}
+
+impl From<Overflow> for io::Error {
+    fn from(overflow: Overflow) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, overflow)
+    }
+}
+
+/// Like `sum_with_next`, but stops at the first item which would overflow
+/// the sum.
+async fn checked_sum_with_next<T: CheckedAdd + Default>(
+    mut stream: Pin<&mut dyn Stream<Item = T>>,
+) -> Result<T, Overflow> {
+    use futures::stream::StreamExt; // for `next`
+    let mut sum = T::default();
+    while let Some(item) = stream.next().await {
+        sum = sum.checked_add(&item).ok_or(Overflow)?;
+    }
+    Ok(sum)
+}
+
+/// Like `sum_with_try_next`, but stops at the first item which would overflow
+/// the sum, with an `InvalidData` error.
+async fn checked_sum_with_try_next<T: CheckedAdd + Default>(
+    mut stream: Pin<&mut dyn Stream<Item = Result<T, io::Error>>>,
+) -> Result<T, io::Error> {
+    use futures::stream::TryStreamExt; // for `try_next`
+    let mut sum = T::default();
+    while let Some(item) = stream.try_next().await? {
+        sum = sum.checked_add(&item).ok_or(Overflow)?;
+    }
+    Ok(sum)
+}
+
 #[test]
 fn run_sum_with_next() {
     let mut stream = stream::iter(vec![2, 3]);
@@ -45,13 +101,107 @@ fn run_sum_with_try_next() {
     assert_eq!(5, block_on(sum_with_try_next(pin)).unwrap());
 }
 
-#[allow(unused)]
+#[test]
+fn sum_with_next_is_generic() {
+    let mut stream = stream::iter(vec![0.5, 0.25]);
+    let pin: Pin<&mut stream::Iter<_>> = Pin::new(&mut stream);
+    assert_eq!(0.75, block_on(sum_with_next(pin)));
+
+    // Adding up `u8`s as `u64`s can't overflow.
+    let mut stream = stream::iter([u8::MAX; 3].map(u64::from));
+    let pin: Pin<&mut stream::Iter<_>> = Pin::new(&mut stream);
+    assert_eq!(765, block_on(sum_with_next(pin)));
+}
+
+#[test]
+fn checked_sums_stop_at_overflow() {
+    let mut stream = stream::iter(vec![100u8, 100, 100, 1]);
+    let pin: Pin<&mut stream::Iter<_>> = Pin::new(&mut stream);
+    assert_eq!(Err(Overflow), block_on(checked_sum_with_next(pin)));
+    // The item after the one which overflowed is never taken.
+    assert_eq!(stream.size_hint(), (1, Some(1)));
+
+    let mut stream = stream::iter(vec![Ok(i32::MIN), Ok(-1)]);
+    let pin: Pin<&mut stream::Iter<_>> = Pin::new(&mut stream);
+    let err = block_on(checked_sum_with_try_next(pin)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "sum overflowed");
+}
+
+proptest::proptest! {
+    #[test]
+    fn sums_match_iterator_sums(values: Vec<i32>) {
+        let expected: i64 = values.iter().map(|&v| i64::from(v)).sum();
+        let mut stream = stream::iter(values.iter().map(|&v| i64::from(v)));
+        let pin: Pin<&mut stream::Iter<_>> = Pin::new(&mut stream);
+        proptest::prop_assert_eq!(block_on(sum_with_next(pin)), expected);
+
+        let expected = values.iter().try_fold(0i32, |sum, &v| sum.checked_add(v));
+        let mut stream = stream::iter(values.clone());
+        let pin: Pin<&mut stream::Iter<_>> = Pin::new(&mut stream);
+        proptest::prop_assert_eq!(block_on(checked_sum_with_next(pin)).ok(), expected);
+    }
+
+    #[test]
+    fn checked_try_sum_reports_first_error(
+        items in proptest::collection::vec(
+            proptest::result::maybe_ok(proptest::num::i8::ANY, proptest::strategy::Just(())),
+            0..20,
+        ),
+    ) {
+        // Whichever comes first of a stream error and an overflow wins.
+        let mut expected = Ok(0i8);
+        for item in &items {
+            expected = match (expected, item) {
+                (Ok(sum), Ok(v)) => sum.checked_add(*v).ok_or(io::ErrorKind::InvalidData),
+                (Ok(_), Err(())) => Err(io::ErrorKind::Other),
+                (err, _) => err,
+            };
+        }
+        let items = items.into_iter().map(|item| item.map_err(|()| io::Error::other("bad item")));
+        let mut stream = stream::iter(items);
+        let pin: Pin<&mut stream::Iter<_>> = Pin::new(&mut stream);
+        let sum = block_on(checked_sum_with_try_next(pin)).map_err(|e| e.kind());
+        proptest::prop_assert_eq!(sum, expected);
+    }
+}
+
+#[test]
+fn sum_generated_stream() {
+    let stream = pin!(generator::from_generator(|mut tx| async move {
+        for i in 1..=4 {
+            tx.send(i * i).await;
+        }
+    }));
+    assert_eq!(30, block_on(sum_with_next(stream)));
+}
+
+#[test]
+fn sum_generated_try_stream() {
+    fn parse_all(input: &'static str) -> impl Stream<Item = Result<i32, io::Error>> {
+        generator::from_generator(move |mut tx| async move {
+            for word in input.split_whitespace() {
+                let n = word
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                tx.send(n).await;
+            }
+            Ok(())
+        })
+    }
+
+    assert_eq!(6, block_on(sum_with_try_next(pin!(parse_all("1 2 3")))).unwrap());
+    let err = block_on(sum_with_try_next(pin!(parse_all("1 two 3")))).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
 // ANCHOR: try_for_each_concurrent
+const MAX_CONCURRENT_JUMPERS: usize = 100;
+
 async fn jump_around(
-    mut stream: Pin<&mut dyn Stream<Item = Result<u8, io::Error>>>,
+    stream: Pin<&mut dyn Stream<Item = Result<u8, io::Error>>>,
 ) -> Result<(), io::Error> {
     use futures::stream::TryStreamExt; // for `try_for_each_concurrent`
-    const MAX_CONCURRENT_JUMPERS: usize = 100;
 
     stream.try_for_each_concurrent(MAX_CONCURRENT_JUMPERS, |num| async move {
         jump_n_times(num).await?;
@@ -63,5 +213,221 @@ async fn jump_around(
 }
 // ANCHOR_END: try_for_each_concurrent
 
-async fn jump_n_times(_: u8) -> Result<(), io::Error> { Ok(()) }
-async fn report_n_jumps(_: u8) -> Result<(), io::Error> { Ok(()) }
+const JUMP_TIME: Duration = Duration::from_millis(1);
+/// Nobody can jump more times than this in one go.
+const MAX_JUMPS: u8 = 50;
+
+// `block_on` polls every jumper on the test's own thread, so thread locals are
+// enough to keep track of them.
+thread_local! {
+    /// How many jumpers are jumping right now.
+    static JUMPERS: Cell<usize> = const { Cell::new(0) };
+    /// The most jumpers there have been at once.
+    static MAX_JUMPERS: Cell<usize> = const { Cell::new(0) };
+    /// Every `report_n_jumps`, in the order they finished.
+    static REPORTS: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Counts as a jumper for as long as it is alive, so a jumper which is
+/// dropped part of the way through stops counting too.
+struct Jumper;
+
+impl Jumper {
+    fn start() -> Self {
+        let jumpers = JUMPERS.get() + 1;
+        JUMPERS.set(jumpers);
+        MAX_JUMPERS.set(MAX_JUMPERS.get().max(jumpers));
+        Jumper
+    }
+}
+
+impl Drop for Jumper {
+    fn drop(&mut self) {
+        JUMPERS.set(JUMPERS.get() - 1);
+    }
+}
+
+async fn jump_n_times(n: u8) -> Result<(), io::Error> {
+    if n > MAX_JUMPS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("can't jump {n} times"),
+        ));
+    }
+    let _jumper = Jumper::start();
+    for _ in 0..n {
+        TimerFuture::new(JUMP_TIME).await;
+    }
+    Ok(())
+}
+
+async fn report_n_jumps(n: u8) -> Result<(), io::Error> {
+    TimerFuture::new(JUMP_TIME).await;
+    REPORTS.with_borrow_mut(|reports| reports.push(n));
+    Ok(())
+}
+
+#[test]
+fn jumpers_are_limited() {
+    let mut stream = stream::iter((0..250).map(|_| Ok(3)));
+    let pin: Pin<&mut stream::Iter<_>> = Pin::new(&mut stream);
+    block_on(jump_around(pin)).unwrap();
+    assert_eq!(REPORTS.take().len(), 250);
+    // How many overlap depends on how quickly the timers fire, but it's never
+    // more than the limit.
+    let max_jumpers = MAX_JUMPERS.get();
+    assert!(max_jumpers > 1 && max_jumpers <= MAX_CONCURRENT_JUMPERS);
+    assert_eq!(JUMPERS.get(), 0);
+}
+
+#[test]
+fn error_aborts_jumpers() {
+    let pulled = Cell::new(0);
+    let items = (0..10)
+        .map(|_| Ok(MAX_JUMPS))
+        .chain([Ok(MAX_JUMPS + 1)])
+        .chain((0..10).map(|_| Ok(1)))
+        .inspect(|_| pulled.set(pulled.get() + 1));
+    let mut stream = stream::iter(items);
+    let pin: Pin<&mut stream::Iter<_>> = Pin::new(&mut stream);
+    let err = block_on(jump_around(pin)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    // The ten jumpers ahead of the one which failed were dropped before they
+    // could finish, and no more were started.
+    assert_eq!(MAX_JUMPERS.get(), 10);
+    assert_eq!(JUMPERS.get(), 0);
+    assert!(REPORTS.take().is_empty());
+    assert_eq!(pulled.get(), 11);
+}
+
+#[test]
+fn stream_error_aborts_jumpers() {
+    let items = vec![
+        Ok(MAX_JUMPS),
+        Ok(MAX_JUMPS),
+        Err(io::ErrorKind::Interrupted.into()),
+    ];
+    let mut stream = stream::iter(items);
+    let pin: Pin<&mut stream::Iter<_>> = Pin::new(&mut stream);
+    let err = block_on(jump_around(pin)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    assert_eq!(MAX_JUMPERS.get(), 2);
+    assert_eq!(JUMPERS.get(), 0);
+    assert!(REPORTS.take().is_empty());
+}
+
+// ANCHOR: buffered
+/// The order in which `run_concurrently` yields the results of the jobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Order {
+    /// The same order as the jobs, using `buffered`.
+    Input,
+    /// As soon as each job finishes, using `buffer_unordered`.
+    Completion,
+}
+
+/// Run `job` on each item of `stream`, with at most `limit` jobs running at
+/// once, and yield their results in the given `order`.
+fn run_concurrently<S, F, Fut>(
+    stream: S,
+    limit: usize,
+    order: Order,
+    job: F,
+) -> impl Stream<Item = Fut::Output>
+where
+    S: Stream,
+    F: FnMut(S::Item) -> Fut,
+    Fut: Future,
+{
+    use futures::{future::Either, stream::StreamExt}; // for `buffered` etc.
+
+    // Nothing runs until the returned stream is polled.
+    let jobs = stream.map(job);
+    match order {
+        Order::Input => Either::Left(jobs.buffered(limit)),
+        Order::Completion => Either::Right(jobs.buffer_unordered(limit)),
+    }
+}
+// ANCHOR_END: buffered
+
+/// Returns `Pending` `n` times, waking the task straight away each time.
+fn yield_n_times(n: u32) -> impl Future<Output = ()> {
+    let mut left = n;
+    future::poll_fn(move |cx| {
+        if left == 0 {
+            return Poll::Ready(());
+        }
+        left -= 1;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+}
+
+/// Run jobs which each take `delays[i]` turns of the executor, returning
+/// the indexes of the jobs in the order they were yielded, and the most jobs
+/// which were running at once.
+fn run_delayed_jobs(delays: &[u32], limit: usize, order: Order) -> (Vec<usize>, usize) {
+    use futures::stream::StreamExt; // for `collect`
+
+    let running = Cell::new(0);
+    let max_running = Cell::new(0);
+    let jobs = stream::iter(delays.iter().copied().enumerate());
+    let results = run_concurrently(jobs, limit, order, |(i, delay)| {
+        let (running, max_running) = (&running, &max_running);
+        async move {
+            running.set(running.get() + 1);
+            max_running.set(max_running.get().max(running.get()));
+            yield_n_times(delay).await;
+            running.set(running.get() - 1);
+            i
+        }
+    });
+    (block_on(results.collect()), max_running.get())
+}
+
+#[test]
+fn buffered_keeps_input_order() {
+    let (results, _) = run_delayed_jobs(&[3, 1, 2], 3, Order::Input);
+    assert_eq!(results, [0, 1, 2]);
+}
+
+#[test]
+fn buffer_unordered_yields_by_completion() {
+    let (results, _) = run_delayed_jobs(&[3, 1, 2], 3, Order::Completion);
+    assert_eq!(results, [1, 2, 0]);
+    // With one job at a time, the jobs finish in order anyway.
+    let (results, _) = run_delayed_jobs(&[3, 1, 2], 1, Order::Completion);
+    assert_eq!(results, [0, 1, 2]);
+}
+
+proptest::proptest! {
+    #[test]
+    fn buffered_preserves_order(
+        delays in proptest::collection::vec(1..10u32, 0..50),
+        limit in 1..20usize,
+    ) {
+        let (results, max_running) = run_delayed_jobs(&delays, limit, Order::Input);
+        proptest::prop_assert_eq!(results, (0..delays.len()).collect::<Vec<_>>());
+        // Every job takes at least one turn, so the first `limit` jobs all
+        // start before any of them finish.
+        proptest::prop_assert_eq!(max_running, limit.min(delays.len()));
+    }
+
+    #[test]
+    fn buffer_unordered_runs_every_job(
+        delays in proptest::collection::vec(1..10u32, 0..50),
+        limit in 1..20usize,
+    ) {
+        let (mut results, max_running) = run_delayed_jobs(&delays, limit, Order::Completion);
+        // When every job starts at once, they finish in order of delay, and
+        // jobs with the same delay finish in input order.
+        if limit >= delays.len() {
+            let mut by_delay: Vec<_> = (0..delays.len()).collect();
+            by_delay.sort_by_key(|&i| delays[i]);
+            proptest::prop_assert_eq!(&results, &by_delay);
+        }
+        results.sort();
+        proptest::prop_assert_eq!(results, (0..delays.len()).collect::<Vec<_>>());
+        proptest::prop_assert_eq!(max_running, limit.min(delays.len()));
+    }
+}
@@ -14,6 +14,10 @@ async fn get_book_and_music() -> (Book, Music) {
     (book, music)
 }
 // ANCHOR_END: naiive
+
+pub(super) fn audit(report: &mut future_audit::Report) {
+    future_audit::audit!(report, get_book_and_music(), 16);
+}
 }
 
 mod other_langs {
@@ -26,6 +30,10 @@ async fn get_book_and_music() -> (Book, Music) {
     (book_future.await, music_future.await)
 }
 // ANCHOR_END: other_langs
+
+pub(super) fn audit(report: &mut future_audit::Report) {
+    future_audit::audit!(report, get_book_and_music(), 16);
+}
 }
 
 mod join {
@@ -39,6 +47,10 @@ async fn get_book_and_music() -> (Book, Music) {
     join!(book_fut, music_fut)
 }
 // ANCHOR_END: join
+
+pub(super) fn audit(report: &mut future_audit::Report) {
+    future_audit::audit!(report, get_book_and_music(), 64);
+}
 }
 
 mod try_join {
@@ -55,6 +67,10 @@ async fn get_book_and_music() -> Result<(Book, Music), String> {
     try_join!(book_fut, music_fut)
 }
 // ANCHOR_END: try_join
+
+pub(super) fn audit(report: &mut future_audit::Report) {
+    future_audit::audit!(report, get_book_and_music(), 256);
+}
 }
 
 mod mismatched_err {
@@ -74,4 +90,32 @@ async fn get_book_and_music() -> Result<(Book, Music), String> {
     try_join!(book_fut, music_fut)
 }
 // ANCHOR_END: try_join_map_err
+
+pub(super) fn audit(report: &mut future_audit::Report) {
+    future_audit::audit!(report, get_book_and_music(), 256);
+}
+}
+
+#[test]
+fn future_sizes() {
+    let mut report = future_audit::Report::new("06_02_join");
+    future_audit::audit!(report, get_book(), 8);
+    future_audit::audit!(report, get_music(), 8);
+    naiive::audit(&mut report);
+    other_langs::audit(&mut report);
+    join::audit(&mut report);
+    try_join::audit(&mut report);
+    mismatched_err::audit(&mut report);
+    report.assert_all_send();
+
+    // Joined futures are all stored at once, so a join is at least as big
+    // as its parts together, while awaiting them in turn only needs space
+    // for one at a time.
+    let parts = report.size("get_book()") + report.size("get_music()");
+    assert!(report.size("join::get_book_and_music()") >= parts);
+    assert!(report.size("other_langs::get_book_and_music()") >= parts);
+    assert!(
+        report.size("naiive::get_book_and_music()")
+            < report.size("join::get_book_and_music()")
+    );
 }
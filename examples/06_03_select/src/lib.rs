@@ -8,22 +8,164 @@ use futures::{
     pin_mut,
     select,
 };
+use std::{future::Future, time::Duration};
+use timer_future::TimerFuture;
 
-async fn task_one() { /* ... */ }
-async fn task_two() { /* ... */ }
+/// Which task completed first.
+#[derive(Debug, PartialEq, Eq)]
+enum Winner {
+    TaskOne,
+    TaskTwo,
+}
+
+async fn task_one() {
+    TimerFuture::new(Duration::from_millis(10)).await
+}
+async fn task_two() {
+    TimerFuture::new(Duration::from_millis(100)).await
+}
+
+async fn race_tasks() -> Winner {
+    race(task_one(), task_two()).await
+}
 
-async fn race_tasks() {
-    let t1 = task_one().fuse();
-    let t2 = task_two().fuse();
+async fn race(t1: impl Future<Output = ()>, t2: impl Future<Output = ()>) -> Winner {
+    let t1 = t1.fuse();
+    let t2 = t2.fuse();
 
     pin_mut!(t1, t2);
 
     select! {
-        () = t1 => println!("task one completed first"),
-        () = t2 => println!("task two completed first"),
+        () = t1 => Winner::TaskOne,
+        () = t2 => Winner::TaskTwo,
     }
+    // The task which didn't complete is dropped here, which cancels it.
 }
 // ANCHOR_END: example
+
+use std::cell::{Cell, RefCell};
+
+const STEPS: u32 = 3;
+
+thread_local! {
+    /// The tasks which were dropped before they completed, with how many
+    /// steps they had done. `block_on` runs the tasks on the test's thread.
+    static CANCELLED: RefCell<Vec<(&'static str, u32)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Records a task in `CANCELLED` if it is dropped before it completes.
+struct CancelGuard<'a> {
+    name: &'static str,
+    steps: &'a Cell<u32>,
+}
+
+impl Drop for CancelGuard<'_> {
+    fn drop(&mut self) {
+        if self.steps.get() < STEPS {
+            CANCELLED.with_borrow_mut(|cancelled| cancelled.push((self.name, self.steps.get())));
+        }
+    }
+}
+
+/// Run `task` `STEPS` times, counting the steps in `steps`.
+async fn run_steps<F: Future<Output = ()>>(
+    name: &'static str,
+    steps: &Cell<u32>,
+    task: impl Fn() -> F,
+) {
+    let _guard = CancelGuard { name, steps };
+    for _ in 0..STEPS {
+        task().await;
+        steps.set(steps.get() + 1);
+    }
+}
+
+/// `race_tasks`, with each task run `STEPS` times and counted.
+async fn race_steps(t1_steps: &Cell<u32>, t2_steps: &Cell<u32>) -> Winner {
+    race(
+        run_steps("task one", t1_steps, task_one),
+        run_steps("task two", t2_steps, task_two),
+    )
+    .await
+}
+
+#[test]
+fn faster_task_wins() {
+    assert_eq!(futures::executor::block_on(race_tasks()), Winner::TaskOne);
+}
+
+#[test]
+fn loser_is_dropped_when_select_returns() {
+    let (t1_steps, t2_steps) = (Cell::new(0), Cell::new(0));
+    futures::executor::block_on(async {
+        // Task one takes 30ms in all, and task two 300ms. Exactly how far
+        // task two gets depends on the timers.
+        assert_eq!(race_steps(&t1_steps, &t2_steps).await, Winner::TaskOne);
+        // By the time `race` has returned, the loser has been dropped part
+        // of the way through, and will never run again.
+        let task_two_steps = t2_steps.get();
+        assert!(task_two_steps < STEPS);
+        assert_eq!(CANCELLED.take(), [("task two", task_two_steps)]);
+        TimerFuture::new(Duration::from_millis(300)).await;
+        assert_eq!(t2_steps.get(), task_two_steps);
+        assert!(CANCELLED.take().is_empty());
+    });
+    assert_eq!(t1_steps.get(), STEPS);
+}
+
+#[test]
+fn race_cancels_both_tasks_if_dropped() {
+    use cancel_check::{check, Outcome};
+
+    check(
+        || (Cell::new(0), Cell::new(0)),
+        |(t1_steps, t2_steps)| Box::pin(race_steps(t1_steps, t2_steps)),
+        |_, outcome| {
+            let mut cancelled: Vec<_> = CANCELLED.take().into_iter().map(|(name, _)| name).collect();
+            cancelled.sort();
+            let expected: &[_] = match outcome {
+                // Neither task had started.
+                Outcome::Cancelled { polls: 0 } => &[],
+                // Both tasks are dropped along with `race`.
+                Outcome::Cancelled { .. } => &["task one", "task two"],
+                Outcome::Completed(_) => &["task two"],
+            };
+            if cancelled == expected {
+                Ok(())
+            } else {
+                Err(format!("cancelled {:?}", cancelled))
+            }
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn race_polls_each_task_at_most_once_to_completion() {
+    use future_trait::fuse::real::{Checked, Fuse};
+
+    // Our own `Fuse` works in place of `FutureExt::fuse`, and `Checked`
+    // panics if `select!` polls a task after it has completed.
+    let (t1_steps, t2_steps) = (Cell::new(0), Cell::new(0));
+    let t1 = Checked::new(Fuse::new(run_steps("task one", &t1_steps, task_one)));
+    let t2 = Checked::new(Fuse::new(run_steps("task two", &t2_steps, task_two)));
+    pin_mut!(t1, t2);
+
+    futures::executor::block_on(async {
+        let mut completed = 0;
+        loop {
+            select! {
+                () = t1 => completed += 1,
+                () = t2 => completed += 1,
+                complete => break,
+            }
+        }
+        assert_eq!(completed, 2);
+    });
+    // Neither task was cancelled.
+    assert_eq!((t1_steps.get(), t2_steps.get()), (STEPS, STEPS));
+    assert!(CANCELLED.take().is_empty());
+}
 }
 
 mod default_and_complete {
@@ -47,10 +189,86 @@ async fn count() {
 }
 // ANCHOR_END: default_and_complete
 
+use futures::FutureExt;
+use select_order::{Branch, Selected, Selector};
+
+/// `count`, with `selector` choosing the order of the branches instead of
+/// `select!`. Returns the branches it took, in order.
+fn count_in_order(selector: &mut Selector) -> Vec<&'static str> {
+    let mut a_fut = future::ready(4);
+    let mut b_fut = future::ready(6);
+    let mut total = 0;
+    let mut taken = Vec::new();
+
+    loop {
+        let mut branches: [Branch<'_, i32>; 2] = [&mut a_fut, &mut b_fut];
+        // `now_or_never` gives `None` where `select!` would run `default`.
+        match selector.select(&mut branches).now_or_never() {
+            Some(Selected::Branch(0, a)) => {
+                total += a;
+                taken.push("a");
+            }
+            Some(Selected::Branch(_, b)) => {
+                total += b;
+                taken.push("b");
+            }
+            Some(Selected::Complete) => {
+                taken.push("complete");
+                break;
+            }
+            None => unreachable!(),
+        }
+    }
+    assert_eq!(total, 10);
+    taken
+}
+
 #[test]
 fn run_count() {
     futures::executor::block_on(count());
 }
+
+#[test]
+fn count_never_polls_completed_futures() {
+    use future_trait::fuse::real::Checked;
+
+    futures::executor::block_on(async {
+        let mut a_fut = Checked::new(future::ready(4));
+        let mut b_fut = Checked::new(future::ready(6));
+        let mut total = 0;
+
+        loop {
+            select! {
+                a = a_fut => total += a,
+                b = b_fut => total += b,
+                complete => break,
+            };
+        }
+        assert_eq!(total, 10);
+    });
+}
+
+#[test]
+fn count_branch_order() {
+    assert_eq!(count_in_order(&mut Selector::biased()), ["a", "b", "complete"]);
+    // With both futures ready, the seed decides which goes first.
+    assert_eq!(count_in_order(&mut Selector::seeded(0)), ["a", "b", "complete"]);
+    assert_eq!(count_in_order(&mut Selector::seeded(2)), ["b", "a", "complete"]);
+
+    // A selector which is used again carries on from where it was, so a
+    // whole run of loops can be replayed from its seed.
+    let mut selector = Selector::seeded(42);
+    let runs: Vec<_> = (0..4).map(|_| count_in_order(&mut selector)).collect();
+    assert_eq!(
+        runs,
+        [
+            ["a", "b", "complete"],
+            ["b", "a", "complete"],
+            ["a", "b", "complete"],
+            ["b", "a", "complete"],
+        ]
+    );
+}
 }
 
 mod fused_stream {
@@ -59,12 +277,19 @@ use futures::{
     stream::{Stream, StreamExt, FusedStream},
     select,
 };
+use std::ops::Add;
 
-async fn add_two_streams(
-    mut s1: impl Stream<Item = u8> + FusedStream + Unpin,
-    mut s2: impl Stream<Item = u8> + FusedStream + Unpin,
-) -> u8 {
-    let mut total = 0;
+async fn add_two_streams<T, Total>(
+    mut s1: impl Stream<Item = T> + FusedStream + Unpin,
+    mut s2: impl Stream<Item = T> + FusedStream + Unpin,
+) -> Total
+where
+    // Adding up into a wider type, such as `u8`s into a `u64`, avoids
+    // overflow.
+    T: Into<Total>,
+    Total: Add<Output = Total> + Default,
+{
+    let mut total = Total::default();
 
     loop {
         let item = select! {
@@ -73,13 +298,207 @@ async fn add_two_streams(
             complete => break,
         };
         if let Some(next_num) = item {
-            total += next_num;
+            total = total + next_num.into();
         }
     }
 
     total
 }
 // ANCHOR_END: fused_stream
+
+use num_traits::CheckedAdd;
+
+/// Like `add_two_streams`, but stops at the first item which would overflow
+/// the total, and returns `None`.
+async fn checked_add_two_streams<T, Total>(
+    mut s1: impl FusedStream<Item = T> + Unpin,
+    mut s2: impl FusedStream<Item = T> + Unpin,
+) -> Option<Total>
+where
+    T: Into<Total>,
+    Total: CheckedAdd + Default,
+{
+    let mut total = Total::default();
+
+    loop {
+        let item = select! {
+            x = s1.next() => x,
+            x = s2.next() => x,
+            complete => break,
+        };
+        if let Some(next_num) = item {
+            total = total.checked_add(&next_num.into())?;
+        }
+    }
+
+    Some(total)
+}
+
+/// Like `add_two_streams`, but adds up into a total owned by the caller.
+///
+/// `add_two_streams` isn't cancellation safe: if it is dropped part of the
+/// way through, the items it has taken from the streams are lost along with
+/// its running total. Keeping the total outside the future fixes that.
+async fn add_two_streams_into<T, Total>(
+    total: &mut Total,
+    mut s1: impl FusedStream<Item = T> + Unpin,
+    mut s2: impl FusedStream<Item = T> + Unpin,
+)
+where
+    T: Into<Total>,
+    Total: Add<Output = Total> + Default,
+{
+    loop {
+        let item = select! {
+            x = s1.next() => x,
+            x = s2.next() => x,
+            complete => break,
+        };
+        if let Some(next_num) = item {
+            *total = std::mem::take(total) + next_num.into();
+        }
+    }
+}
+
+/// A stream of `items` which returns `Pending` before each one, so that a
+/// future reading it can be cancelled in between.
+fn paced(items: Vec<u8>) -> impl FusedStream<Item = u8> + Unpin {
+    cancel_check::yield_between(futures::stream::iter(items)).fuse()
+}
+
+/// The sum of the items left in `stream`.
+fn rest(stream: impl FusedStream<Item = u8> + Unpin) -> u64 {
+    futures::executor::block_on(stream.map(u64::from).fold(0, |a, b| async move { a + b }))
+}
+
+#[test]
+fn add_two_streams_is_not_cancellation_safe() {
+    use cancel_check::{check, Outcome};
+
+    let failure = check(
+        || (paced(vec![1, 2, 3]), paced(vec![10, 20])),
+        |(s1, s2)| Box::pin(add_two_streams::<u8, u64>(s1, s2)),
+        |(s1, s2), outcome| {
+            let total = match outcome {
+                Outcome::Cancelled { .. } => 0,
+                Outcome::Completed(total) => total,
+            };
+            match total + rest(s1) + rest(s2) {
+                36 => Ok(()),
+                sum => Err(format!("only {} is accounted for", sum)),
+            }
+        },
+    )
+    .unwrap_err();
+    // The first item is taken on the second poll, and lost if the future is
+    // dropped after that.
+    assert_eq!(failure.polls, 2);
+    assert!(failure.cancelled);
+}
+
+#[test]
+fn add_two_streams_into_is_cancellation_safe() {
+    let points = cancel_check::check(
+        || (0, paced(vec![1, 2, 3]), paced(vec![10, 20])),
+        |(total, s1, s2)| Box::pin(add_two_streams_into::<u8, u64>(total, s1, s2)),
+        |(total, s1, s2), _| match *total + rest(s1) + rest(s2) {
+            36 => Ok(()),
+            sum => Err(format!("only {} is accounted for", sum)),
+        },
+    )
+    .unwrap();
+    assert!(points > 0);
+}
+
+#[test]
+fn add_two_streams_does_not_overflow() {
+    use futures::stream;
+
+    let s1 = stream::iter(vec![u8::MAX; 1000]).fuse();
+    let s2 = stream::iter(vec![1; 10]).fuse();
+    let total: u64 = futures::executor::block_on(add_two_streams(s1, s2));
+    assert_eq!(total, 255 * 1000 + 10);
+}
+
+#[test]
+fn checked_add_two_streams_reports_overflow() {
+    use futures::stream;
+
+    let s1 = stream::iter(vec![200u8]).fuse();
+    let s2 = stream::iter(vec![100u8]).fuse();
+    let total = futures::executor::block_on(checked_add_two_streams::<u8, u8>(s1, s2));
+    assert_eq!(total, None);
+
+    let s1 = stream::iter(vec![200u8]).fuse();
+    let s2 = stream::iter(vec![100u8]).fuse();
+    let total = futures::executor::block_on(checked_add_two_streams::<u8, u16>(s1, s2));
+    assert_eq!(total, Some(300));
+}
+
+proptest::proptest! {
+    #[test]
+    fn totals_match_iterator_sums(a: Vec<u8>, b: Vec<u8>) {
+        use futures::{executor::block_on, stream};
+
+        let expected: u64 = a.iter().chain(&b).map(|&x| u64::from(x)).sum();
+        let total: u64 = block_on(add_two_streams(
+            stream::iter(a.clone()).fuse(),
+            stream::iter(b.clone()).fuse(),
+        ));
+        proptest::prop_assert_eq!(total, expected);
+
+        // The items are added in a random order, but all of them are
+        // non-negative, so the total overflows at some point if and only if
+        // the full sum does.
+        let expected = u16::try_from(expected).ok();
+        let total = block_on(checked_add_two_streams::<u8, u16>(
+            stream::iter(a).fuse(),
+            stream::iter(b).fuse(),
+        ));
+        proptest::prop_assert_eq!(total, expected);
+    }
+
+    #[test]
+    fn signed_totals_fit_or_overflow(a: Vec<i8>, b: Vec<i8>) {
+        use futures::{executor::block_on, stream};
+
+        let total = block_on(checked_add_two_streams::<i8, i8>(
+            stream::iter(a.clone()).fuse(),
+            stream::iter(b.clone()).fuse(),
+        ));
+        let full: i64 = a.iter().chain(&b).map(|&x| i64::from(x)).sum();
+        // With mixed signs, whether an overflow happens along the way depends
+        // on the order, but a success is always the right answer.
+        if let Some(total) = total {
+            proptest::prop_assert_eq!(i64::from(total), full);
+        }
+        if i8::try_from(full).is_err() {
+            proptest::prop_assert_eq!(total, None);
+        }
+    }
+}
+}
+
+/// Helpers shared by the `run_loop` examples.
+mod daemon {
+use futures::stream::{self, FusedStream, StreamExt};
+use std::time::Duration;
+use timer_future::TimerFuture;
+
+/// Wait for `ms` milliseconds.
+pub(crate) fn sleep_ms(ms: u8) -> TimerFuture {
+    TimerFuture::new(Duration::from_millis(ms.into()))
+}
+
+/// A stream which yields every `period`, forever.
+pub(crate) fn interval(period: Duration) -> impl FusedStream<Item = ()> + Unpin {
+    stream::unfold((), move |()| async move {
+        TimerFuture::new(period).await;
+        Some(((), ()))
+    })
+    .boxed()
+    .fuse()
+}
 }
 
 mod fuse_terminated {
@@ -90,18 +509,36 @@ use futures::{
     pin_mut,
     select,
 };
+use std::{future::Future, time::Duration};
+use timer_future::TimerFuture;
 
-async fn get_new_num() -> u8 { /* ... */ 5 }
+/// What `run_loop` did before it was shut down.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Stats {
+    numbers_fetched: u32,
+    tasks_run: u32,
+    tasks_cancelled: u32,
+}
 
-async fn run_on_new_num(_: u8) { /* ... */ }
+async fn get_new_num() -> u8 {
+    TimerFuture::new(Duration::from_millis(1)).await;
+    50
+}
+
+async fn run_on_new_num(n: u8) {
+    TimerFuture::new(Duration::from_millis(n.into())).await
+}
 
 async fn run_loop(
     mut interval_timer: impl Stream<Item = ()> + FusedStream + Unpin,
     starting_num: u8,
-) {
+    shutdown: impl Future<Output = ()>,
+) -> Stats {
+    let mut stats = Stats::default();
     let run_on_new_num_fut = run_on_new_num(starting_num).fuse();
     let get_new_num_fut = Fuse::terminated();
-    pin_mut!(run_on_new_num_fut, get_new_num_fut);
+    let shutdown = shutdown.fuse();
+    pin_mut!(run_on_new_num_fut, get_new_num_fut, shutdown);
     loop {
         select! {
             () = interval_timer.select_next_some() => {
@@ -114,17 +551,64 @@ async fn run_loop(
             new_num = get_new_num_fut => {
                 // A new number has arrived -- start a new `run_on_new_num_fut`,
                 // dropping the old one.
+                stats.numbers_fetched += 1;
+                if !run_on_new_num_fut.is_terminated() {
+                    stats.tasks_cancelled += 1;
+                }
                 run_on_new_num_fut.set(run_on_new_num(new_num).fuse());
             },
             // Run the `run_on_new_num_fut`
-            () = run_on_new_num_fut => {},
-            // panic if everything completed, since the `interval_timer` should
-            // keep yielding values indefinitely.
-            complete => panic!("`interval_timer` completed unexpectedly"),
+            () = run_on_new_num_fut => stats.tasks_run += 1,
+            // Stop when we're told to. `shutdown` never terminates without
+            // completing this branch, so we don't need a `complete` branch.
+            () = shutdown => break,
         }
     }
+    // The task which is still running is dropped when we return.
+    if !run_on_new_num_fut.is_terminated() {
+        stats.tasks_cancelled += 1;
+    }
+    stats
 }
 // ANCHOR_END: fuse_terminated
+
+use super::daemon::{interval, sleep_ms};
+use futures::{channel::oneshot, executor::block_on, future};
+
+#[test]
+fn stops_straight_away_if_already_shut_down() {
+    let stats = block_on(run_loop(interval(Duration::from_millis(10)), 50, future::ready(())));
+    // Nothing else was ready, and the first task was cancelled.
+    assert_eq!(stats, Stats { numbers_fetched: 0, tasks_run: 0, tasks_cancelled: 1 });
+}
+
+#[test]
+fn new_numbers_cancel_the_running_task() {
+    // A number arrives every 10ms or so, and each task takes 50ms, so tasks
+    // are usually replaced before they can finish. Exactly how many depends
+    // on the timers, but every task either ran or was cancelled.
+    let shutdown = sleep_ms(105);
+    let stats = block_on(run_loop(interval(Duration::from_millis(10)), 50, shutdown));
+    assert_eq!(stats.tasks_run + stats.tasks_cancelled, stats.numbers_fetched + 1);
+}
+
+#[test]
+fn shuts_down_from_a_channel() {
+    let (tx, rx) = oneshot::channel::<()>();
+    let stats = block_on(async {
+        let run = run_loop(interval(Duration::from_millis(10)), 1, rx.map(|_| ()));
+        let stop = async {
+            sleep_ms(30).await;
+            tx.send(()).unwrap();
+        };
+        future::join(run, stop).await.0
+    });
+    // The 1ms starting task had time to finish, but none of the 50ms tasks
+    // started after it could have.
+    assert!((1..=3).contains(&stats.numbers_fetched), "{:?}", stats);
+    assert_eq!(stats.tasks_run, 1);
+    assert_eq!(stats.tasks_run + stats.tasks_cancelled, stats.numbers_fetched + 1);
+}
 }
 
 mod futures_unordered {
@@ -135,19 +619,38 @@ use futures::{
     pin_mut,
     select,
 };
+use std::{future::Future, time::Duration};
+use timer_future::TimerFuture;
 
-async fn get_new_num() -> u8 { /* ... */ 5 }
+/// What `run_loop` did before it was shut down.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Stats {
+    numbers_fetched: u32,
+    tasks_run: u32,
+    tasks_cancelled: u32,
+}
 
-async fn run_on_new_num(_: u8) -> u8 { /* ... */ 5 }
+async fn get_new_num() -> u8 {
+    TimerFuture::new(Duration::from_millis(1)).await;
+    50
+}
+
+async fn run_on_new_num(n: u8) -> u8 {
+    TimerFuture::new(Duration::from_millis(n.into())).await;
+    n
+}
 
 async fn run_loop(
     mut interval_timer: impl Stream<Item = ()> + FusedStream + Unpin,
     starting_num: u8,
-) {
+    shutdown: impl Future<Output = ()>,
+) -> Stats {
+    let mut stats = Stats::default();
     let mut run_on_new_num_futs = FuturesUnordered::new();
     run_on_new_num_futs.push(run_on_new_num(starting_num));
     let get_new_num_fut = Fuse::terminated();
-    pin_mut!(get_new_num_fut);
+    let shutdown = shutdown.fuse();
+    pin_mut!(get_new_num_fut, shutdown);
     loop {
         select! {
             () = interval_timer.select_next_some() => {
@@ -159,18 +662,45 @@ async fn run_loop(
             },
             new_num = get_new_num_fut => {
                 // A new number has arrived -- start a new `run_on_new_num_fut`.
+                stats.numbers_fetched += 1;
                 run_on_new_num_futs.push(run_on_new_num(new_num));
             },
             // Run the `run_on_new_num_futs` and check if any have completed
             res = run_on_new_num_futs.select_next_some() => {
                 println!("run_on_new_num_fut returned {:?}", res);
+                stats.tasks_run += 1;
             },
-            // panic if everything completed, since the `interval_timer` should
-            // keep yielding values indefinitely.
-            complete => panic!("`interval_timer` completed unexpectedly"),
+            // Stop when we're told to.
+            () = shutdown => break,
         }
     }
+    // The tasks which are still running are dropped when we return.
+    stats.tasks_cancelled = run_on_new_num_futs.len() as u32;
+    stats
 }
-
 // ANCHOR_END: futures_unordered
+
+use super::daemon::{interval, sleep_ms};
+use futures::{executor::block_on, future};
+
+#[test]
+fn stops_straight_away_if_already_shut_down() {
+    let stats = block_on(run_loop(interval(Duration::from_millis(10)), 50, future::ready(())));
+    assert_eq!(stats, Stats { numbers_fetched: 0, tasks_run: 0, tasks_cancelled: 1 });
 }
+
+#[test]
+fn tasks_run_alongside_each_other() {
+    // A number arrives every 10ms or so, and each task takes 50ms. New tasks
+    // don't cancel old ones, so the early ones finish, and only the ones
+    // still running at shutdown are cancelled.
+    let shutdown = sleep_ms(105);
+    let stats = block_on(run_loop(interval(Duration::from_millis(10)), 50, shutdown));
+    assert!((3..=10).contains(&stats.numbers_fetched), "{:?}", stats);
+    assert!(stats.tasks_run >= 1, "{:?}", stats);
+    assert!(stats.tasks_cancelled >= 1, "{:?}", stats);
+    assert_eq!(stats.tasks_run + stats.tasks_cancelled, stats.numbers_fetched + 1);
+}
+}
+
+mod merge;
@@ -0,0 +1,347 @@
+//! Combining several streams into one, without starving any of them.
+//!
+//! `select!` in a loop picks a ready branch at random, so no stream is
+//! starved on average, but with `select_biased!` (or a hand-written poll
+//! which always checks the same stream first) a stream which is always ready
+//! shuts the others out. `merge_all` takes turns between its streams
+//! instead, and `zip_latest` takes turns between its two.
+
+use futures::stream::{FusedStream, Stream, StreamExt};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Merge the items of `streams` into one stream, in the order they become
+/// ready.
+///
+/// The streams are polled round-robin: after a stream yields an item, the
+/// next poll starts with the stream after it, so each stream gets a turn
+/// before any stream gets two. The merged stream ends when all of them have.
+pub fn merge_all<S: Stream + Unpin>(streams: Vec<S>) -> MergeAll<S> {
+    MergeAll { streams, next: 0 }
+}
+
+/// Stream for `merge_all`.
+pub struct MergeAll<S> {
+    /// The streams which haven't ended.
+    streams: Vec<S>,
+    /// The index of the stream to poll first.
+    next: usize,
+}
+
+impl<S: Stream + Unpin> Stream for MergeAll<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = &mut *self;
+        // Each stream is polled at most once per call, so that a stream which
+        // is always ready can't keep us here. `polled` counts the streams
+        // which returned `Pending`.
+        let mut polled = 0;
+        let mut i = this.next;
+        while polled < this.streams.len() {
+            if i >= this.streams.len() {
+                i = 0;
+            }
+            match this.streams[i].poll_next_unpin(cx) {
+                Poll::Ready(Some(item)) => {
+                    this.next = i + 1;
+                    return Poll::Ready(Some(item));
+                }
+                Poll::Ready(None) => {
+                    // The stream after it moves down into its place, and gets
+                    // polled next.
+                    this.streams.remove(i);
+                }
+                Poll::Pending => {
+                    polled += 1;
+                    i += 1;
+                }
+            }
+        }
+        if this.streams.is_empty() {
+            Poll::Ready(None)
+        } else {
+            // Every stream which is left has returned `Pending`, and will
+            // wake us.
+            this.next = i;
+            Poll::Pending
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.streams
+            .iter()
+            .map(|stream| stream.size_hint())
+            .fold((0, Some(0)), |(lower, upper), (l, u)| {
+                (
+                    lower.saturating_add(l),
+                    upper.zip(u).and_then(|(upper, u)| upper.checked_add(u)),
+                )
+            })
+    }
+}
+
+impl<S: Stream + Unpin> FusedStream for MergeAll<S> {
+    fn is_terminated(&self) -> bool {
+        self.streams.is_empty()
+    }
+}
+
+/// Combine the latest items of `a` and `b`: once both have yielded an item,
+/// yield a pair of their latest items each time either of them yields a new
+/// one.
+///
+/// The two streams take turns to be polled first. The combined stream ends
+/// when both have ended, or when one ends without ever yielding an item.
+pub fn zip_latest<A, B>(a: A, b: B) -> ZipLatest<A, B>
+where
+    A: Stream,
+    B: Stream,
+    A::Item: Clone,
+    B::Item: Clone,
+{
+    ZipLatest {
+        a,
+        b,
+        latest_a: None,
+        latest_b: None,
+        a_done: false,
+        b_done: false,
+        b_first: false,
+    }
+}
+
+/// Stream for `zip_latest`.
+pub struct ZipLatest<A: Stream, B: Stream> {
+    a: A,
+    b: B,
+    latest_a: Option<A::Item>,
+    latest_b: Option<B::Item>,
+    a_done: bool,
+    b_done: bool,
+    /// Whether to poll `b` before `a` next time.
+    b_first: bool,
+}
+
+impl<A, B> ZipLatest<A, B>
+where
+    A: Stream,
+    B: Stream,
+    A::Item: Clone,
+    B::Item: Clone,
+{
+    /// Poll `a` for a new item, returning whether it ended.
+    fn poll_a(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<bool> {
+        // SAFETY: `a` and `b` are structurally pinned: they are never moved
+        // out of `self`, and we don't implement `Drop`. Nothing else is
+        // pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.a_done {
+            return Poll::Pending;
+        }
+        match unsafe { Pin::new_unchecked(&mut this.a) }.poll_next(cx) {
+            Poll::Ready(Some(item)) => {
+                this.latest_a = Some(item);
+                Poll::Ready(false)
+            }
+            Poll::Ready(None) => {
+                this.a_done = true;
+                Poll::Ready(true)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Poll `b` for a new item, returning whether it ended.
+    fn poll_b(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<bool> {
+        // SAFETY: as for `poll_a`.
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.b_done {
+            return Poll::Pending;
+        }
+        match unsafe { Pin::new_unchecked(&mut this.b) }.poll_next(cx) {
+            Poll::Ready(Some(item)) => {
+                this.latest_b = Some(item);
+                Poll::Ready(false)
+            }
+            Poll::Ready(None) => {
+                this.b_done = true;
+                Poll::Ready(true)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Whether there will never be another pair.
+    fn finished(&self) -> bool {
+        (self.a_done && (self.b_done || self.latest_a.is_none()))
+            || (self.b_done && self.latest_b.is_none())
+    }
+}
+
+impl<A, B> Stream for ZipLatest<A, B>
+where
+    A: Stream,
+    B: Stream,
+    A::Item: Clone,
+    B::Item: Clone,
+{
+    type Item = (A::Item, B::Item);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut updated = false;
+        for b_turn in [self.b_first, !self.b_first] {
+            let polled = if b_turn {
+                self.as_mut().poll_b(cx)
+            } else {
+                self.as_mut().poll_a(cx)
+            };
+            match polled {
+                Poll::Ready(false) => {
+                    updated = true;
+                    if let (Some(a), Some(b)) = (&self.latest_a, &self.latest_b) {
+                        let pair = (a.clone(), b.clone());
+                        // SAFETY: we don't move `a` or `b`.
+                        let this = unsafe { self.get_unchecked_mut() };
+                        // The other stream goes first next time.
+                        this.b_first = !b_turn;
+                        return Poll::Ready(Some(pair));
+                    }
+                }
+                Poll::Ready(true) => updated = true,
+                Poll::Pending => {}
+            }
+        }
+        if self.finished() {
+            Poll::Ready(None)
+        } else if updated {
+            // A stream moved on without making a pair, and might be ready
+            // again straight away. Give other tasks a turn before we poll it
+            // again.
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<A, B> FusedStream for ZipLatest<A, B>
+where
+    A: Stream,
+    B: Stream,
+    A::Item: Clone,
+    B::Item: Clone,
+{
+    fn is_terminated(&self) -> bool {
+        self.finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{channel::mpsc, executor::block_on, select_biased, stream};
+
+    fn poll_next<S: Stream + Unpin>(stream: &mut S) -> Poll<Option<S::Item>> {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        stream.poll_next_unpin(&mut cx)
+    }
+
+    #[test]
+    fn biased_select_starves_the_second_stream() {
+        // For comparison: the first branch of `select_biased!` always wins.
+        let mut busy = stream::repeat('a');
+        let mut other = stream::repeat('b');
+        let mut seen = String::new();
+        block_on(async {
+            for _ in 0..6 {
+                select_biased! {
+                    c = busy.next() => seen.extend(c),
+                    c = other.next() => seen.extend(c),
+                }
+            }
+        });
+        assert_eq!(seen, "aaaaaa");
+    }
+
+    #[test]
+    fn merge_all_takes_turns() {
+        let streams = vec![
+            stream::repeat('a').boxed(),
+            stream::repeat('b').boxed(),
+            stream::iter("cc".chars()).boxed(),
+        ];
+        let merged: String = block_on(merge_all(streams).take(10).collect());
+        assert_eq!(merged, "abcabcabab");
+    }
+
+    #[test]
+    fn merge_all_does_not_starve_a_slow_stream() {
+        let (tx, rx) = mpsc::unbounded();
+        let mut merged = merge_all(vec![stream::repeat(0).boxed(), rx.boxed()]);
+        assert_eq!(poll_next(&mut merged), Poll::Ready(Some(0)));
+        assert_eq!(poll_next(&mut merged), Poll::Ready(Some(0)));
+        tx.unbounded_send(1).unwrap();
+        // The busy stream has had its turn, so the slow one is next.
+        assert_eq!(poll_next(&mut merged), Poll::Ready(Some(1)));
+        assert_eq!(poll_next(&mut merged), Poll::Ready(Some(0)));
+    }
+
+    #[test]
+    fn merge_all_ends_when_every_stream_ends() {
+        let streams = vec![stream::iter(1..=3), stream::iter(4..=4), stream::iter(5..=6)];
+        let merged = merge_all(streams);
+        assert_eq!(merged.size_hint(), (6, Some(6)));
+        let items: Vec<_> = block_on(merged.collect());
+        assert_eq!(items, [1, 4, 5, 2, 6, 3]);
+
+        let mut empty = merge_all(Vec::<stream::Empty<()>>::new());
+        assert_eq!(poll_next(&mut empty), Poll::Ready(None));
+        assert!(empty.is_terminated());
+    }
+
+    #[test]
+    fn zip_latest_pairs_latest_items() {
+        let (tx_a, rx_a) = mpsc::unbounded();
+        let (tx_b, rx_b) = mpsc::unbounded();
+        let mut zipped = zip_latest(rx_a, rx_b);
+
+        tx_a.unbounded_send(1).unwrap();
+        // There's nothing to pair it with yet.
+        assert!(poll_next(&mut zipped).is_pending());
+        tx_b.unbounded_send('x').unwrap();
+        assert_eq!(poll_next(&mut zipped), Poll::Ready(Some((1, 'x'))));
+        tx_b.unbounded_send('y').unwrap();
+        assert_eq!(poll_next(&mut zipped), Poll::Ready(Some((1, 'y'))));
+        tx_a.unbounded_send(2).unwrap();
+        assert_eq!(poll_next(&mut zipped), Poll::Ready(Some((2, 'y'))));
+        assert!(poll_next(&mut zipped).is_pending());
+
+        // After `a` ends, its last item is paired with `b`'s new ones.
+        drop(tx_a);
+        tx_b.unbounded_send('z').unwrap();
+        assert_eq!(poll_next(&mut zipped), Poll::Ready(Some((2, 'z'))));
+        drop(tx_b);
+        assert_eq!(poll_next(&mut zipped), Poll::Ready(None));
+        assert!(zipped.is_terminated());
+    }
+
+    #[test]
+    fn zip_latest_takes_turns() {
+        let zipped = zip_latest(stream::iter(0..), stream::iter(0..));
+        let pairs: Vec<_> = block_on(zipped.take(6).collect());
+        assert_eq!(pairs, [(0, 0), (1, 0), (1, 1), (2, 1), (2, 2), (3, 2)]);
+    }
+
+    #[test]
+    fn zip_latest_ends_if_a_stream_never_yields() {
+        let (tx, rx) = mpsc::unbounded::<u8>();
+        let mut zipped = zip_latest(stream::repeat(()), rx);
+        assert!(poll_next(&mut zipped).is_pending());
+        drop(tx);
+        assert_eq!(poll_next(&mut zipped), Poll::Ready(None));
+    }
+}
@@ -40,6 +40,39 @@ async fn task_spawner(){
 }
 // ANCHOR_END: join_all
 
+// ANCHOR: scope
+use std::{cell::RefCell, convert::Infallible};
+
+async fn scoped_task_spawner() -> Vec<Duration> {
+    let finished = RefCell::new(Vec::new());
+    let finished_ref = &finished;
+    task_scope::scope(|s| async move {
+        for millis in [30, 10, 20] {
+            let time = Duration::from_millis(millis);
+            // The tasks can borrow `finished`, since they can't outlive the
+            // scope.
+            s.spawn(async move {
+                my_task(time).await;
+                finished_ref.borrow_mut().push(time);
+                Ok(())
+            });
+        }
+        Ok::<_, Infallible>(())
+    })
+    .await
+    .unwrap();
+    // Every task has finished by now.
+    finished.into_inner()
+}
+// ANCHOR_END: scope
+
+#[test]
+fn run_scoped_task_spawner() {
+    let finished = futures::executor::block_on(scoped_task_spawner());
+    let millis: Vec<_> = finished.iter().map(Duration::as_millis).collect();
+    assert_eq!(millis, [10, 20, 30]);
+}
+
 #[test]
 fn run_task_spawner() {
     futures::executor::block_on(task_spawner());
@@ -1,4 +1,3 @@
-#![cfg(test)]
 #![allow(dead_code)]
 
 // ANCHOR: example
@@ -18,3 +17,115 @@ async fn recursive_pinned() {
     Box::pin(recursive_pinned()).await;
 }
 // ANCHOR_END: example_pinned
+
+mod fibonacci {
+// ANCHOR: fib_boxed
+use futures::future::{join_all, BoxFuture, FutureExt};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+type Cache = Arc<Mutex<HashMap<u64, u64>>>;
+
+fn fib(n: u64, cache: Cache) -> BoxFuture<'static, u64> {
+    async move {
+        if n < 2 {
+            return n;
+        }
+        if let Some(&result) = cache.lock().unwrap().get(&n) {
+            return result;
+        }
+        // Compute both children concurrently.
+        let children = vec![fib(n - 1, cache.clone()), fib(n - 2, cache.clone())];
+        let result = join_all(children).await.into_iter().sum();
+        cache.lock().unwrap().insert(n, result);
+        result
+    }.boxed()
+}
+// ANCHOR_END: fib_boxed
+
+// ANCHOR: fib_pinned
+async fn fib_pinned(n: u64, cache: &Mutex<HashMap<u64, u64>>) -> u64 {
+    if n < 2 {
+        return n;
+    }
+    if let Some(&result) = cache.lock().unwrap().get(&n) {
+        return result;
+    }
+    let children = vec![
+        Box::pin(fib_pinned(n - 1, cache)),
+        Box::pin(fib_pinned(n - 2, cache)),
+    ];
+    let result = join_all(children).await.into_iter().sum();
+    cache.lock().unwrap().insert(n, result);
+    result
+}
+// ANCHOR_END: fib_pinned
+
+fn fib_iterative(n: u64) -> u64 {
+    let (mut a, mut b) = (0, 1);
+    for _ in 0..n {
+        (a, b) = (b, a + b);
+    }
+    a
+}
+
+#[test]
+fn run_fib() {
+    use futures::executor::block_on;
+
+    // Without the cache this would make billions of calls.
+    let cache = Cache::default();
+    assert_eq!(block_on(fib(60, cache.clone())), fib_iterative(60));
+    assert_eq!(cache.lock().unwrap().len(), 59);
+
+    let cache = Mutex::default();
+    assert_eq!(block_on(fib_pinned(60, &cache)), fib_iterative(60));
+    assert_eq!(cache.lock().unwrap().len(), 59);
+}
+
+#[test]
+fn run_fib_concurrently() {
+    use futures::executor::block_on;
+
+    // Many traversals can share one cache, even while they run concurrently.
+    let cache = Cache::default();
+    let all = block_on(join_all((0..=40).map(|n| fib(n, cache.clone()))));
+    let expected: Vec<_> = (0..=40).map(fib_iterative).collect();
+    assert_eq!(all, expected);
+
+    let cache = Mutex::default();
+    let all = block_on(join_all((0..=40).map(|n| fib_pinned(n, &cache))));
+    assert_eq!(all, expected);
+}
+
+#[test]
+fn fib_on_a_thread_pool() {
+    use futures::executor::ThreadPool;
+
+    // `fib` is `Send + 'static`, so it can be spawned onto another thread.
+    let pool = ThreadPool::new().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    pool.spawn_ok(async move {
+        tx.send(fib(80, Cache::default()).await).unwrap();
+    });
+    assert_eq!(rx.recv().unwrap(), fib_iterative(80));
+}
+}
+
+pub mod trampoline;
+
+#[test]
+fn future_sizes() {
+    let mut report = future_audit::Report::new("07_05_recursion");
+    future_audit::audit!(report, recursive(), 32);
+    future_audit::audit!(report, recursive_pinned(), 32);
+    report.assert_all_send();
+
+    // Boxing keeps the recursive futures small, however deep they recurse:
+    // each level only holds a pointer to the next one.
+    let pointer = std::mem::size_of::<BoxFuture<'static, ()>>();
+    assert!(report.size("recursive()") <= pointer);
+    assert!(report.size("recursive_pinned()") <= pointer);
+}
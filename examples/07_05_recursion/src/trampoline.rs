@@ -0,0 +1,298 @@
+//! Running recursive async functions without growing the stack.
+//!
+//! With `Box::pin` recursion, each level of recursion is a future which polls
+//! the next level from inside its own `poll`, so polling a future `n` levels
+//! deep takes `n` nested calls, and a deep enough recursion overflows the
+//! stack.
+//!
+//! `Recurse` is a trampoline: instead of awaiting a recursive call directly,
+//! the body awaits `Recurser::recurse`, which hands the argument back to the
+//! driver. The driver keeps the suspended callers on an explicit,
+//! heap-allocated stack, and only ever polls the innermost call, so the
+//! native stack stays the same depth however deep the recursion goes.
+//!
+//! Recursive calls are sequential: each call to the body may only have one
+//! `recurse` call in flight at a time.
+
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+
+/// The error returned when a recursion goes deeper than the limit passed to
+/// `recurse`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DepthLimitExceeded {
+    pub limit: usize,
+}
+
+impl fmt::Display for DepthLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "recursion exceeded the depth limit of {}", self.limit)
+    }
+}
+
+impl Error for DepthLimitExceeded {}
+
+/// Passes arguments and results between the driver and the running call.
+struct Slot<A, T> {
+    /// The argument of a recursive call which the driver should start.
+    call: Option<A>,
+    /// The result of the last recursive call to complete.
+    ret: Option<T>,
+}
+
+/// Handle passed to the body of a recursive function, for making recursive
+/// calls.
+pub struct Recurser<A, T> {
+    slot: Arc<Mutex<Slot<A, T>>>,
+}
+
+impl<A, T> Clone for Recurser<A, T> {
+    fn clone(&self) -> Self {
+        Recurser {
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+impl<A, T> Recurser<A, T> {
+    /// Call the recursive function with `arg`, and wait for its result.
+    pub fn recurse(&self, arg: A) -> RecursiveCall<A, T> {
+        RecursiveCall {
+            arg: Some(arg),
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+/// The future returned by `Recurser::recurse`.
+pub struct RecursiveCall<A, T> {
+    // `None` once the call has been handed to the driver.
+    arg: Option<A>,
+    slot: Arc<Mutex<Slot<A, T>>>,
+}
+
+// We never pin any of our fields.
+impl<A, T> Unpin for RecursiveCall<A, T> {}
+
+impl<A, T> Future for RecursiveCall<A, T> {
+    type Output = T;
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<T> {
+        let this = &mut *self;
+        let mut slot = this.slot.lock().unwrap();
+        if let Some(arg) = this.arg.take() {
+            // Ask the driver to start the call. It will see this as soon as
+            // we return, so there's no need to wake the task.
+            assert!(
+                slot.call.is_none(),
+                "only one recursive call may be in flight at a time"
+            );
+            slot.call = Some(arg);
+            return Poll::Pending;
+        }
+        // The driver only polls us again once the call has completed.
+        Poll::Ready(slot.ret.take().expect("recursive call polled outside of `Recurse`"))
+    }
+}
+
+/// A future which runs a recursive async function to completion, keeping
+/// its suspended calls on the heap.
+pub struct Recurse<'a, A, T, F> {
+    f: F,
+    recurser: Recurser<A, T>,
+    /// Suspended calls; the last one is the innermost and the only one which
+    /// is polled.
+    stack: Vec<BoxFuture<'a, T>>,
+    max_depth: usize,
+}
+
+/// Call the recursive function `f` with `arg`.
+///
+/// `f` is called for each level of recursion with that level's argument and
+/// a `Recurser`, which it uses to make recursive calls. The returned future
+/// fails with `DepthLimitExceeded` if the recursion gets more than
+/// `max_depth` calls deep.
+pub fn recurse<'a, A, T, F, Fut>(arg: A, max_depth: usize, f: F) -> Recurse<'a, A, T, F>
+where
+    F: FnMut(A, Recurser<A, T>) -> Fut,
+    Fut: Future<Output = T> + Send + 'a,
+{
+    let recurser = Recurser {
+        slot: Arc::new(Mutex::new(Slot {
+            call: Some(arg),
+            ret: None,
+        })),
+    };
+    Recurse {
+        f,
+        recurser,
+        stack: Vec::new(),
+        max_depth,
+    }
+}
+
+impl<A, T, F> Unpin for Recurse<'_, A, T, F> {}
+
+impl<'a, A, T, F, Fut> Future for Recurse<'a, A, T, F>
+where
+    F: FnMut(A, Recurser<A, T>) -> Fut,
+    Fut: Future<Output = T> + Send + 'a,
+{
+    type Output = Result<T, DepthLimitExceeded>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            // Start a call if one was requested: either the initial call, or a
+            // recursive call made by the innermost call when it was polled.
+            let call = this.recurser.slot.lock().unwrap().call.take();
+            if let Some(arg) = call {
+                if this.stack.len() == this.max_depth {
+                    this.stack.clear();
+                    return Poll::Ready(Err(DepthLimitExceeded {
+                        limit: this.max_depth,
+                    }));
+                }
+                let future = (this.f)(arg, this.recurser.clone());
+                this.stack.push(Box::pin(future));
+            }
+
+            let Some(innermost) = this.stack.last_mut() else {
+                panic!("`Recurse` polled after completion");
+            };
+            match innermost.as_mut().poll(cx) {
+                Poll::Ready(output) => {
+                    this.stack.pop();
+                    if this.stack.is_empty() {
+                        return Poll::Ready(Ok(output));
+                    }
+                    // Resume the caller with the result.
+                    this.recurser.slot.lock().unwrap().ret = Some(output);
+                }
+                Poll::Pending => {
+                    if this.recurser.slot.lock().unwrap().call.is_none() {
+                        // Waiting on something other than a recursive call,
+                        // which will wake the task when it's ready.
+                        return Poll::Pending;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, future};
+
+    /// A tree stored as a list of nodes, each with the indices of its
+    /// children. Unlike a tree of `Box`es, dropping a very deep one doesn't
+    /// recurse.
+    struct Tree {
+        children: Vec<Vec<usize>>,
+    }
+
+    impl Tree {
+        /// A tree which is just a path, `depth` nodes deep.
+        fn path(depth: usize) -> Self {
+            Tree {
+                children: (0..depth)
+                    .map(|i| if i + 1 < depth { vec![i + 1] } else { vec![] })
+                    .collect(),
+            }
+        }
+    }
+
+    /// Count the nodes of the tree by visiting every node, depth first.
+    async fn count_nodes(tree: &Tree, max_depth: usize) -> Result<usize, DepthLimitExceeded> {
+        recurse(0, max_depth, |node, r| async move {
+            let mut count = 1;
+            for &child in &tree.children[node] {
+                count += r.recurse(child).await;
+            }
+            count
+        })
+        .await
+    }
+
+    #[test]
+    fn deep_recursion_does_not_overflow() {
+        let tree = Tree::path(100_000);
+        assert_eq!(block_on(count_nodes(&tree, usize::MAX)), Ok(100_000));
+    }
+
+    #[test]
+    fn wide_tree() {
+        // Node 0 has children 1..=3, each of which has two leaf children.
+        let tree = Tree {
+            children: vec![
+                vec![1, 2, 3],
+                vec![4, 5],
+                vec![6, 7],
+                vec![8, 9],
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+            ],
+        };
+        assert_eq!(block_on(count_nodes(&tree, 3)), Ok(10));
+    }
+
+    #[test]
+    fn depth_limit() {
+        let tree = Tree::path(1000);
+        assert_eq!(
+            block_on(count_nodes(&tree, 999)),
+            Err(DepthLimitExceeded { limit: 999 })
+        );
+        assert_eq!(block_on(count_nodes(&tree, 1000)), Ok(1000));
+    }
+
+    #[test]
+    fn calls_can_await_other_futures() {
+        // Each call yields to the executor before recursing, so the driver
+        // has to handle real `Pending`s as well as recursive calls.
+        let sum = recurse(10_000u64, usize::MAX, |n, r| async move {
+            let mut yielded = false;
+            future::poll_fn(|cx| {
+                if yielded {
+                    Poll::Ready(())
+                } else {
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            })
+            .await;
+            if n == 0 {
+                0
+            } else {
+                n + r.recurse(n - 1).await
+            }
+        });
+        assert_eq!(block_on(sum), Ok(50_005_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "only one recursive call may be in flight at a time")]
+    fn concurrent_calls_panic() {
+        let future = recurse(1u32, usize::MAX, |n, r| async move {
+            if n == 0 {
+                return 0;
+            }
+            let (a, b) = futures::join!(r.recurse(n - 1), r.recurse(n - 1));
+            a + b
+        });
+        let _ = block_on(future);
+    }
+}
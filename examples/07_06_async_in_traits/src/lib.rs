@@ -0,0 +1,294 @@
+#![cfg(test)]
+
+use futures::future::BoxFuture;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::Future,
+    io,
+    path::PathBuf,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
+
+// ANCHOR: storage
+/// A simple key-value store.
+trait Storage {
+    async fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+    async fn put(&self, key: &str, value: Vec<u8>) -> io::Result<()>;
+    /// Returns `true` if the key was present.
+    async fn delete(&self, key: &str) -> io::Result<bool>;
+}
+// ANCHOR_END: storage
+
+// ANCHOR: send_storage
+/// `Storage` for stores whose futures can be sent between threads.
+///
+/// Callers of a generic `S: Storage` can't tell whether `S::get` returns a
+/// `Send` future, so they can't spawn it onto a multithreaded runtime.
+/// Spelling out the return types lets us add a `Send` bound.
+trait SendStorage: Send + Sync {
+    fn get(&self, key: &str) -> impl Future<Output = io::Result<Option<Vec<u8>>>> + Send;
+    fn put(&self, key: &str, value: Vec<u8>) -> impl Future<Output = io::Result<()>> + Send;
+    fn delete(&self, key: &str) -> impl Future<Output = io::Result<bool>> + Send;
+}
+
+// Every `SendStorage` can be used as a `Storage`.
+impl<S: SendStorage> Storage for S {
+    async fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        SendStorage::get(self, key).await
+    }
+    async fn put(&self, key: &str, value: Vec<u8>) -> io::Result<()> {
+        SendStorage::put(self, key, value).await
+    }
+    async fn delete(&self, key: &str) -> io::Result<bool> {
+        SendStorage::delete(self, key).await
+    }
+}
+// ANCHOR_END: send_storage
+
+// ANCHOR: dyn_storage
+/// A version of `SendStorage` which can be used as a trait object.
+///
+/// A trait with `async fn`s isn't dyn compatible, since each implementation
+/// returns a different future type. Boxing the futures gives every
+/// implementation the same return types, at the cost of an allocation per
+/// call.
+trait DynStorage: Send + Sync {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<Option<Vec<u8>>>>;
+    fn put<'a>(&'a self, key: &'a str, value: Vec<u8>) -> BoxFuture<'a, io::Result<()>>;
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<bool>>;
+}
+
+impl<S: SendStorage> DynStorage for S {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<Option<Vec<u8>>>> {
+        Box::pin(SendStorage::get(self, key))
+    }
+    fn put<'a>(&'a self, key: &'a str, value: Vec<u8>) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(SendStorage::put(self, key, value))
+    }
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        Box::pin(SendStorage::delete(self, key))
+    }
+}
+// ANCHOR_END: dyn_storage
+
+// ANCHOR: memory_storage
+#[derive(Default)]
+struct MemoryStorage {
+    map: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl SendStorage for MemoryStorage {
+    // We can still write `async fn`s here, as long as they are `Send`.
+    async fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.map.lock().unwrap().get(key).cloned())
+    }
+    async fn put(&self, key: &str, value: Vec<u8>) -> io::Result<()> {
+        self.map.lock().unwrap().insert(key.to_owned(), value);
+        Ok(())
+    }
+    async fn delete(&self, key: &str) -> io::Result<bool> {
+        Ok(self.map.lock().unwrap().remove(key).is_some())
+    }
+}
+// ANCHOR_END: memory_storage
+
+// ANCHOR: file_storage
+/// Stores each value in a file named after its key.
+struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        if key.is_empty() || key.contains(['/', '\\']) || key.starts_with('.') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid key: {key:?}"),
+            ));
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+impl SendStorage for FileStorage {
+    async fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    async fn put(&self, key: &str, value: Vec<u8>) -> io::Result<()> {
+        tokio::fs::write(self.path(key)?, value).await
+    }
+    async fn delete(&self, key: &str) -> io::Result<bool> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+// ANCHOR_END: file_storage
+
+// ANCHOR: local_storage
+/// A single-threaded store. `Rc` isn't `Send`, so this can implement
+/// `Storage`, but not `SendStorage`.
+#[derive(Default)]
+struct LocalStorage {
+    map: Rc<RefCell<HashMap<String, Vec<u8>>>>,
+}
+
+impl Storage for LocalStorage {
+    async fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.map.borrow().get(key).cloned())
+    }
+    async fn put(&self, key: &str, value: Vec<u8>) -> io::Result<()> {
+        self.map.borrow_mut().insert(key.to_owned(), value);
+        Ok(())
+    }
+    async fn delete(&self, key: &str) -> io::Result<bool> {
+        Ok(self.map.borrow_mut().remove(key).is_some())
+    }
+}
+// ANCHOR_END: local_storage
+
+/// A directory for a `FileStorage`, which is removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "async-book-07_06-{name}-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    fn storage(&self) -> FileStorage {
+        FileStorage { dir: self.0.clone() }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// ANCHOR: generic
+/// Works with any `Storage`, but the future it returns is only `Send` if
+/// `S`'s futures are, which the compiler can't know here.
+async fn rename<S: Storage>(storage: &S, from: &str, to: &str) -> io::Result<bool> {
+    let Some(value) = storage.get(from).await? else {
+        return Ok(false);
+    };
+    storage.put(to, value).await?;
+    storage.delete(from).await
+}
+// ANCHOR_END: generic
+
+/// Puts, gets and deletes a key through a trait object.
+async fn exercise(storage: &dyn DynStorage, key: &str) -> io::Result<()> {
+    assert_eq!(storage.get(key).await?, None);
+    storage.put(key, key.as_bytes().to_vec()).await?;
+    assert_eq!(storage.get(key).await?.as_deref(), Some(key.as_bytes()));
+    assert!(storage.delete(key).await?);
+    assert!(!storage.delete(key).await?);
+    Ok(())
+}
+
+#[test]
+fn local_storage() {
+    futures::executor::block_on(async {
+        let storage = LocalStorage::default();
+        storage.put("a", b"1".to_vec()).await.unwrap();
+        assert!(rename(&storage, "a", "b").await.unwrap());
+        assert!(!rename(&storage, "a", "b").await.unwrap());
+        assert_eq!(storage.get("b").await.unwrap(), Some(b"1".to_vec()));
+    });
+}
+
+#[tokio::test]
+async fn send_storage_is_also_storage() {
+    let storage = MemoryStorage::default();
+    Storage::put(&storage, "a", b"1".to_vec()).await.unwrap();
+    assert!(rename(&storage, "a", "b").await.unwrap());
+    assert_eq!(Storage::get(&storage, "b").await.unwrap(), Some(b"1".to_vec()));
+}
+
+// ANCHOR: spawn_generic
+// With `SendStorage`, generic code can spawn tasks which use the store.
+fn spawn_put<S: SendStorage + 'static>(
+    storage: Arc<S>,
+    key: String,
+) -> tokio::task::JoinHandle<io::Result<()>> {
+    tokio::spawn(async move { SendStorage::put(&*storage, &key, key.clone().into_bytes()).await })
+}
+// ANCHOR_END: spawn_generic
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn spawn_generic() {
+    let storage = Arc::new(MemoryStorage::default());
+    let handles: Vec<_> = (0..8)
+        .map(|i| spawn_put(storage.clone(), format!("key{i}")))
+        .collect();
+    for handle in handles {
+        handle.await.unwrap().unwrap();
+    }
+    assert_eq!(storage.map.lock().unwrap().len(), 8);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn trait_objects_on_multithreaded_runtime() {
+    let dir = TempDir::new("trait_objects");
+    let stores: Vec<Arc<dyn DynStorage>> = vec![
+        Arc::new(MemoryStorage::default()),
+        Arc::new(dir.storage()),
+    ];
+
+    let mut handles = Vec::new();
+    for storage in &stores {
+        for i in 0..16 {
+            let storage = storage.clone();
+            handles.push(tokio::spawn(async move {
+                exercise(&*storage, &format!("key{i}")).await
+            }));
+        }
+    }
+    for handle in handles {
+        handle.await.unwrap().unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn boxed_trait_object_moves_between_tasks() {
+    let dir = TempDir::new("boxed");
+    let storage: Box<dyn DynStorage> = Box::new(dir.storage());
+
+    // Move the store into one task, then get it back and use it in another.
+    let storage = tokio::spawn(async move {
+        storage.put("greeting", b"hello".to_vec()).await.unwrap();
+        storage
+    })
+    .await
+    .unwrap();
+    let value = tokio::spawn(async move { storage.get("greeting").await })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(value.as_deref(), Some(&b"hello"[..]));
+}
+
+#[tokio::test]
+async fn file_storage_rejects_bad_keys() {
+    let dir = TempDir::new("bad_keys");
+    let storage = dir.storage();
+    for key in ["", "../escape", "a/b", ".hidden"] {
+        let err = SendStorage::get(&storage, key).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,424 @@
+//! A test harness for the cancellation safety of futures.
+//!
+//! A future used as a branch of `select!` in a loop is dropped whenever
+//! another branch completes first. That cancels it at whichever `.await` it
+//! had reached, and anything it had done up to then, like taking a message
+//! out of a channel, is lost unless it was recorded somewhere outside the
+//! future (see the "Cancellation and cancellation safety" chapter).
+//!
+//! `check` tries every such point: it creates the future, polls it until it
+//! has returned `Pending` `n` times, drops it, and then checks an invariant
+//! on the state the future was working on, for `n = 0, 1, 2, ...` until the
+//! future completes without being dropped.
+//!
+//! ```
+//! use example_cancel_check::{check, yield_now, Outcome};
+//! use std::collections::VecDeque;
+//!
+//! // Takes a message, then yields, then takes another: if it's cancelled at
+//! // the `yield_now`, the first message is lost.
+//! let result = check(
+//!     || VecDeque::from([1, 2]),
+//!     |queue| {
+//!         Box::pin(async move {
+//!             let a = queue.pop_front();
+//!             yield_now().await;
+//!             (a, queue.pop_front())
+//!         })
+//!     },
+//!     |queue, outcome| match outcome {
+//!         Outcome::Cancelled { .. } if queue.len() != 2 => Err("a message was lost".into()),
+//!         _ => Ok(()),
+//!     },
+//! );
+//! let failure = result.unwrap_err();
+//! assert_eq!(failure.polls, 1);
+//! assert_eq!(failure.to_string(), "after being cancelled at poll 1: a message was lost");
+//! ```
+//!
+//! The future has to make progress by itself, like the futures in a test
+//! run with `block_on`: each time it returns `Pending`, the harness waits
+//! for it to be woken before polling it again, and panics if it isn't woken
+//! in time. `yield_now` and `yield_between` add `Pending` points to futures
+//! and streams which would otherwise always be ready.
+
+use futures::{future::LocalBoxFuture, stream::Stream};
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll, Wake, Waker},
+    time::Duration,
+};
+
+/// How a future which was being checked ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome<T> {
+    /// It was dropped after returning `Pending` from `polls` polls.
+    Cancelled { polls: usize },
+    /// It completed.
+    Completed(T),
+}
+
+/// The error returned when an invariant doesn't hold.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    /// How many times the future was polled.
+    pub polls: usize,
+    /// Whether it was cancelled, rather than completing.
+    pub cancelled: bool,
+    /// The message returned by the invariant.
+    pub message: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.cancelled {
+            write!(f, "after being cancelled at poll {}: {}", self.polls, self.message)
+        } else {
+            write!(f, "after completing in {} polls: {}", self.polls, self.message)
+        }
+    }
+}
+
+impl Error for Failure {}
+
+/// Checks futures with the default settings: at most 1000 polls, and a
+/// timeout of 5 seconds for each wakeup.
+///
+/// * `setup` creates the state the future works on, once for each run.
+/// * `make` creates the future from that state.
+/// * `invariant` is called with the state after the future has been dropped,
+///   and returns a message if something is wrong.
+///
+/// Returns how many times the future returned `Pending` when it was run to
+/// completion, which is the number of points at which it was cancelled.
+pub fn check<C, T>(
+    setup: impl FnMut() -> C,
+    make: impl for<'a> FnMut(&'a mut C) -> LocalBoxFuture<'a, T>,
+    invariant: impl FnMut(&mut C, Outcome<T>) -> Result<(), String>,
+) -> Result<usize, Failure> {
+    Checker::new().check(setup, make, invariant)
+}
+
+/// Checks futures, with settings. See `check`.
+#[derive(Debug, Clone)]
+pub struct Checker {
+    max_polls: usize,
+    wake_timeout: Duration,
+}
+
+impl Default for Checker {
+    fn default() -> Self {
+        Checker::new()
+    }
+}
+
+impl Checker {
+    pub fn new() -> Self {
+        Checker {
+            max_polls: 1000,
+            wake_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Stop once the future has been cancelled after `max_polls` polls,
+    /// even if it hasn't completed. This is for futures like event loops
+    /// which never finish by themselves.
+    pub fn max_polls(mut self, max_polls: usize) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    /// How long to wait for the future to be woken after it returns
+    /// `Pending`, before giving up and panicking.
+    pub fn wake_timeout(mut self, wake_timeout: Duration) -> Self {
+        self.wake_timeout = wake_timeout;
+        self
+    }
+
+    /// See `check`.
+    pub fn check<C, T>(
+        &self,
+        mut setup: impl FnMut() -> C,
+        mut make: impl for<'a> FnMut(&'a mut C) -> LocalBoxFuture<'a, T>,
+        mut invariant: impl FnMut(&mut C, Outcome<T>) -> Result<(), String>,
+    ) -> Result<usize, Failure> {
+        for cancel_at in 0.. {
+            let mut state = setup();
+            // The future borrows the state, so it has to be dropped before
+            // the invariant can look at it.
+            let (outcome, polls) = self.run(make(&mut state), cancel_at);
+            let cancelled = matches!(outcome, Outcome::Cancelled { .. });
+            invariant(&mut state, outcome).map_err(|message| Failure {
+                polls,
+                cancelled,
+                message,
+            })?;
+            if !cancelled {
+                // Every poll but the last returned `Pending`.
+                return Ok(polls - 1);
+            }
+            if cancel_at == self.max_polls {
+                return Ok(cancel_at);
+            }
+        }
+        unreachable!()
+    }
+
+    /// Poll `fut` until it completes, or until it has returned `Pending`
+    /// `cancel_at` times, and return how it ended and how many times it was
+    /// polled. `fut` is dropped before this returns.
+    fn run<T>(&self, mut fut: LocalBoxFuture<'_, T>, cancel_at: usize) -> (Outcome<T>, usize) {
+        let notify = Arc::new(Notify::default());
+        let waker = Waker::from(notify.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut polls = 0;
+        loop {
+            if polls == cancel_at {
+                return (Outcome::Cancelled { polls }, polls);
+            }
+            polls += 1;
+            if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+                return (Outcome::Completed(output), polls);
+            }
+            // There's no need to wait if we're about to drop it.
+            if polls < cancel_at && !notify.wait(self.wake_timeout) {
+                panic!(
+                    "the future returned `Pending` from poll {} and wasn't woken within {:?}",
+                    polls, self.wake_timeout,
+                );
+            }
+        }
+    }
+}
+
+/// A waker which records that it was woken, for a thread to wait on.
+#[derive(Default)]
+struct Notify {
+    woken: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Notify {
+    /// Wait until we have been woken, and reset. Returns `false` if we
+    /// weren't woken within `timeout`.
+    fn wait(&self, timeout: Duration) -> bool {
+        let woken = self.woken.lock().unwrap();
+        let (mut woken, _) = self
+            .condvar
+            .wait_timeout_while(woken, timeout, |woken| !*woken)
+            .unwrap();
+        std::mem::replace(&mut *woken, false)
+    }
+}
+
+impl Wake for Notify {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        *self.woken.lock().unwrap() = true;
+        self.condvar.notify_one();
+    }
+}
+
+/// Returns `Pending` once, waking the task straight away. This gives a
+/// future a point at which it can be cancelled.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// The future returned by `yield_now`.
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Make `stream` return `Pending` once before each item (and before it
+/// ends), waking the task straight away.
+pub fn yield_between<S: Stream + Unpin>(stream: S) -> YieldBetween<S> {
+    YieldBetween {
+        stream,
+        yielded: false,
+    }
+}
+
+/// The stream returned by `yield_between`.
+pub struct YieldBetween<S> {
+    stream: S,
+    yielded: bool,
+}
+
+impl<S: Stream + Unpin> Stream for YieldBetween<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        if !self.yielded {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        let next = Pin::new(&mut self.stream).poll_next(cx);
+        if next.is_ready() {
+            self.yielded = false;
+        }
+        next
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.stream.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, future, StreamExt};
+    use std::collections::VecDeque;
+
+    /// Take two messages, yielding in between, which loses the first one if
+    /// it's cancelled there.
+    async fn take_two_unsafe(queue: &mut VecDeque<u32>) -> Vec<u32> {
+        let a = queue.pop_front().unwrap();
+        yield_now().await;
+        let b = queue.pop_front().unwrap();
+        vec![a, b]
+    }
+
+    /// Take two messages once they are both wanted, which is cancellation
+    /// safe.
+    async fn take_two_safe(queue: &mut VecDeque<u32>) -> Vec<u32> {
+        yield_now().await;
+        yield_now().await;
+        queue.drain(..2).collect()
+    }
+
+    fn nothing_lost(queue: &mut VecDeque<u32>, outcome: Outcome<Vec<u32>>) -> Result<(), String> {
+        let mut seen = match outcome {
+            Outcome::Cancelled { .. } => Vec::new(),
+            Outcome::Completed(taken) => taken,
+        };
+        seen.extend(queue.iter());
+        if seen == [1, 2] {
+            Ok(())
+        } else {
+            Err(format!("saw {:?}", seen))
+        }
+    }
+
+    #[test]
+    fn safe_future_passes() {
+        let points = check(
+            || VecDeque::from([1, 2]),
+            |queue| Box::pin(take_two_safe(queue)),
+            nothing_lost,
+        );
+        assert_eq!(points, Ok(2));
+    }
+
+    #[test]
+    fn unsafe_future_fails() {
+        let failure = check(
+            || VecDeque::from([1, 2]),
+            |queue| Box::pin(take_two_unsafe(queue)),
+            nothing_lost,
+        )
+        .unwrap_err();
+        assert_eq!(
+            failure,
+            Failure {
+                polls: 1,
+                cancelled: true,
+                message: "saw [2]".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn every_point_is_tried() {
+        let mut cancelled_at = Vec::new();
+        let points = check(
+            || (),
+            |()| {
+                Box::pin(async {
+                    for _ in 0..3 {
+                        yield_now().await;
+                    }
+                    "done"
+                })
+            },
+            |(), outcome| {
+                cancelled_at.push(outcome);
+                Ok(())
+            },
+        );
+        assert_eq!(points, Ok(3));
+        assert_eq!(
+            cancelled_at,
+            [
+                Outcome::Cancelled { polls: 0 },
+                Outcome::Cancelled { polls: 1 },
+                Outcome::Cancelled { polls: 2 },
+                Outcome::Cancelled { polls: 3 },
+                Outcome::Completed("done"),
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_max_polls() {
+        let mut runs = 0;
+        let points = Checker::new().max_polls(5).check(
+            || (),
+            |()| {
+                Box::pin(async {
+                    loop {
+                        yield_now().await;
+                    }
+                })
+            },
+            |(), _: Outcome<()>| {
+                runs += 1;
+                Ok(())
+            },
+        );
+        assert_eq!(points, Ok(5));
+        assert_eq!(runs, 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "the future returned `Pending` from poll 1 and wasn't woken")]
+    fn lost_wakeup_panics() {
+        let _ = Checker::new().wake_timeout(Duration::from_millis(10)).check(
+            || (),
+            |()| Box::pin(future::pending::<()>()),
+            |_, _| Ok(()),
+        );
+    }
+
+    #[test]
+    fn yield_between_items() {
+        let mut stream = yield_between(futures::stream::iter([1, 2]));
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        assert!(stream.poll_next_unpin(&mut cx).is_pending());
+        assert_eq!(stream.poll_next_unpin(&mut cx), Poll::Ready(Some(1)));
+        assert!(stream.poll_next_unpin(&mut cx).is_pending());
+        assert_eq!(block_on(stream.collect::<Vec<_>>()), [2]);
+    }
+}
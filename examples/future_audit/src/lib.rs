@@ -0,0 +1,331 @@
+//! Test helpers for checking the size and `Send`-ness of futures.
+//!
+//! Whether a future is `Send`, and how big it is, depends on which variables
+//! are held across each `.await` (see the `Send` Approximation chapter). Both
+//! can change by accident when the body of an `async fn` is edited, so the
+//! example crates check their futures in tests:
+//!
+//! * `assert_send!` and `assert_not_send!` check `Send`-ness at compile time.
+//! * `assert_size_le!` checks that a future is no bigger than a limit.
+//! * `Report` and `audit!` collect the size and `Send`-ness of a list of
+//!   futures, and print them as a table. Tests can then compare the sizes
+//!   with each other, and check that every future is `Send` with
+//!   `Report::assert_all_send`.
+
+use std::fmt;
+
+/// Fails to compile unless the future (or any other value) is `Send`.
+///
+/// The expression is evaluated, so the future is created and then dropped
+/// without being polled.
+#[macro_export]
+macro_rules! assert_send {
+    ($fut:expr $(,)?) => {{
+        fn assert_send<T: ::core::marker::Send>(_: &T) {}
+        assert_send(&$fut);
+    }};
+}
+
+/// Fails to compile if the future (or any other value) is `Send`.
+#[macro_export]
+macro_rules! assert_not_send {
+    ($fut:expr $(,)?) => {{
+        // If `T: Send`, both impls apply, so the type of `_` can't be
+        // inferred and compilation fails.
+        trait AmbiguousIfSend<A> {
+            fn check(&self) {}
+        }
+        impl<T: ?Sized> AmbiguousIfSend<()> for T {}
+        impl<T: ?Sized + ::core::marker::Send> AmbiguousIfSend<u8> for T {}
+        <_ as AmbiguousIfSend<_>>::check(&$fut);
+    }};
+}
+
+/// Panics if `size_of_val(&fut)` is more than `max` bytes.
+#[macro_export]
+macro_rules! assert_size_le {
+    ($fut:expr, $max:expr $(,)?) => {{
+        let size = ::core::mem::size_of_val(&$fut);
+        let max: usize = $max;
+        assert!(
+            size <= max,
+            "`{}` is {} bytes, which is more than the limit of {} bytes",
+            stringify!($fut),
+            size,
+            max,
+        );
+    }};
+}
+
+/// Evaluates to `true` if the value is `Send`.
+///
+/// This only works where the type of the value is known, not inside a
+/// generic function, since it relies on method resolution picking the
+/// `Send` impl when it applies.
+#[macro_export]
+macro_rules! is_send {
+    ($value:expr $(,)?) => {{
+        #[allow(unused_imports)]
+        use $crate::__private::{IsSend, IsNotSend};
+        (&$crate::__private::Probe(&$value)).is_send()
+    }};
+}
+
+/// Records the size and `Send`-ness of a future in a `Report`, under the
+/// name of the expression which created it.
+///
+/// If a maximum size is given, also panics like `assert_size_le!` if the
+/// future is bigger than that.
+#[macro_export]
+macro_rules! audit {
+    ($report:expr, $fut:expr $(,)?) => {{
+        let fut = $fut;
+        $report.add(
+            module_path!(),
+            stringify!($fut),
+            ::core::mem::size_of_val(&fut),
+            $crate::is_send!(fut),
+        );
+    }};
+    ($report:expr, $fut:expr, $max:expr $(,)?) => {{
+        let fut = $fut;
+        let size = ::core::mem::size_of_val(&fut);
+        $report.add(module_path!(), stringify!($fut), size, $crate::is_send!(fut));
+        let max: usize = $max;
+        assert!(
+            size <= max,
+            "`{}` is {} bytes, which is more than the limit of {} bytes",
+            stringify!($fut),
+            size,
+            max,
+        );
+    }};
+}
+
+#[doc(hidden)]
+pub mod __private {
+    pub struct Probe<'a, T>(pub &'a T);
+
+    pub trait IsSend {
+        fn is_send(&self) -> bool {
+            true
+        }
+    }
+
+    impl<T: Send> IsSend for Probe<'_, T> {}
+
+    // Only found through auto-ref, once the `IsSend` impl has been ruled out.
+    pub trait IsNotSend {
+        fn is_send(&self) -> bool {
+            false
+        }
+    }
+
+    impl<T> IsNotSend for &Probe<'_, T> {}
+}
+
+/// The size and `Send`-ness of a list of futures.
+pub struct Report {
+    title: String,
+    entries: Vec<Entry>,
+}
+
+pub struct Entry {
+    /// The module in which the future was created.
+    pub module: &'static str,
+    /// The expression which created the future.
+    pub name: String,
+    pub size: usize,
+    pub send: bool,
+}
+
+impl Report {
+    pub fn new(title: impl Into<String>) -> Self {
+        Report {
+            title: title.into(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Use `audit!` rather than calling this directly.
+    pub fn add(&mut self, module: &'static str, name: impl Into<String>, size: usize, send: bool) {
+        self.entries.push(Entry {
+            module,
+            name: name.into(),
+            size,
+            send,
+        });
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Returns the first entry for a future created by `name`, which may
+    /// include the module, like `join::get_book_and_music()`.
+    pub fn get(&self, name: &str) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|e| e.name == name || e.path() == name)
+    }
+
+    /// Returns the size of the first future created by `name`, as `get`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there's no such future in the report.
+    pub fn size(&self, name: &str) -> usize {
+        match self.get(name) {
+            Some(entry) => entry.size,
+            None => panic!("no future called `{name}` in {}", self.title),
+        }
+    }
+
+    /// Panics, listing the futures which aren't `Send`, if there are any.
+    pub fn assert_all_send(&self) {
+        let not_send: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|e| !e.send)
+            .map(Entry::path)
+            .collect();
+        assert!(
+            not_send.is_empty(),
+            "these futures in {} should be `Send`: {}",
+            self.title,
+            not_send.join(", "),
+        );
+    }
+}
+
+impl Entry {
+    /// The path of the future within its crate, like `join::get_book_and_music()`.
+    pub fn path(&self) -> String {
+        match self.module.split_once("::") {
+            Some((_crate, module)) => format!("{module}::{}", self.name),
+            None => self.name.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let paths: Vec<_> = self.entries.iter().map(Entry::path).collect();
+        let width = paths
+            .iter()
+            .map(|p| p.len())
+            .chain(["future".len()])
+            .max()
+            .unwrap();
+        writeln!(f, "{}", self.title)?;
+        writeln!(f, "{:width$}  {:>5}  send", "future", "bytes")?;
+        for (path, entry) in paths.iter().zip(&self.entries) {
+            let send = if entry.send { "yes" } else { "no" };
+            writeln!(f, "{path:width$}  {:>5}  {send}", entry.size)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct NotSend(#[allow(dead_code)] Rc<()>);
+
+    async fn bar() {}
+
+    // From the `Send` Approximation chapter.
+    async fn temporary() {
+        NotSend::default();
+        bar().await;
+    }
+
+    async fn held_across_await() {
+        let _x = NotSend::default();
+        bar().await;
+    }
+
+    async fn scoped() {
+        {
+            let _x = NotSend::default();
+        }
+        bar().await;
+    }
+
+    #[test]
+    fn send_approximation() {
+        assert_send!(temporary());
+        assert_not_send!(held_across_await());
+        assert_send!(scoped());
+
+        assert!(is_send!(temporary()));
+        assert!(!is_send!(held_across_await()));
+    }
+
+    #[test]
+    fn size_le() {
+        assert_size_le!(bar(), 1);
+        assert_size_le!([0u8; 16], 16);
+    }
+
+    #[test]
+    #[should_panic(expected = "`[0u64; 4]` is 32 bytes, which is more than the limit of 16 bytes")]
+    fn size_le_too_big() {
+        assert_size_le!([0u64; 4], 16);
+    }
+
+    #[test]
+    fn report() {
+        let mut report = Report::new("send approximation");
+        audit!(report, temporary());
+        let size = std::mem::size_of_val(&held_across_await());
+        audit!(report, held_across_await(), size);
+
+        let entry = report.get("held_across_await()").unwrap();
+        assert!(!entry.send);
+        assert_eq!(entry.size, size);
+        assert!(report.get("temporary()").unwrap().send);
+
+        let table = report.to_string();
+        let lines: Vec<_> = table.lines().collect();
+        assert_eq!(lines[0], "send approximation");
+        assert!(lines[2].starts_with("tests::temporary()          "));
+        assert!(lines[3].ends_with("  no"));
+    }
+
+    #[test]
+    fn sizes_by_name_or_path() {
+        let mut report = Report::new("sizes");
+        audit!(report, bar());
+        audit!(report, scoped());
+        assert_eq!(report.size("bar()"), std::mem::size_of_val(&bar()));
+        assert_eq!(report.size("tests::scoped()"), std::mem::size_of_val(&scoped()));
+        report.assert_all_send();
+    }
+
+    #[test]
+    #[should_panic(expected = "these futures in not send should be `Send`: tests::held_across_await()")]
+    fn assert_all_send_lists_the_futures_which_are_not() {
+        let mut report = Report::new("not send");
+        audit!(report, temporary());
+        audit!(report, held_across_await());
+        report.assert_all_send();
+    }
+
+    #[test]
+    fn report_too_big() {
+        let size = std::mem::size_of_val(&held_across_await());
+        let panic = std::panic::catch_unwind(|| {
+            let mut report = Report::new("send approximation");
+            audit!(report, held_across_await(), 1);
+        })
+        .unwrap_err();
+        assert_eq!(
+            panic.downcast_ref::<String>().unwrap(),
+            &format!("`held_across_await()` is {size} bytes, which is more than the limit of 1 bytes"),
+        );
+    }
+}
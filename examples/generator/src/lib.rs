@@ -0,0 +1,306 @@
+//! Writing streams as async blocks, instead of implementing `poll_next` by
+//! hand.
+//!
+//! Rust doesn't have async generators yet (see the "Future work" section of
+//! the streams chapter), but we can get close with an async block which is
+//! given a `Sender`, and sends each item instead of yielding it:
+//!
+//! ```
+//! use futures::{executor::block_on, StreamExt};
+//!
+//! let evens = example_generator::from_generator(|mut tx| async move {
+//!     for i in 0..5 {
+//!         tx.send(i * 2).await;
+//!     }
+//! });
+//! assert_eq!(block_on(evens.collect::<Vec<_>>()), [0, 2, 4, 6, 8]);
+//! ```
+//!
+//! If the block returns a `Result`, the stream is a `TryStream`, and `?`
+//! ends it with an error:
+//!
+//! ```
+//! use futures::{executor::block_on, TryStreamExt};
+//!
+//! let numbers = example_generator::from_generator(|mut tx| async move {
+//!     for s in ["1", "2", "three"] {
+//!         tx.send(s.parse::<i32>()?).await;
+//!     }
+//!     Ok::<_, std::num::ParseIntError>(())
+//! });
+//! let numbers: Result<Vec<_>, _> = block_on(numbers.try_collect());
+//! assert!(numbers.is_err());
+//! ```
+//!
+//! There is no channel buffer: `send` puts the item in a slot shared with
+//! the stream, and then returns `Pending`, which makes the stream's
+//! `poll_next` return the item. The block only carries on when the stream is
+//! polled for the next item.
+
+use futures::stream::{FusedStream, Stream};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+/// Create a stream of the items sent by the future returned from `body`.
+///
+/// The future can return `()`, or `Result<(), E>` for a stream of
+/// `Result<T, E>`; see `Finish`.
+pub fn from_generator<T, F, Fut>(body: F) -> Generator<T, Fut>
+where
+    F: FnOnce(Sender<T>) -> Fut,
+    Fut: Future,
+    Fut::Output: Finish<T>,
+{
+    let slot = Arc::new(Mutex::new(None));
+    let sender = Sender { slot: slot.clone() };
+    Generator {
+        slot,
+        body: Some(body(sender)),
+        last: None,
+    }
+}
+
+/// What the future of a generator can return.
+pub trait Finish<T> {
+    /// The type of the stream's items.
+    type Item;
+
+    /// Turn a sent value into an item.
+    fn item(value: T) -> Self::Item;
+
+    /// The last item of the stream, if there is one.
+    fn finish(self) -> Option<Self::Item>;
+}
+
+/// A plain stream of the items which were sent.
+impl<T> Finish<T> for () {
+    type Item = T;
+
+    fn item(value: T) -> T {
+        value
+    }
+
+    fn finish(self) -> Option<T> {
+        None
+    }
+}
+
+/// A stream of `Ok(item)`s, ending with `Err(e)` if the future returns one.
+impl<T, E> Finish<T> for Result<(), E> {
+    type Item = Result<T, E>;
+
+    fn item(value: T) -> Result<T, E> {
+        Ok(value)
+    }
+
+    fn finish(self) -> Option<Result<T, E>> {
+        self.err().map(Err)
+    }
+}
+
+/// Sends items from a generator's future to its stream.
+///
+/// It only works inside that future: sending from another task would never
+/// wake the stream.
+pub struct Sender<T> {
+    slot: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Make `value` the next item of the stream, and wait until the stream
+    /// is polled again.
+    pub fn send(&mut self, value: T) -> Send<'_, T> {
+        Send {
+            sender: self,
+            value: Some(value),
+        }
+    }
+}
+
+/// The future returned by `Sender::send`.
+pub struct Send<'a, T> {
+    sender: &'a mut Sender<T>,
+    /// `None` once the value is in the slot.
+    value: Option<T>,
+}
+
+// We never pin the value.
+impl<T> Unpin for Send<'_, T> {}
+
+impl<T> Future for Send<'_, T> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        let this = &mut *self;
+        let mut slot = this.sender.slot.lock().unwrap();
+        if slot.is_some() {
+            // The stream hasn't taken the last value yet. It will poll us
+            // again once it has, so we don't need to wake it.
+            return Poll::Pending;
+        }
+        match this.value.take() {
+            Some(value) => {
+                *slot = Some(value);
+                // Returning `Pending` without waking anybody is usually a
+                // bug, but here it makes the stream yield the value.
+                Poll::Pending
+            }
+            None => Poll::Ready(()),
+        }
+    }
+}
+
+/// The stream returned by `from_generator`.
+pub struct Generator<T, Fut: Future<Output: Finish<T>>> {
+    slot: Arc<Mutex<Option<T>>>,
+    /// `None` once the future has finished.
+    body: Option<Fut>,
+    /// The item from `Finish::finish`, which comes after everything sent.
+    last: Option<<Fut::Output as Finish<T>>::Item>,
+}
+
+impl<T, Fut> Stream for Generator<T, Fut>
+where
+    Fut: Future<Output: Finish<T>>,
+{
+    type Item = <Fut::Output as Finish<T>>::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // SAFETY: `body` is structurally pinned: it is never moved out of
+        // `self` (only dropped in place by `Pin::set`), and we don't
+        // implement `Drop`. Nothing else is pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut body = unsafe { Pin::new_unchecked(&mut this.body) };
+        if let Some(fut) = body.as_mut().as_pin_mut() {
+            if let Poll::Ready(output) = fut.poll(cx) {
+                body.set(None);
+                this.last = output.finish();
+            }
+        }
+        if let Some(value) = this.slot.lock().unwrap().take() {
+            return Poll::Ready(Some(<Fut::Output as Finish<T>>::item(value)));
+        }
+        if this.body.is_some() {
+            Poll::Pending
+        } else {
+            Poll::Ready(this.last.take())
+        }
+    }
+}
+
+impl<T, Fut> FusedStream for Generator<T, Fut>
+where
+    Fut: Future<Output: Finish<T>>,
+{
+    fn is_terminated(&self) -> bool {
+        self.body.is_none() && self.last.is_none() && self.slot.lock().unwrap().is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, future, select, StreamExt};
+    use std::{io, pin::pin, time::Duration};
+    use timer_future::TimerFuture;
+
+    #[test]
+    fn yields_items_in_order() {
+        let stream = from_generator(|mut tx| async move {
+            tx.send("a").await;
+            tx.send("b").await;
+        });
+        assert_eq!(block_on(stream.collect::<Vec<_>>()), ["a", "b"]);
+    }
+
+    #[test]
+    fn body_runs_lazily() {
+        let steps = Mutex::new(Vec::new());
+        let mut stream = pin!(from_generator(|mut tx| {
+            let steps = &steps;
+            async move {
+                for i in 0..3 {
+                    steps.lock().unwrap().push(i);
+                    tx.send(i).await;
+                }
+            }
+        }));
+        assert!(steps.lock().unwrap().is_empty());
+        assert_eq!(block_on(stream.next()), Some(0));
+        // The body is suspended in `send` until the next item is wanted.
+        assert_eq!(*steps.lock().unwrap(), [0]);
+        assert_eq!(block_on(stream.next()), Some(1));
+        assert_eq!(*steps.lock().unwrap(), [0, 1]);
+    }
+
+    #[test]
+    fn body_can_await_other_futures() {
+        let stream = from_generator(|mut tx| async move {
+            for i in 0..3 {
+                TimerFuture::new(Duration::from_millis(1)).await;
+                tx.send(i).await;
+            }
+        });
+        assert_eq!(block_on(stream.collect::<Vec<_>>()), [0, 1, 2]);
+    }
+
+    #[test]
+    fn question_mark_ends_the_stream() {
+        let mut stream = pin!(from_generator(|mut tx| async move {
+            tx.send(1).await;
+            Err(io::Error::other("oops"))?;
+            tx.send(2).await;
+            Ok::<_, io::Error>(())
+        }));
+        assert_eq!(block_on(stream.next()).unwrap().unwrap(), 1);
+        assert_eq!(block_on(stream.next()).unwrap().unwrap_err().to_string(), "oops");
+        assert!(block_on(stream.next()).is_none());
+        assert!(stream.is_terminated());
+    }
+
+    #[test]
+    fn ok_stream_ends_without_error() {
+        let stream = from_generator(|mut tx| async move {
+            tx.send(1).await;
+            Ok::<_, io::Error>(())
+        });
+        let items: Vec<_> = block_on(stream.map(Result::unwrap).collect());
+        assert_eq!(items, [1]);
+    }
+
+    #[test]
+    fn works_in_select() {
+        let mut numbers = pin!(from_generator(|mut tx| async move {
+            for i in 0..3 {
+                tx.send(i).await;
+            }
+        }));
+        let mut never = future::pending::<()>();
+        let mut seen = Vec::new();
+        block_on(async {
+            loop {
+                select! {
+                    n = numbers.next() => match n {
+                        Some(n) => seen.push(n),
+                        None => break,
+                    },
+                    () = never => unreachable!(),
+                }
+            }
+        });
+        assert_eq!(seen, [0, 1, 2]);
+    }
+
+    #[test]
+    fn generator_is_send() {
+        fn assert_send<T: std::marker::Send>(_: &T) {}
+        let stream = from_generator(|mut tx| async move { tx.send(1).await });
+        assert_send(&stream);
+        let handle = std::thread::spawn(move || block_on(stream.collect::<Vec<_>>()));
+        assert_eq!(handle.join().unwrap(), [1]);
+    }
+}
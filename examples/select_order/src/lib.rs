@@ -0,0 +1,269 @@
+//! A `select` with a reproducible branch order.
+//!
+//! When more than one branch of `futures::select!` is ready, it picks one at
+//! random, and there's no way to replay the same choices. That's usually
+//! what you want, since it stops one branch from starving the others, but it
+//! makes tests which depend on the order flaky. A `Selector` picks in one of
+//! two ways instead:
+//!
+//! * `Selector::biased()` polls the branches in the order they're given,
+//!   like `futures::select_biased!`.
+//! * `Selector::seeded(seed)` polls them in a shuffled order, which is
+//!   different each time but always the same sequence for the same seed.
+//!   `Selector::random()` picks a seed, which `seed` returns so that a
+//!   failing run can be repeated.
+//!
+//! Unlike `select!`, the branches all have the same output type, and the
+//! result says which one completed:
+//!
+//! ```
+//! use futures::{executor::block_on, future};
+//! use example_select_order::{Selected, Selector};
+//!
+//! let mut selector = Selector::biased();
+//! let mut a = future::ready(1);
+//! let mut b = future::ready(2);
+//! let mut seen = Vec::new();
+//! block_on(async {
+//!     loop {
+//!         match selector.select(&mut [&mut a, &mut b]).await {
+//!             Selected::Branch(i, value) => seen.push((i, value)),
+//!             Selected::Complete => break,
+//!         }
+//!     }
+//! });
+//! assert_eq!(seen, [(0, 1), (1, 2)]);
+//! ```
+//!
+//! Branches which have terminated are skipped, and once all of them have,
+//! the result is `Selected::Complete`, like the `complete` branch of
+//! `select!`. For a `default` branch, use `FutureExt::now_or_never`, which
+//! gives `None` if no branch is ready.
+
+use futures::future::FusedFuture;
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::BuildHasher,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A branch of a `select`: a fused future which can be polled in place.
+pub type Branch<'a, T> = &'a mut (dyn FusedFuture<Output = T> + Unpin);
+
+/// The result of a `select`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selected<T> {
+    /// The branch at this index completed with this value.
+    Branch(usize, T),
+    /// Every branch had already terminated.
+    Complete,
+}
+
+/// Chooses the order in which `select` polls its branches.
+#[derive(Debug, Clone)]
+pub struct Selector {
+    /// `None` for declaration order.
+    rng: Option<SplitMix64>,
+    seed: Option<u64>,
+}
+
+impl Selector {
+    /// Poll the branches in the order they're given.
+    pub fn biased() -> Self {
+        Selector {
+            rng: None,
+            seed: None,
+        }
+    }
+
+    /// Poll the branches in an order drawn from a generator seeded with
+    /// `seed`.
+    pub fn seeded(seed: u64) -> Self {
+        Selector {
+            rng: Some(SplitMix64(seed)),
+            seed: Some(seed),
+        }
+    }
+
+    /// Like `seeded`, with a seed which is different each time.
+    pub fn random() -> Self {
+        Selector::seeded(RandomState::new().hash_one(0))
+    }
+
+    /// The seed this selector was created with, or `None` if it's biased.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// Wait for the first of `branches` to complete, polling them in this
+    /// selector's order.
+    pub fn select<'a, 'b, T>(&'a mut self, branches: &'a mut [Branch<'b, T>]) -> Select<'a, 'b, T> {
+        Select {
+            selector: self,
+            branches,
+        }
+    }
+
+    /// Poll `branches` once each, in this selector's order, until one is
+    /// ready.
+    pub fn poll_select<T>(
+        &mut self,
+        branches: &mut [Branch<'_, T>],
+        cx: &mut Context<'_>,
+    ) -> Poll<Selected<T>> {
+        let mut order: Vec<usize> = (0..branches.len()).collect();
+        if let Some(rng) = &mut self.rng {
+            rng.shuffle(&mut order);
+        }
+        let mut any_left = false;
+        for i in order {
+            let branch = &mut branches[i];
+            if branch.is_terminated() {
+                continue;
+            }
+            any_left = true;
+            if let Poll::Ready(value) = Pin::new(&mut **branch).poll(cx) {
+                return Poll::Ready(Selected::Branch(i, value));
+            }
+        }
+        if any_left {
+            Poll::Pending
+        } else {
+            Poll::Ready(Selected::Complete)
+        }
+    }
+}
+
+/// The future returned by `Selector::select`.
+pub struct Select<'a, 'b, T> {
+    selector: &'a mut Selector,
+    branches: &'a mut [Branch<'b, T>],
+}
+
+impl<T> Future for Select<'_, '_, T> {
+    type Output = Selected<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Selected<T>> {
+        let this = self.get_mut();
+        this.selector.poll_select(this.branches, cx)
+    }
+}
+
+/// A small, fast random number generator. Its output is only used to
+/// shuffle branches, so it doesn't need to be any good.
+///
+/// See <https://prng.di.unimi.it/splitmix64.c>.
+#[derive(Debug, Clone)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Shuffle `items` in place, with a Fisher-Yates shuffle.
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            // The modulo bias is negligible for a handful of branches.
+            let j = (self.next_u64() % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        channel::oneshot,
+        executor::block_on,
+        future::{self, FutureExt},
+    };
+
+    /// Select from three ready futures until they have all completed, and
+    /// return the order in which they did.
+    fn order(selector: &mut Selector) -> Vec<usize> {
+        let (mut a, mut b, mut c) = (future::ready(0), future::ready(1), future::ready(2));
+        let mut branches: [Branch<'_, usize>; 3] = [&mut a, &mut b, &mut c];
+        let mut order = Vec::new();
+        block_on(async {
+            while let Selected::Branch(i, value) = selector.select(&mut branches).await {
+                assert_eq!(i, value);
+                order.push(i);
+            }
+        });
+        order
+    }
+
+    #[test]
+    fn biased_takes_branches_in_order() {
+        assert_eq!(order(&mut Selector::biased()), [0, 1, 2]);
+        assert_eq!(Selector::biased().seed(), None);
+    }
+
+    #[test]
+    fn seeded_is_reproducible() {
+        let orders: Vec<_> = (0..20).map(|seed| order(&mut Selector::seeded(seed))).collect();
+        for (seed, order) in orders.iter().enumerate() {
+            assert_eq!(*order, self::order(&mut Selector::seeded(seed as u64)));
+        }
+        // Different seeds give different orders.
+        assert!(orders.iter().any(|order| *order != orders[0]));
+    }
+
+    #[test]
+    fn seeded_order_changes_between_selects() {
+        // One selector used for several loops carries on drawing from the
+        // same sequence.
+        let mut selector = Selector::seeded(7);
+        let orders: Vec<_> = (0..20).map(|_| order(&mut selector)).collect();
+        assert!(orders.iter().any(|order| *order != orders[0]));
+
+        let mut again = Selector::seeded(7);
+        let replayed: Vec<_> = (0..20).map(|_| order(&mut again)).collect();
+        assert_eq!(orders, replayed);
+    }
+
+    #[test]
+    fn random_reports_its_seed() {
+        let mut selector = Selector::random();
+        let seed = selector.seed().unwrap();
+        assert_eq!(order(&mut selector), order(&mut Selector::seeded(seed)));
+    }
+
+    #[test]
+    fn seeded_is_roughly_fair() {
+        let mut selector = Selector::seeded(1);
+        let mut first = [0; 3];
+        for _ in 0..3000 {
+            first[order(&mut selector)[0]] += 1;
+        }
+        assert!(first.iter().all(|&n| (800..1200).contains(&n)), "{:?}", first);
+    }
+
+    #[test]
+    fn waits_for_a_pending_branch() {
+        let (tx, rx) = oneshot::channel();
+        let mut rx = rx.map(Result::unwrap);
+        let mut never = future::pending().fuse();
+        let mut selector = Selector::seeded(0);
+        let mut branches: [Branch<'_, i32>; 2] = [&mut never, &mut rx];
+        let mut select = selector.select(&mut branches);
+        assert_eq!((&mut select).now_or_never(), None);
+        tx.send(5).unwrap();
+        assert_eq!(block_on(select), Selected::Branch(1, 5));
+    }
+
+    #[test]
+    fn complete_when_no_branches() {
+        let mut selector = Selector::biased();
+        let selected = block_on(selector.select::<()>(&mut []));
+        assert_eq!(selected, Selected::Complete);
+    }
+}
@@ -0,0 +1,28 @@
+//! Async synchronization primitives, for the "Channels, locking, and
+//! synchronization" chapter.
+//!
+//! These are simple, runtime-independent versions of the primitives in
+//! `tokio::sync` and `futures::channel`:
+//!
+//! * `oneshot`: a channel for sending a single value.
+//! * `Mutex`: a fair async mutex, whose guard can be held across an `.await`.
+//! * `Semaphore`: a fair counting semaphore, with `acquire_many`.
+//! * `Notify`: wakes waiting tasks.
+//!
+//! Every future which waits is cancellation-safe: if it is dropped while
+//! waiting, it leaves the queue of waiters, and if it had already been given
+//! the lock, permits or notification it was waiting for, it passes them on.
+
+pub mod oneshot;
+
+mod mutex;
+mod notify;
+mod semaphore;
+mod waiters;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::{Notified, Notify};
+pub use semaphore::{Acquire, Permit, Semaphore};
+
+#[cfg(test)]
+mod test_util;
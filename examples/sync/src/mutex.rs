@@ -0,0 +1,221 @@
+//! An async mutex.
+
+use std::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+use crate::semaphore::Semaphore;
+
+/// A fair async mutex.
+///
+/// Unlike `std::sync::Mutex`, waiting for the lock doesn't block the thread,
+/// and the guard can be held across an `.await`. Tasks get the lock in the
+/// order they asked for it. It is a `Semaphore` with one permit.
+pub struct Mutex<T: ?Sized> {
+    semaphore: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: the semaphore makes sure only one task at a time can access `data`,
+// so, like `std::sync::Mutex`, we only need `T: Send`.
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            semaphore: Semaphore::new(1),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Wait for the lock.
+    ///
+    /// If the future is dropped while waiting, it gives up its place in the
+    /// queue, or, if it had already been given the lock, passes it on.
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        // The permit is returned by `MutexGuard::drop` instead.
+        self.semaphore.acquire().await.forget();
+        MutexGuard {
+            mutex: self,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Take the lock if it is free and nobody is waiting for it.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.semaphore.try_acquire()?.forget();
+        Some(MutexGuard {
+            mutex: self,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Access the data without locking, since we have the only reference.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mutex").finish_non_exhaustive()
+    }
+}
+
+/// Access to the data in a `Mutex`. The lock is released when this is
+/// dropped.
+#[must_use]
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    // Makes the guard `Send` only if `T: Send`, and `Sync` only if `T: Sync`,
+    // like `&mut T`.
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: we hold the lock.
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: we hold the lock.
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.semaphore.add_permits(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{poll_once, yield_now, CountingWaker};
+    use futures::{executor::block_on, future::join_all};
+    use std::{pin::pin, sync::Arc, task::Poll};
+
+    #[test]
+    fn lock_across_await() {
+        let mutex = Mutex::new(Vec::new());
+        let mutex_ref = &mutex;
+        block_on(join_all((0..3).map(|i| async move {
+            let mut guard = mutex_ref.lock().await;
+            guard.push(i);
+            // Nobody else can get in while we're suspended.
+            yield_now().await;
+            guard.push(i);
+        })));
+        assert_eq!(mutex.into_inner(), [0, 0, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn waiters_get_the_lock_in_order() {
+        let mutex = Mutex::new(());
+        let guard = mutex.try_lock().unwrap();
+        let order = std::sync::Mutex::new(Vec::new());
+        let lock = |i| {
+            let mutex = &mutex;
+            let order = &order;
+            async move {
+                let _guard = mutex.lock().await;
+                order.lock().unwrap().push(i);
+            }
+        };
+        let (_, waker) = CountingWaker::new();
+        let mut tasks: Vec<_> = (0..5).map(|i| Box::pin(lock(i))).collect();
+        // Poll them in reverse, so they start waiting in the order 4, 3, ...
+        for task in tasks.iter_mut().rev() {
+            assert!(poll_once(task.as_mut(), &waker).is_pending());
+        }
+        drop(guard);
+        block_on(join_all(tasks));
+        assert_eq!(*order.lock().unwrap(), [4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn cancelled_waiter_passes_the_lock_on() {
+        let mutex = Mutex::new(0);
+        let guard = mutex.try_lock().unwrap();
+        let (woken, waker) = CountingWaker::new();
+        let mut first = Box::pin(mutex.lock());
+        let mut second = pin!(mutex.lock());
+        assert!(poll_once(first.as_mut(), &waker).is_pending());
+        assert!(poll_once(second.as_mut(), &waker).is_pending());
+
+        drop(guard);
+        assert_eq!(woken.count(), 1);
+        // `first` has been given the lock, but never takes it.
+        drop(first);
+        assert_eq!(woken.count(), 2);
+        let Poll::Ready(mut guard) = poll_once(second.as_mut(), &waker) else {
+            panic!("expected `second` to get the lock");
+        };
+        *guard += 1;
+    }
+
+    #[test]
+    fn cancelled_waiter_gives_up_its_place() {
+        let mutex = Mutex::new(());
+        let guard = mutex.try_lock().unwrap();
+        let (_, waker) = CountingWaker::new();
+        let mut first = Box::pin(mutex.lock());
+        assert!(poll_once(first.as_mut(), &waker).is_pending());
+        drop(first);
+        drop(guard);
+        assert!(mutex.try_lock().is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn counter_on_tokio() {
+        let counter = Arc::new(Mutex::new(0));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    for _ in 0..100 {
+                        let mut guard = counter.lock().await;
+                        let value = *guard;
+                        tokio::task::yield_now().await;
+                        *guard = value + 1;
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(*counter.lock().await, 800);
+    }
+
+    #[tokio::test]
+    async fn timed_out_lock_on_tokio() {
+        let mutex = Mutex::new(());
+        let guard = mutex.lock().await;
+        let timeout = std::time::Duration::from_millis(10);
+        assert!(tokio::time::timeout(timeout, mutex.lock()).await.is_err());
+        drop(guard);
+        assert!(mutex.try_lock().is_some());
+    }
+}
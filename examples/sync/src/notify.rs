@@ -0,0 +1,321 @@
+//! Notifying waiting tasks.
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll, Waker},
+};
+
+use crate::waiters::{List, Node};
+
+/// Wakes up tasks which are waiting for something to happen.
+///
+/// `notify_one` wakes the task which has been waiting longest, or, if there
+/// are no tasks waiting, stores a permit so that the next call to `notified`
+/// completes straight away. `notify_waiters` wakes every waiting task.
+pub struct Notify {
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Set by `notify_one` when no task is waiting.
+    permit: bool,
+    /// Tasks waiting to be notified. Each node holds the notification it has
+    /// received, if any.
+    waiters: List<Option<Notification>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    One,
+    All,
+}
+
+impl State {
+    /// Pick the oldest waiter, returning its waker to be woken once the lock
+    /// has been released.
+    fn notify_one(&mut self) -> Option<Waker> {
+        match self.waiters.pop_front() {
+            Some(waiter) => {
+                waiter.data = Some(Notification::One);
+                waiter.waker.take()
+            }
+            None => {
+                self.permit = true;
+                None
+            }
+        }
+    }
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: Mutex::new(State {
+                permit: false,
+                waiters: List::new(),
+            }),
+        }
+    }
+
+    /// Wait to be notified.
+    ///
+    /// The future only starts waiting when it is first polled, so it won't
+    /// see `notify_waiters` calls from before then.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            node: Node::new(None),
+            state: NotifiedState::Idle,
+        }
+    }
+
+    /// Wake the task which has been waiting longest, or, if none is, let the
+    /// next call to `notified` complete straight away.
+    pub fn notify_one(&self) {
+        let waker = self.state.lock().unwrap().notify_one();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Wake every task which is waiting.
+    pub fn notify_waiters(&self) {
+        let mut to_wake = Vec::new();
+        let mut state = self.state.lock().unwrap();
+        while let Some(waiter) = state.waiters.pop_front() {
+            waiter.data = Some(Notification::All);
+            to_wake.extend(waiter.waker.take());
+        }
+        drop(state);
+        for waker in to_wake {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+impl fmt::Debug for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Notify").finish_non_exhaustive()
+    }
+}
+
+/// The future returned by `Notify::notified`.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    /// Our node in the list of waiters.
+    node: Node<Option<Notification>>,
+    state: NotifiedState,
+}
+
+#[derive(PartialEq, Eq)]
+enum NotifiedState {
+    Idle,
+    Waiting,
+    Done,
+}
+
+// SAFETY: `node` is only accessed with the `Notify`'s lock held.
+unsafe impl Send for Notified<'_> {}
+unsafe impl Sync for Notified<'_> {}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // SAFETY: as for `Acquire`, we never move `node` out of `self`, and
+        // `Drop` removes it from the list.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut state = this.notify.state.lock().unwrap();
+        // SAFETY: we hold the lock.
+        let node = unsafe { this.node.fields() };
+        match this.state {
+            NotifiedState::Idle => {
+                if state.permit {
+                    state.permit = false;
+                    this.state = NotifiedState::Done;
+                    return Poll::Ready(());
+                }
+                node.waker = Some(cx.waker().clone());
+                // SAFETY: we hold the lock, and `node` is pinned and not in a
+                // list. `Drop` removes it.
+                unsafe { state.waiters.push_back(&this.node) };
+                this.state = NotifiedState::Waiting;
+                Poll::Pending
+            }
+            NotifiedState::Waiting if node.data.is_some() => {
+                this.state = NotifiedState::Done;
+                Poll::Ready(())
+            }
+            NotifiedState::Waiting => {
+                match &mut node.waker {
+                    Some(waker) => waker.clone_from(cx.waker()),
+                    None => node.waker = Some(cx.waker().clone()),
+                }
+                Poll::Pending
+            }
+            NotifiedState::Done => panic!("`Notified` polled after completion"),
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if self.state != NotifiedState::Waiting {
+            return;
+        }
+        let mut state = self.notify.state.lock().unwrap();
+        // SAFETY: we hold the lock, and `node` is either in the list or in
+        // none.
+        let notification = unsafe {
+            state.waiters.remove(&self.node);
+            self.node.fields().data
+        };
+        if notification == Some(Notification::One) {
+            // We were picked by `notify_one`, but will never act on it, so
+            // pass it on.
+            let waker = state.notify_one();
+            drop(state);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{poll_once, waker_fn, CountingWaker};
+    use futures::executor::block_on;
+    use std::{
+        pin::pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering::SeqCst},
+            Arc,
+        },
+    };
+
+    #[test]
+    fn notify_one_wakes_oldest_waiter() {
+        let notify = Notify::new();
+        let (first_woken, first_waker) = CountingWaker::new();
+        let (second_woken, second_waker) = CountingWaker::new();
+        let mut first = pin!(notify.notified());
+        let mut second = pin!(notify.notified());
+        assert!(poll_once(first.as_mut(), &first_waker).is_pending());
+        assert!(poll_once(second.as_mut(), &second_waker).is_pending());
+
+        notify.notify_one();
+        assert_eq!((first_woken.count(), second_woken.count()), (1, 0));
+        assert!(poll_once(first.as_mut(), &first_waker).is_ready());
+        assert!(poll_once(second.as_mut(), &second_waker).is_pending());
+    }
+
+    #[test]
+    fn notify_one_stores_a_permit() {
+        let notify = Notify::new();
+        notify.notify_one();
+        notify.notify_one();
+        // Permits don't add up.
+        block_on(notify.notified());
+        let (_, waker) = CountingWaker::new();
+        assert!(poll_once(pin!(notify.notified()), &waker).is_pending());
+    }
+
+    #[test]
+    fn notify_waiters_wakes_everyone() {
+        let notify = Notify::new();
+        let (woken, waker) = CountingWaker::new();
+        let mut waiters: Vec<_> = (0..3).map(|_| Box::pin(notify.notified())).collect();
+        for waiter in &mut waiters {
+            assert!(poll_once(waiter.as_mut(), &waker).is_pending());
+        }
+        notify.notify_waiters();
+        assert_eq!(woken.count(), 3);
+        for waiter in &mut waiters {
+            assert!(poll_once(waiter.as_mut(), &waker).is_ready());
+        }
+        // `notify_waiters` doesn't store a permit.
+        assert!(poll_once(pin!(notify.notified()), &waker).is_pending());
+    }
+
+    #[test]
+    fn cancelled_waiter_passes_notification_on() {
+        let notify = Notify::new();
+        let (woken, waker) = CountingWaker::new();
+        let mut first = Box::pin(notify.notified());
+        let mut second = pin!(notify.notified());
+        assert!(poll_once(first.as_mut(), &waker).is_pending());
+        assert!(poll_once(second.as_mut(), &waker).is_pending());
+
+        notify.notify_one();
+        drop(first);
+        assert_eq!(woken.count(), 2);
+        assert!(poll_once(second.as_mut(), &waker).is_ready());
+    }
+
+    #[test]
+    fn cancelled_waiter_gives_up_its_place() {
+        let notify = Notify::new();
+        let (_, waker) = CountingWaker::new();
+        let mut first = Box::pin(notify.notified());
+        assert!(poll_once(first.as_mut(), &waker).is_pending());
+        drop(first);
+        // With nobody waiting, the notification is stored.
+        notify.notify_one();
+        block_on(notify.notified());
+    }
+
+    #[test]
+    fn waiters_are_woken_after_unlocking() {
+        let notify = Arc::new(Notify::new());
+        let woken = Arc::new(AtomicUsize::new(0));
+        // This waker locks the `Notify`, so it would deadlock if it were
+        // woken with the lock held.
+        let waker = waker_fn({
+            let notify = notify.clone();
+            let woken = woken.clone();
+            move || {
+                drop(notify.state.lock().unwrap());
+                woken.fetch_add(1, SeqCst);
+            }
+        });
+
+        let mut first = Box::pin(notify.notified());
+        let mut second = pin!(notify.notified());
+        assert!(poll_once(first.as_mut(), &waker).is_pending());
+        assert!(poll_once(second.as_mut(), &waker).is_pending());
+        notify.notify_one();
+        assert_eq!(woken.load(SeqCst), 1);
+        // `first` passes its notification on to `second`.
+        drop(first);
+        assert_eq!(woken.load(SeqCst), 2);
+        assert!(poll_once(second.as_mut(), &waker).is_ready());
+
+        let mut third = pin!(notify.notified());
+        assert!(poll_once(third.as_mut(), &waker).is_pending());
+        notify.notify_waiters();
+        assert_eq!(woken.load(SeqCst), 3);
+        assert!(poll_once(third.as_mut(), &waker).is_ready());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn wake_a_task_on_tokio() {
+        let notify = Arc::new(Notify::new());
+        let waiter = tokio::spawn({
+            let notify = notify.clone();
+            async move { notify.notified().await }
+        });
+        notify.notify_one();
+        waiter.await.unwrap();
+    }
+}
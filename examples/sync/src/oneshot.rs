@@ -0,0 +1,308 @@
+//! A channel for sending a single value.
+
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+/// Create a oneshot channel.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Mutex::new(State {
+        value: None,
+        sender_dropped: false,
+        receiver_closed: false,
+        receiver_waker: None,
+        sender_waker: None,
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+struct State<T> {
+    value: Option<T>,
+    /// Set when the `Sender` is dropped, whether or not it sent a value.
+    sender_dropped: bool,
+    /// Set when the `Receiver` is closed or dropped.
+    receiver_closed: bool,
+    /// The task waiting for the value.
+    receiver_waker: Option<Waker>,
+    /// The task waiting for the receiver to close.
+    sender_waker: Option<Waker>,
+}
+
+/// The sending half of a oneshot channel.
+pub struct Sender<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Send the value, or return it if the receiver has been closed.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let mut state = self.shared.lock().unwrap();
+        if state.receiver_closed {
+            return Err(value);
+        }
+        state.value = Some(value);
+        // `drop(self)` wakes the receiver.
+        Ok(())
+    }
+
+    /// Whether the receiver has been closed or dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().unwrap().receiver_closed
+    }
+
+    /// Wait for the receiver to be closed or dropped, so that a task can stop
+    /// working on a value nobody wants.
+    pub fn closed(&mut self) -> Closed<'_, T> {
+        Closed { sender: self }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.sender_dropped = true;
+        let waker = state.receiver_waker.take();
+        drop(state);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// The future returned by `Sender::closed`.
+pub struct Closed<'a, T> {
+    sender: &'a mut Sender<T>,
+}
+
+impl<T> Future for Closed<'_, T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.sender.shared.lock().unwrap();
+        if state.receiver_closed {
+            Poll::Ready(())
+        } else {
+            state.sender_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// The receiving half of a oneshot channel. It is a future which resolves
+/// to the value, or to an error if the `Sender` is dropped without sending
+/// one.
+pub struct Receiver<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Receiver<T> {
+    /// Take the value if it has been sent, without waiting.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.shared.lock().unwrap();
+        match state.value.take() {
+            Some(value) => Ok(value),
+            None if state.sender_dropped => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Stop the `Sender` from sending a value. A value which has already been
+    /// sent can still be received.
+    pub fn close(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.receiver_closed = true;
+        let waker = state.sender_waker.take();
+        drop(state);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.shared.lock().unwrap();
+        if let Some(value) = state.value.take() {
+            Poll::Ready(Ok(value))
+        } else if state.sender_dropped {
+            Poll::Ready(Err(RecvError))
+        } else {
+            state.receiver_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// The error returned by a `Receiver` when the `Sender` was dropped without
+/// sending a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the sender was dropped without sending a value")
+    }
+}
+
+impl Error for RecvError {}
+
+/// The error returned by `Receiver::try_recv`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No value has been sent yet.
+    Empty,
+    /// The sender was dropped without sending a value, or the value has
+    /// already been received.
+    Closed,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "no value has been sent yet"),
+            TryRecvError::Closed => write!(f, "the sender has been dropped"),
+        }
+    }
+}
+
+impl Error for TryRecvError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{poll_once, waker_fn, CountingWaker};
+    use futures::executor::block_on;
+    use std::{
+        sync::atomic::{AtomicBool, Ordering::SeqCst},
+        thread,
+    };
+
+    #[test]
+    fn send_and_receive() {
+        let (tx, rx) = channel();
+        let sender = thread::spawn(move || tx.send("hello").unwrap());
+        assert_eq!(block_on(rx), Ok("hello"));
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn receiver_is_woken_by_send() {
+        let (tx, mut rx) = channel();
+        let (woken, waker) = CountingWaker::new();
+        assert!(poll_once(Pin::new(&mut rx), &waker).is_pending());
+        tx.send(1).unwrap();
+        assert_eq!(woken.count(), 1);
+        assert_eq!(poll_once(Pin::new(&mut rx), &waker), Poll::Ready(Ok(1)));
+    }
+
+    #[test]
+    fn sender_dropped_without_sending() {
+        let (tx, mut rx) = channel::<()>();
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        drop(tx);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Closed));
+        assert_eq!(block_on(rx), Err(RecvError));
+    }
+
+    #[test]
+    fn send_to_closed_receiver_fails() {
+        let (tx, mut rx) = channel();
+        rx.close();
+        assert!(tx.is_closed());
+        assert_eq!(tx.send(1), Err(1));
+        assert_eq!(block_on(rx), Err(RecvError));
+
+        let (tx, rx) = channel();
+        drop(rx);
+        assert_eq!(tx.send(1), Err(1));
+    }
+
+    #[test]
+    fn value_sent_before_close_is_received() {
+        let (tx, mut rx) = channel();
+        tx.send(1).unwrap();
+        rx.close();
+        assert_eq!(block_on(rx), Ok(1));
+    }
+
+    #[test]
+    fn closed_wakes_sender() {
+        let (mut tx, rx) = channel::<()>();
+        let (woken, waker) = CountingWaker::new();
+        {
+            let mut closed = tx.closed();
+            assert!(poll_once(Pin::new(&mut closed), &waker).is_pending());
+            drop(rx);
+            assert_eq!(woken.count(), 1);
+            assert!(poll_once(Pin::new(&mut closed), &waker).is_ready());
+        }
+    }
+
+    #[test]
+    fn woken_after_unlocking() {
+        // Each waker locks the channel, so it would deadlock if it were woken
+        // with the lock held.
+        let (tx, rx) = channel::<()>();
+        let rx = Arc::new(Mutex::new(rx));
+        let woken = Arc::new(AtomicBool::new(false));
+        let waker = waker_fn({
+            let (rx, woken) = (rx.clone(), woken.clone());
+            move || woken.store(rx.lock().unwrap().try_recv().is_err(), SeqCst)
+        });
+        assert!(poll_once(Pin::new(&mut *rx.lock().unwrap()), &waker).is_pending());
+        drop(tx);
+        assert!(woken.load(SeqCst));
+
+        let (tx, mut rx) = channel::<()>();
+        let tx = Arc::new(Mutex::new(tx));
+        let woken = Arc::new(AtomicBool::new(false));
+        let waker = waker_fn({
+            let (tx, woken) = (tx.clone(), woken.clone());
+            move || woken.store(tx.lock().unwrap().is_closed(), SeqCst)
+        });
+        assert!(poll_once(Pin::new(&mut tx.lock().unwrap().closed()), &waker).is_pending());
+        rx.close();
+        assert!(woken.load(SeqCst));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn send_between_tokio_tasks() {
+        let (tx, rx) = channel();
+        tokio::spawn(async move {
+            tokio::task::yield_now().await;
+            tx.send(42).unwrap();
+        });
+        assert_eq!(rx.await, Ok(42));
+    }
+
+    #[tokio::test]
+    async fn sender_stops_when_receiver_is_dropped() {
+        let (mut tx, rx) = channel::<u32>();
+        let worker = tokio::spawn(async move {
+            tokio::select! {
+                _ = tx.closed() => "cancelled",
+                _ = tokio::time::sleep(std::time::Duration::from_secs(60)) => "finished",
+            }
+        });
+        drop(rx);
+        assert_eq!(worker.await.unwrap(), "cancelled");
+    }
+}
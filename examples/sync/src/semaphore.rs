@@ -0,0 +1,447 @@
+//! A counting semaphore.
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll, Waker},
+};
+
+use crate::waiters::{List, Node};
+
+/// A fair, counting semaphore.
+///
+/// Tasks acquire permits in the order they started waiting. A task which
+/// needs many permits holds up the tasks behind it, even if there are enough
+/// permits for them, so that it isn't starved by a stream of smaller
+/// requests.
+pub struct Semaphore {
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Permits which haven't been given to anyone.
+    permits: usize,
+    /// Tasks waiting for permits. Each node holds the number of permits its
+    /// task still needs; the front task may have been given some already.
+    /// Whenever there are waiters, `permits` is 0.
+    waiters: List<usize>,
+}
+
+impl State {
+    /// Return permits to the semaphore, handing them to waiting tasks. The
+    /// tasks which now have all their permits are returned, to be woken once
+    /// the lock has been released.
+    fn release(&mut self, permits: usize) -> Vec<Waker> {
+        let mut to_wake = Vec::new();
+        self.permits += permits;
+        while let Some(front) = self.waiters.front() {
+            let given = front.data.min(self.permits);
+            front.data -= given;
+            self.permits -= given;
+            if front.data > 0 {
+                break;
+            }
+            let front = self.waiters.pop_front().unwrap();
+            to_wake.extend(front.waker.take());
+        }
+        to_wake
+    }
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Mutex::new(State {
+                permits,
+                waiters: List::new(),
+            }),
+        }
+    }
+
+    /// The number of permits which are free right now.
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().unwrap().permits
+    }
+
+    /// Add permits to the semaphore.
+    pub fn add_permits(&self, permits: usize) {
+        self.release(permits);
+    }
+
+    fn release(&self, permits: usize) {
+        let to_wake = self.state.lock().unwrap().release(permits);
+        for waker in to_wake {
+            waker.wake();
+        }
+    }
+
+    /// Wait for a permit.
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Wait for `permits` permits, which are acquired all together.
+    ///
+    /// If the future is dropped while waiting, any permits it has been given
+    /// so far are returned to the semaphore.
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            node: Node::new(permits),
+            state: AcquireState::Idle,
+        }
+    }
+
+    /// Acquire a permit if one is free and nobody is waiting, without waiting.
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    /// Acquire `permits` permits if they are free and nobody is waiting,
+    /// without waiting.
+    pub fn try_acquire_many(&self, permits: usize) -> Option<Permit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        if state.waiters.is_empty() && state.permits >= permits {
+            state.permits -= permits;
+            Some(Permit {
+                semaphore: self,
+                permits,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("permits", &self.available_permits())
+            .finish()
+    }
+}
+
+/// Permits acquired from a `Semaphore`, which are returned when this is
+/// dropped.
+#[must_use]
+pub struct Permit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl Permit<'_> {
+    /// The number of permits held.
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+
+    /// Drop the permits without returning them to the semaphore.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.release(self.permits);
+        }
+    }
+}
+
+/// The future returned by `Semaphore::acquire` and
+/// `Semaphore::acquire_many`.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    /// Our node in the semaphore's list of waiters.
+    node: Node<usize>,
+    state: AcquireState,
+}
+
+#[derive(PartialEq, Eq)]
+enum AcquireState {
+    /// Not yet polled.
+    Idle,
+    /// `node` is, or has been, in the list of waiters.
+    Waiting,
+    /// The permits have been returned from `poll`.
+    Done,
+}
+
+// SAFETY: `node` is only accessed with the semaphore's lock held.
+unsafe impl Send for Acquire<'_> {}
+unsafe impl Sync for Acquire<'_> {}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Permit<'a>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Permit<'a>> {
+        // SAFETY: we never move `node` out of `self`. `Drop` removes it from
+        // the list before it goes away.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut state = this.semaphore.state.lock().unwrap();
+        // SAFETY: we hold the lock.
+        let node = unsafe { this.node.fields() };
+        match this.state {
+            AcquireState::Idle => {
+                // If anybody else is waiting, there are no free permits, so we
+                // can't overtake them.
+                let given = state.permits.min(this.permits);
+                state.permits -= given;
+                if given == this.permits {
+                    this.state = AcquireState::Done;
+                    return Poll::Ready(Permit {
+                        semaphore: this.semaphore,
+                        permits: this.permits,
+                    });
+                }
+                node.data = this.permits - given;
+                node.waker = Some(cx.waker().clone());
+                // SAFETY: we hold the lock, and `node` is pinned and not in a
+                // list. `Drop` removes it.
+                unsafe { state.waiters.push_back(&this.node) };
+                this.state = AcquireState::Waiting;
+                Poll::Pending
+            }
+            AcquireState::Waiting if !node.queued => {
+                // `release` has given us all our permits.
+                this.state = AcquireState::Done;
+                Poll::Ready(Permit {
+                    semaphore: this.semaphore,
+                    permits: this.permits,
+                })
+            }
+            AcquireState::Waiting => {
+                match &mut node.waker {
+                    Some(waker) => waker.clone_from(cx.waker()),
+                    None => node.waker = Some(cx.waker().clone()),
+                }
+                Poll::Pending
+            }
+            AcquireState::Done => panic!("`Acquire` polled after completion"),
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if self.state != AcquireState::Waiting {
+            return;
+        }
+        let mut state = self.semaphore.state.lock().unwrap();
+        // SAFETY: we hold the lock, and `node` is either in the semaphore's
+        // list or in none.
+        let given = unsafe {
+            state.waiters.remove(&self.node);
+            self.permits - self.node.fields().data
+        };
+        // Hand back any permits we were given, which may let the next waiter
+        // go ahead.
+        let to_wake = state.release(given);
+        drop(state);
+        for waker in to_wake {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{poll_once, waker_fn, yield_now, CountingWaker};
+    use futures::{executor::block_on, future::join_all};
+    use std::{
+        pin::pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering::SeqCst},
+            Arc,
+        },
+    };
+
+    #[test]
+    fn acquire_and_release() {
+        let semaphore = Semaphore::new(2);
+        block_on(async {
+            let a = semaphore.acquire().await;
+            let b = semaphore.acquire().await;
+            assert_eq!(semaphore.available_permits(), 0);
+            assert!(semaphore.try_acquire().is_none());
+            drop(a);
+            assert_eq!(semaphore.available_permits(), 1);
+            drop(b);
+        });
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+
+    #[test]
+    fn acquire_many_waits_for_enough_permits() {
+        let semaphore = Semaphore::new(3);
+        let one = semaphore.try_acquire().unwrap();
+        let two = semaphore.try_acquire_many(2).unwrap();
+
+        let (woken, waker) = CountingWaker::new();
+        let mut three = pin!(semaphore.acquire_many(3));
+        assert!(poll_once(three.as_mut(), &waker).is_pending());
+        drop(two);
+        assert_eq!(woken.count(), 0);
+        drop(one);
+        assert_eq!(woken.count(), 1);
+        let permit = match poll_once(three.as_mut(), &waker) {
+            Poll::Ready(permit) => permit,
+            Poll::Pending => panic!("expected three permits"),
+        };
+        assert_eq!(permit.permits(), 3);
+        assert_eq!(semaphore.available_permits(), 0);
+    }
+
+    #[test]
+    fn large_requests_are_not_starved() {
+        let semaphore = Semaphore::new(2);
+        let held = semaphore.try_acquire().unwrap();
+        let (_, waker) = CountingWaker::new();
+        let mut big = pin!(semaphore.acquire_many(2));
+        assert!(poll_once(big.as_mut(), &waker).is_pending());
+
+        // There is a free permit, but `big` got in first.
+        let mut small = pin!(semaphore.acquire());
+        assert!(poll_once(small.as_mut(), &waker).is_pending());
+        assert!(semaphore.try_acquire().is_none());
+
+        drop(held);
+        let Poll::Ready(big_permit) = poll_once(big.as_mut(), &waker) else {
+            panic!("expected `big` to get its permits");
+        };
+        assert!(poll_once(small.as_mut(), &waker).is_pending());
+        drop(big_permit);
+        assert!(poll_once(small.as_mut(), &waker).is_ready());
+    }
+
+    #[test]
+    fn dropped_waiter_returns_partial_permits() {
+        let semaphore = Semaphore::new(2);
+        let held = semaphore.try_acquire().unwrap();
+        let (_, waker) = CountingWaker::new();
+        let (next_woken, next_waker) = CountingWaker::new();
+
+        // Given one permit straight away, and waiting for another.
+        let mut big = Box::pin(semaphore.acquire_many(2));
+        assert!(poll_once(big.as_mut(), &waker).is_pending());
+        assert_eq!(semaphore.available_permits(), 0);
+        let mut next = pin!(semaphore.acquire());
+        assert!(poll_once(next.as_mut(), &next_waker).is_pending());
+
+        // Dropping `big` gives its permit to `next`.
+        drop(big);
+        assert_eq!(next_woken.count(), 1);
+        let Poll::Ready(permit) = poll_once(next.as_mut(), &next_waker) else {
+            panic!("expected `next` to get a permit");
+        };
+        drop((held, permit));
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+
+    #[test]
+    fn dropped_after_being_woken() {
+        let semaphore = Semaphore::new(1);
+        let held = semaphore.try_acquire().unwrap();
+        let (woken, waker) = CountingWaker::new();
+        let mut first = Box::pin(semaphore.acquire());
+        let mut second = pin!(semaphore.acquire());
+        assert!(poll_once(first.as_mut(), &waker).is_pending());
+        assert!(poll_once(second.as_mut(), &waker).is_pending());
+
+        // `first` is given the permit, but is dropped without taking it.
+        drop(held);
+        assert_eq!(woken.count(), 1);
+        drop(first);
+        assert_eq!(woken.count(), 2);
+        assert!(poll_once(second.as_mut(), &waker).is_ready());
+    }
+
+    #[test]
+    fn waiters_are_woken_after_unlocking() {
+        let semaphore = Arc::new(Semaphore::new(0));
+        let woken = Arc::new(AtomicUsize::new(0));
+        // This waker locks the semaphore, so it would deadlock if it were
+        // woken with the lock held.
+        let waker = waker_fn({
+            let semaphore = semaphore.clone();
+            let woken = woken.clone();
+            move || {
+                semaphore.available_permits();
+                woken.fetch_add(1, SeqCst);
+            }
+        });
+
+        let mut first = pin!(semaphore.acquire());
+        assert!(poll_once(first.as_mut(), &waker).is_pending());
+        semaphore.add_permits(1);
+        assert_eq!(woken.load(SeqCst), 1);
+        let Poll::Ready(permit) = poll_once(first.as_mut(), &waker) else {
+            panic!("expected `first` to get a permit");
+        };
+
+        let mut big = Box::pin(semaphore.acquire_many(2));
+        let mut next = pin!(semaphore.acquire());
+        assert!(poll_once(big.as_mut(), &waker).is_pending());
+        assert!(poll_once(next.as_mut(), &waker).is_pending());
+        drop(permit);
+        assert_eq!(woken.load(SeqCst), 1);
+        drop(big);
+        assert_eq!(woken.load(SeqCst), 2);
+        assert!(poll_once(next.as_mut(), &waker).is_ready());
+    }
+
+    #[test]
+    fn limits_concurrency_on_futures_executor() {
+        let semaphore = Semaphore::new(3);
+        let running = AtomicUsize::new(0);
+        let max = AtomicUsize::new(0);
+        block_on(join_all((0..10).map(|_| async {
+            let _permit = semaphore.acquire().await;
+            let now = running.fetch_add(1, SeqCst) + 1;
+            max.fetch_max(now, SeqCst);
+            yield_now().await;
+            running.fetch_sub(1, SeqCst);
+        })));
+        assert_eq!(max.load(SeqCst), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn limits_concurrency_on_tokio() {
+        static SEMAPHORE: Semaphore = Semaphore::new(3);
+        static RUNNING: AtomicUsize = AtomicUsize::new(0);
+        let handles: Vec<_> = (0..32)
+            .map(|i| {
+                tokio::spawn(async move {
+                    let _permit = SEMAPHORE.acquire_many(1 + i % 3).await;
+                    let now = RUNNING.fetch_add(1 + i % 3, SeqCst) + 1 + i % 3;
+                    assert!(now <= 3);
+                    tokio::task::yield_now().await;
+                    RUNNING.fetch_sub(1 + i % 3, SeqCst);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(SEMAPHORE.available_permits(), 3);
+    }
+
+    #[tokio::test]
+    async fn timed_out_acquire_on_tokio() {
+        let semaphore = Semaphore::new(1);
+        let held = semaphore.acquire().await;
+        let timeout = std::time::Duration::from_millis(10);
+        assert!(tokio::time::timeout(timeout, semaphore.acquire_many(1))
+            .await
+            .is_err());
+        drop(held);
+        // The cancelled waiter didn't keep the permit.
+        assert!(semaphore.try_acquire().is_some());
+    }
+}
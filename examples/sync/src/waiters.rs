@@ -0,0 +1,148 @@
+//! An intrusive, doubly linked list of waiting tasks.
+//!
+//! "Intrusive" means that the list doesn't own its nodes: each future which
+//! has to wait embeds a `Node` in itself, and links it into the list when it
+//! is first polled. Waiting never allocates, and a waiter can unlink itself
+//! in constant time when it is dropped, which is what makes the futures in
+//! this crate cancellation-safe.
+//!
+//! This only works because the futures are pinned: once a node is in a list,
+//! it must not move until it has been unlinked. Each primitive keeps its list
+//! behind a `std::sync::Mutex`, and every node in the list is only ever
+//! accessed with that lock held.
+
+use std::{cell::UnsafeCell, marker::PhantomPinned, ptr::NonNull, task::Waker};
+
+/// A node which a waiting future embeds in itself.
+pub(crate) struct Node<T> {
+    fields: UnsafeCell<Fields<T>>,
+    // Nodes must not move while they are linked into a list.
+    _pinned: PhantomPinned,
+}
+
+pub(crate) struct Fields<T> {
+    /// The waker of the task waiting on this node.
+    pub waker: Option<Waker>,
+    /// Whatever the primitive needs to know about the waiter.
+    pub data: T,
+    /// Whether the node is linked into a list.
+    pub queued: bool,
+    prev: Option<NonNull<Node<T>>>,
+    next: Option<NonNull<Node<T>>>,
+}
+
+impl<T> Node<T> {
+    pub fn new(data: T) -> Self {
+        Node {
+            fields: UnsafeCell::new(Fields {
+                waker: None,
+                data,
+                queued: false,
+                prev: None,
+                next: None,
+            }),
+            _pinned: PhantomPinned,
+        }
+    }
+
+    /// Access the node's fields.
+    ///
+    /// # Safety
+    ///
+    /// If the node is, or might be, in a list, the caller must hold the lock
+    /// protecting that list, and must not create any other reference to the
+    /// fields while this one is alive.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn fields(&self) -> &mut Fields<T> {
+        &mut *self.fields.get()
+    }
+}
+
+/// A list of waiting tasks, oldest first.
+pub(crate) struct List<T> {
+    head: Option<NonNull<Node<T>>>,
+    tail: Option<NonNull<Node<T>>>,
+}
+
+// SAFETY: the list only points to nodes which are accessed with the list's
+// lock held, and sending the list sends the `T`s and `Waker`s in them.
+unsafe impl<T: Send> Send for List<T> {}
+
+impl<T> List<T> {
+    pub const fn new() -> Self {
+        List {
+            head: None,
+            tail: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    /// Link `node` onto the back of the list.
+    ///
+    /// # Safety
+    ///
+    /// The caller must hold the lock protecting this list, and `node` must
+    /// not be in any list. `node` must be pinned, and must be removed from
+    /// the list (with `remove`, or `pop_front`) before it is dropped.
+    pub unsafe fn push_back(&mut self, node: &Node<T>) {
+        let ptr = NonNull::from(node);
+        let fields = node.fields();
+        debug_assert!(!fields.queued);
+        fields.queued = true;
+        fields.prev = self.tail;
+        fields.next = None;
+        match self.tail {
+            Some(tail) => tail.as_ref().fields().next = Some(ptr),
+            None => self.head = Some(ptr),
+        }
+        self.tail = Some(ptr);
+    }
+
+    /// Unlink `node` from the list, if it is in it. Returns whether it was.
+    ///
+    /// # Safety
+    ///
+    /// The caller must hold the lock protecting this list, and `node` must
+    /// either be in this list or in none.
+    pub unsafe fn remove(&mut self, node: &Node<T>) -> bool {
+        let fields = node.fields();
+        if !fields.queued {
+            return false;
+        }
+        match fields.prev {
+            Some(prev) => prev.as_ref().fields().next = fields.next,
+            None => self.head = fields.next,
+        }
+        match fields.next {
+            Some(next) => next.as_ref().fields().prev = fields.prev,
+            None => self.tail = fields.prev,
+        }
+        fields.queued = false;
+        fields.prev = None;
+        fields.next = None;
+        true
+    }
+
+    /// The fields of the oldest node in the list.
+    pub fn front(&mut self) -> Option<&mut Fields<T>> {
+        // SAFETY: the contract of `push_back` means every node in the list is
+        // alive, and `&mut self` means we hold the list's lock.
+        self.head.map(|head| unsafe { head.as_ref().fields() })
+    }
+
+    /// Unlink the oldest node from the list, and return its fields.
+    pub fn pop_front(&mut self) -> Option<&mut Fields<T>> {
+        let head = self.head?;
+        // SAFETY: as for `front`. The node's owner can't drop it while we hold
+        // the lock, since it has to take the lock to find out whether it's
+        // still in the list.
+        unsafe {
+            let node = head.as_ref();
+            self.remove(node);
+            Some(node.fields())
+        }
+    }
+}
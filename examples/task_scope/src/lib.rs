@@ -0,0 +1,533 @@
+//! Structured concurrency for futures: a scope (or "nursery") whose child
+//! tasks can't outlive it.
+//!
+//! `scope` runs an async block which can spawn child tasks on a `Scope`. The
+//! future it returns doesn't complete until every child has completed, so
+//! the children can borrow from the code which created the scope, just like
+//! the threads of `std::thread::scope`:
+//!
+//! ```
+//! use futures::executor::block_on;
+//! use std::convert::Infallible;
+//!
+//! let mut totals = [0, 0];
+//! let numbers = vec![1, 2, 3, 4];
+//! let ([evens, odds], numbers) = (&mut totals, &numbers);
+//! block_on(example_task_scope::scope(|s| async move {
+//!     s.spawn(async move {
+//!         *evens = numbers.iter().filter(|&n| n % 2 == 0).sum();
+//!         Ok(())
+//!     });
+//!     s.spawn(async move {
+//!         *odds = numbers.iter().filter(|&n| n % 2 == 1).sum();
+//!         Ok(())
+//!     });
+//!     Ok::<_, Infallible>(())
+//! }))
+//! .unwrap();
+//! assert_eq!(totals, [6, 4]);
+//! ```
+//!
+//! The children aren't spawned onto an executor: they are polled by the
+//! scope's future, along with the body, so they run concurrently with each
+//! other but not in parallel. That is what makes borrowing sound without any
+//! `unsafe` code. If the scope's future is dropped, or even leaked with
+//! `mem::forget`, the children go with it, and can never run again.
+//!
+//! Errors and panics go to the parent:
+//!
+//! * If the body or a child returns an error, every other task in the scope
+//!   is cancelled, and the scope returns that error.
+//! * If the body or a child panics, every other task is cancelled, and then
+//!   the panic carries on out of the scope.
+//! * If the scope itself is cancelled by being dropped, its tasks are too.
+//!
+//! Tasks are always cancelled in the same order: children newest first,
+//! like local variables going out of scope, and then the body.
+
+use futures::{
+    channel::oneshot,
+    future::{FutureExt, LocalBoxFuture},
+};
+use std::{
+    cell::RefCell,
+    future::Future,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll, Waker},
+};
+
+/// Run `body` in a new scope, and wait for it and every task it spawns to
+/// complete.
+///
+/// Returns the body's output, or the first error from the body or any task,
+/// in which case the others are cancelled.
+pub fn scope<'env, T, E, F, Fut>(body: F) -> TaskScope<'env, T, E>
+where
+    F: FnOnce(Scope<'env, E>) -> Fut,
+    Fut: Future<Output = Result<T, E>> + 'env,
+{
+    let shared = Rc::new(RefCell::new(Shared {
+        spawned: Vec::new(),
+        finished: false,
+        waker: None,
+    }));
+    let body = body(Scope {
+        shared: shared.clone(),
+    });
+    TaskScope {
+        shared,
+        body: Some(Box::pin(body)),
+        output: None,
+        children: Vec::new(),
+    }
+}
+
+type Task<'env, E> = LocalBoxFuture<'env, Result<(), E>>;
+
+/// State shared between a `TaskScope` and its `Scope` handles.
+struct Shared<'env, E> {
+    /// Tasks which have been spawned since the scope was last polled.
+    spawned: Vec<Task<'env, E>>,
+    /// Set once every task has completed or been cancelled.
+    finished: bool,
+    /// Woken when a task is spawned from outside the scope's own tasks.
+    waker: Option<Waker>,
+}
+
+/// A handle for spawning tasks in a scope. It can be cloned, and passed to
+/// child tasks so that they can spawn siblings.
+pub struct Scope<'env, E> {
+    shared: Rc<RefCell<Shared<'env, E>>>,
+}
+
+impl<E> Clone for Scope<'_, E> {
+    fn clone(&self) -> Self {
+        Scope {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<'env, E: 'env> Scope<'env, E> {
+    /// Spawn `task` as a child of the scope.
+    ///
+    /// The task may borrow anything which outlives the scope. If it returns
+    /// an error, the rest of the scope is cancelled. If the scope has already
+    /// finished, the task is dropped without being run.
+    pub fn spawn<T: 'env>(
+        &self,
+        task: impl Future<Output = Result<T, E>> + 'env,
+    ) -> JoinHandle<T> {
+        let (tx, rx) = oneshot::channel();
+        let child = async move {
+            let value = task.await?;
+            // The handle may have been dropped, which is fine.
+            let _ = tx.send(value);
+            Ok(())
+        };
+        let mut shared = self.shared.borrow_mut();
+        if !shared.finished {
+            shared.spawned.push(Box::pin(child));
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        }
+        JoinHandle { rx }
+    }
+}
+
+/// A future for the output of a spawned task.
+///
+/// Awaiting it isn't necessary: the scope waits for every task anyway.
+pub struct JoinHandle<T> {
+    rx: oneshot::Receiver<T>,
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    /// # Panics
+    ///
+    /// If the task was cancelled. Within the scope this can't happen, since
+    /// a task is only cancelled when the whole scope is.
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        self.rx
+            .poll_unpin(cx)
+            .map(|result| result.expect("awaited a task which was cancelled"))
+    }
+}
+
+/// The future returned by `scope`.
+pub struct TaskScope<'env, T, E> {
+    shared: Rc<RefCell<Shared<'env, E>>>,
+    /// `None` once the body has completed.
+    body: Option<LocalBoxFuture<'env, Result<T, E>>>,
+    output: Option<T>,
+    /// The children which are still running, oldest first.
+    children: Vec<Task<'env, E>>,
+}
+
+// Everything which is polled is boxed, and `output` is never pinned.
+impl<T, E> Unpin for TaskScope<'_, T, E> {}
+
+impl<T, E> Future for TaskScope<'_, T, E> {
+    type Output = Result<T, E>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T, E>> {
+        let this = &mut *self;
+        this.shared.borrow_mut().waker = Some(cx.waker().clone());
+        loop {
+            if let Some(body) = &mut this.body {
+                match panic::catch_unwind(AssertUnwindSafe(|| body.as_mut().poll(cx))) {
+                    Ok(Poll::Ready(Ok(output))) => {
+                        this.body = None;
+                        this.output = Some(output);
+                    }
+                    Ok(Poll::Ready(Err(e))) => {
+                        this.body = None;
+                        this.cancel();
+                        return Poll::Ready(Err(e));
+                    }
+                    Ok(Poll::Pending) => {}
+                    Err(panic) => {
+                        this.body = None;
+                        this.cancel();
+                        panic::resume_unwind(panic);
+                    }
+                }
+            }
+
+            let mut i = 0;
+            while i < this.children.len() {
+                let child = &mut this.children[i];
+                match panic::catch_unwind(AssertUnwindSafe(|| child.as_mut().poll(cx))) {
+                    Ok(Poll::Ready(Ok(()))) => {
+                        drop(this.children.remove(i));
+                    }
+                    Ok(Poll::Ready(Err(e))) => {
+                        drop(this.children.remove(i));
+                        this.cancel();
+                        return Poll::Ready(Err(e));
+                    }
+                    Ok(Poll::Pending) => i += 1,
+                    Err(panic) => {
+                        drop(this.children.remove(i));
+                        this.cancel();
+                        panic::resume_unwind(panic);
+                    }
+                }
+            }
+
+            // Tasks spawned while we were polling haven't been polled yet.
+            let spawned = mem::take(&mut this.shared.borrow_mut().spawned);
+            if !spawned.is_empty() {
+                this.children.extend(spawned);
+                continue;
+            }
+
+            if this.body.is_none() && this.children.is_empty() {
+                this.shared.borrow_mut().finished = true;
+                let output = this.output.take().expect("`TaskScope` polled after completion");
+                return Poll::Ready(Ok(output));
+            }
+            return Poll::Pending;
+        }
+    }
+}
+
+impl<T, E> TaskScope<'_, T, E> {
+    /// Drop every task which is still running: the children newest first,
+    /// and then the body.
+    fn cancel(&mut self) {
+        // A task which is being dropped can't spawn any more.
+        let mut spawned = {
+            let mut shared = self.shared.borrow_mut();
+            shared.finished = true;
+            mem::take(&mut shared.spawned)
+        };
+        // The shared state isn't borrowed while tasks are dropped, since
+        // they may hold `Scope`s.
+        while let Some(task) = spawned.pop() {
+            drop(task);
+        }
+        while let Some(child) = self.children.pop() {
+            drop(child);
+        }
+        self.body = None;
+    }
+}
+
+impl<T, E> Drop for TaskScope<'_, T, E> {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, future, select, FutureExt};
+    use std::{cell::Cell, convert::Infallible, time::Duration};
+    use timer_future::TimerFuture;
+
+    /// The order in which tasks finished or were cancelled.
+    type Log = RefCell<Vec<String>>;
+
+    /// Logs that `name` was cancelled if it's dropped before `done`.
+    struct Guard<'a> {
+        name: &'static str,
+        log: &'a Log,
+        done: bool,
+    }
+
+    impl<'a> Guard<'a> {
+        fn new(name: &'static str, log: &'a Log) -> Self {
+            Guard {
+                name,
+                log,
+                done: false,
+            }
+        }
+
+        fn done(mut self) {
+            self.done = true;
+            self.log.borrow_mut().push(format!("{} done", self.name));
+        }
+    }
+
+    impl Drop for Guard<'_> {
+        fn drop(&mut self) {
+            if !self.done {
+                self.log.borrow_mut().push(format!("{} cancelled", self.name));
+            }
+        }
+    }
+
+    fn sleep_ms(ms: u64) -> TimerFuture {
+        TimerFuture::new(Duration::from_millis(ms))
+    }
+
+    /// A task which sleeps for `ms`, logging whether it finished.
+    async fn sleeper<E>(name: &'static str, ms: u64, log: &Log) -> Result<(), E> {
+        let guard = Guard::new(name, log);
+        sleep_ms(ms).await;
+        guard.done();
+        Ok(())
+    }
+
+    #[test]
+    fn waits_for_every_child() {
+        let log = &Log::default();
+        let output = block_on(scope(|s| async move {
+            s.spawn(sleeper("slow", 30, log));
+            s.spawn(sleeper("fast", 10, log));
+            // The body finishes first, but the scope waits for the
+            // children.
+            Ok::<_, Infallible>("body")
+        }));
+        assert_eq!(output, Ok("body"));
+        assert_eq!(*log.borrow(), ["fast done", "slow done"]);
+    }
+
+    #[test]
+    fn children_borrow_local_data() {
+        let mut counts = [0; 4];
+        let shared = &Cell::new(0);
+        let counts_mut = &mut counts;
+        block_on(scope(|s| async move {
+            for (i, count) in counts_mut.iter_mut().enumerate() {
+                s.spawn(async move {
+                    sleep_ms(1).await;
+                    *count = i * 10;
+                    shared.set(shared.get() + 1);
+                    Ok(())
+                });
+            }
+            Ok::<_, Infallible>(())
+        }))
+        .unwrap();
+        // The borrows ended with the scope.
+        assert_eq!(counts, [0, 10, 20, 30]);
+        assert_eq!(shared.get(), 4);
+    }
+
+    #[test]
+    fn join_handles_return_outputs() {
+        let output = block_on(scope(|s| async move {
+            let a = s.spawn(async {
+                sleep_ms(10).await;
+                Ok(1)
+            });
+            let b = s.spawn(async { Ok(2) });
+            Ok::<_, Infallible>(a.await + b.await)
+        }));
+        assert_eq!(output, Ok(3));
+    }
+
+    #[test]
+    fn children_can_spawn_siblings() {
+        let log = &Log::default();
+        block_on(scope(|s| async move {
+            let s2 = s.clone();
+            s.spawn(async move {
+                sleep_ms(5).await;
+                s2.spawn(sleeper("sibling", 5, log));
+                Ok(())
+            });
+            Ok::<_, Infallible>(())
+        }))
+        .unwrap();
+        assert_eq!(*log.borrow(), ["sibling done"]);
+    }
+
+    #[test]
+    fn child_error_cancels_siblings_and_body() {
+        let log = &Log::default();
+        let result = block_on(scope(|s| async move {
+            let guard = Guard::new("body", log);
+            s.spawn(sleeper("first", 1000, log));
+            s.spawn(async {
+                sleep_ms(10).await;
+                Err::<(), _>("failed")
+            });
+            s.spawn(sleeper("third", 1000, log));
+            sleep_ms(1000).await;
+            guard.done();
+            Ok(())
+        }));
+        assert_eq!(result, Err("failed"));
+        // Newest first, then the body.
+        assert_eq!(
+            *log.borrow(),
+            ["third cancelled", "first cancelled", "body cancelled"]
+        );
+    }
+
+    #[test]
+    fn body_error_cancels_children() {
+        let log = &Log::default();
+        let result = block_on(scope(|s| async move {
+            s.spawn(sleeper("first", 1000, log));
+            s.spawn(sleeper("second", 1000, log));
+            sleep_ms(10).await;
+            Err::<(), _>("failed")
+        }));
+        assert_eq!(result, Err("failed"));
+        assert_eq!(*log.borrow(), ["second cancelled", "first cancelled"]);
+    }
+
+    #[test]
+    fn dropping_the_scope_cancels_its_tasks() {
+        let log = &Log::default();
+        block_on(async {
+            let mut scoped = scope(|s| async move {
+                let guard = Guard::new("body", log);
+                s.spawn(sleeper("first", 1000, log));
+                s.spawn(sleeper("second", 1000, log));
+                future::pending::<()>().await;
+                guard.done();
+                Ok::<_, Infallible>(())
+            })
+            .fuse();
+            select! {
+                _ = scoped => unreachable!(),
+                () = sleep_ms(10).fuse() => {}
+            }
+            // `scoped` is still alive, and so are its tasks.
+            assert!(log.borrow().is_empty());
+        });
+        assert_eq!(
+            *log.borrow(),
+            ["second cancelled", "first cancelled", "body cancelled"]
+        );
+    }
+
+    #[test]
+    fn cancelling_a_scope_cancels_nested_scopes() {
+        let log = &Log::default();
+        let result = block_on(scope(|s| async move {
+            s.spawn(scope(|inner| async move {
+                let guard = Guard::new("inner body", log);
+                inner.spawn(sleeper("grandchild", 1000, log));
+                sleep_ms(1000).await;
+                guard.done();
+                Ok(())
+            }));
+            s.spawn(async {
+                sleep_ms(10).await;
+                Err::<(), _>("failed")
+            });
+            Ok(())
+        }));
+        assert_eq!(result, Err("failed"));
+        // The inner scope's own tasks are cancelled before it finishes
+        // being cancelled.
+        assert_eq!(
+            *log.borrow(),
+            ["grandchild cancelled", "inner body cancelled"]
+        );
+    }
+
+    #[test]
+    fn child_panic_cancels_siblings_then_propagates() {
+        let log = &Log::default();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            block_on(scope(|s| async move {
+                let guard = Guard::new("body", log);
+                s.spawn(sleeper("sibling", 1000, log));
+                s.spawn(async {
+                    sleep_ms(10).await;
+                    if true {
+                        panic!("child panicked");
+                    }
+                    Ok(())
+                });
+                sleep_ms(1000).await;
+                guard.done();
+                Ok::<_, Infallible>(())
+            }))
+        }));
+        let panic = result.unwrap_err();
+        assert_eq!(panic.downcast_ref::<&str>(), Some(&"child panicked"));
+        assert_eq!(*log.borrow(), ["sibling cancelled", "body cancelled"]);
+    }
+
+    #[test]
+    fn body_panic_cancels_children_then_propagates() {
+        let log = &Log::default();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            block_on(scope(|s| async move {
+                s.spawn(sleeper("child", 1000, log));
+                sleep_ms(10).await;
+                if true {
+                    panic!("body panicked");
+                }
+                Ok::<_, Infallible>(())
+            }))
+        }));
+        assert!(result.is_err());
+        assert_eq!(*log.borrow(), ["child cancelled"]);
+    }
+
+    #[test]
+    fn spawning_after_the_scope_finishes_does_nothing() {
+        let mut leaked = None;
+        let ran = Cell::new(false);
+        block_on(scope(|s| {
+            leaked = Some(s.clone());
+            async { Ok::<_, Infallible>(()) }
+        }))
+        .unwrap();
+        let handle = leaked.unwrap().spawn(async {
+            ran.set(true);
+            Ok(())
+        });
+        assert!(!ran.get());
+        // The handle can tell that the task will never run.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| block_on(handle)));
+        assert!(result.is_err());
+    }
+}